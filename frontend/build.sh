@@ -17,5 +17,6 @@ deno cache --import-map=./import_map.json --lock deno.lock --lock-write *.tsx de
 mkdir -p ./static/js
 deno bundle $DENO_FLAGS ./instance_detail.tsx ./static/js/instance_detail.js &
 deno bundle $DENO_FLAGS ./instance_create.tsx ./static/js/instance_create.js &
+deno bundle $DENO_FLAGS ./instance_graphics.tsx ./static/js/instance_graphics.js &
 
 wait
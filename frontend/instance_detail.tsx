@@ -89,20 +89,38 @@ function InstanceButton(
   );
 }
 
+const auditRow = (al: { ts: number; op: string }) => (
+  <tr>
+    <td>{new Date(al.ts * 1000).toLocaleString()}</td>
+    <td>{al.op}</td>
+  </tr>
+);
+
 export async function Page() {
   const instance_id = g("instance_id").innerText;
-  const auditLogs = (await getAuditLogsForInstance(instance_id)).map((al) => (
-    <tr>
-      <td>{new Date(al.ts * 1000).toLocaleString()}</td>
-      <td>{al.op}</td>
-    </tr>
-  ));
+  const auditLogs = (await getAuditLogsForInstance(instance_id)).map(auditRow);
   auditLogs.unshift(
     <tr>
       <th>Time</th>
       <th>Operation</th>
     </tr>,
   );
+  const auditTable = <table>{auditLogs}</table>;
+
+  // keep the page up to date without reloading
+  const events = new EventSource(
+    u(`/api/v1/events?instance=${instance_id}`),
+  );
+  events.addEventListener("status", (e) => {
+    const ev = JSON.parse((e as MessageEvent).data);
+    g("instance_status").innerText = ev.status;
+  });
+  events.addEventListener("audit", (e) => {
+    const ev = JSON.parse((e as MessageEvent).data);
+    if (ev.kind === "instance") {
+      auditTable.appendChild(auditRow(ev));
+    }
+  });
 
   return (
     <div>
@@ -145,9 +163,7 @@ export async function Page() {
       />
       <div>
         <h3>Audit Logs</h3>
-        <table>
-          {auditLogs}
-        </table>
+        {auditTable}
       </div>
       <div id="messages">
         <h3>Messages</h3>
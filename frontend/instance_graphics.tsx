@@ -0,0 +1,53 @@
+/** @jsxImportSource xeact */
+
+import { g, t } from "xeact";
+import RFB from "novnc";
+import { SpiceMainConn } from "spice-html5";
+
+const socketURL = (instance_id: string): string => {
+  const scheme = window.location.protocol === "https:" ? "wss:" : "ws:";
+  return `${scheme}//${window.location.host}/api/v1/instances/${instance_id}/graphics`;
+};
+
+export const Page = async () => {
+  const graphics = g("graphics");
+  const instance_id = graphics.dataset.instance as string;
+  const kind = graphics.dataset.kind as string;
+  const url = socketURL(instance_id);
+
+  const status = <p>Connecting to {kind} display...</p>;
+  const screen = <div id="screen" style="width:100%;min-height:480px"></div>;
+  const setStatus = (msg: string) => {
+    status.replaceChildren(t(msg));
+  };
+
+  // give the screen time to be put in the page before the viewer looks for it
+  setTimeout(() => {
+    switch (kind) {
+      case "vnc": {
+        const rfb = new RFB(screen, url);
+        rfb.scaleViewport = true;
+        rfb.addEventListener("connect", () => setStatus("Connected."));
+        rfb.addEventListener("disconnect", () => setStatus("Disconnected."));
+        break;
+      }
+      case "spice":
+        new SpiceMainConn({
+          uri: url,
+          screen_id: "screen",
+          onsuccess: () => setStatus("Connected."),
+          onerror: (e: Error) => setStatus(`Disconnected: ${e}`),
+        });
+        break;
+      default:
+        setStatus(`Don't know how to show a ${kind} display.`);
+    }
+  }, 0);
+
+  return (
+    <div>
+      {status}
+      {screen}
+    </div>
+  );
+};
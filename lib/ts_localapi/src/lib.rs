@@ -110,6 +110,8 @@ pub struct WhoisPeer {
     pub machine_authorized: Option<bool>,
     #[serde(rename = "Capabilities")]
     pub capabilities: Option<Vec<String>>,
+    #[serde(rename = "Tags", default)]
+    pub tags: Option<Vec<String>>,
     #[serde(rename = "ComputedName")]
     pub computed_name: String,
     #[serde(rename = "ComputedNameWithHost")]
@@ -1,6 +1,8 @@
 use crate::{
     api::libvirt::Machine,
+    graphics,
     models::{Distro, Instance},
+    principal::Principal,
     tailauth::Tailauth,
     Config, Result, State,
 };
@@ -8,6 +10,7 @@ use axum::{extract::Path, Extension, Json};
 use maud::{html, Markup, PreEscaped};
 use rusqlite::params;
 use std::sync::Arc;
+use tokio::task::spawn_blocking;
 use ts_localapi::User;
 use uuid::Uuid;
 use virt::{connect::Connect, domain::Domain};
@@ -142,10 +145,19 @@ pub async fn instance(
 
     let instance = Instance::from_uuid(&conn, id)?;
 
-    let conn = Connect::open(&format!("qemu+ssh://root@{}/system", instance.host))?;
-    let machine: Option<Machine> = Domain::lookup_by_uuid_string(&conn, &id.to_string())
-        .ok()
-        .and_then(|dom| Machine::try_from(dom).ok());
+    // instances with a static address don't need a trip to libvirt
+    let ip = match instance.ip_address.clone() {
+        Some(ip) => Some(ip),
+        None => {
+            let uri = state.exec.libvirt_uri(&instance.host);
+            let machine: Result<Machine> = spawn_blocking(move || {
+                let conn = Connect::open(&uri)?;
+                Machine::try_from(Domain::lookup_by_uuid_string(&conn, &id.to_string())?)
+            })
+            .await?;
+            machine.ok().and_then(|m| m.addr)
+        }
+    };
 
     Ok(base(
         Some(instance.name.clone()),
@@ -159,15 +171,23 @@ pub async fn instance(
             table {
                 tr {
                     th {"Status"}
-                    td {(instance.status)}
+                    td #instance_status {(instance.status.to_string())}
+                }
+                @if let Some(reason) = &instance.status_reason {
+                    tr {
+                        th {"Status reason"}
+                        td {(reason)}
+                    }
+                }
+                @if let Some(stage) = instance.boot_stage {
+                    tr {
+                        th {"Boot stage"}
+                        td {(stage.to_string())}
+                    }
                 }
                 tr {
                     th {"IP Address"}
-                    td {
-                        @if let Some(m) = machine {
-                            (m.addr.unwrap_or("".to_string()))
-                        }
-                    }
+                    td {(ip.unwrap_or_default())}
                 }
                 tr {
                     th {"Host"}
@@ -177,6 +197,10 @@ pub async fn instance(
                     th {"Memory"}
                     td {(instance.memory) " MB"}
                 }
+                tr {
+                    th {"CPUs"}
+                    td {(instance.cpus)}
+                }
                 tr {
                     th {"Disk size"}
                     td {(instance.disk_size) " GB"}
@@ -189,47 +213,67 @@ pub async fn instance(
                     th {"Distro"}
                     td {(instance.distro)}
                 }
+                tr {
+                    th {"Owner"}
+                    td {(instance.owner.clone().unwrap_or_default())}
+                }
                 tr {
                     th {"UUID"}
                     td #instance_id {(instance.uuid.to_string())}
                 }
             }
 
+            p { a href={"/admin/instances/" (instance.uuid.to_string()) "/graphics"} {"Open display"} }
+
             h2 {"Quick Actions"}
             div #app {"Loading..."}
         },
     ))
 }
 
-pub async fn instances(
+pub async fn instance_graphics(
     Extension(state): Extension<Arc<State>>,
     Tailauth(user, _): Tailauth,
+    who: Principal,
+    Path(id): Path<Uuid>,
 ) -> Result<Markup> {
     let conn = state.pool.get().await?;
 
-    let mut result: Vec<Instance> = Vec::new();
+    let instance = Instance::from_uuid(&conn, id)?;
+    who.check_owns(&instance)?;
 
-    let mut stmt = conn.prepare(
-        "SELECT uuid, name, host, mac_address, memory, disk_size, zvol_name, status, distro, join_tailnet FROM instances",
-    )?;
-    let instances = stmt.query_map(params![], |row| {
-        Ok(Instance {
-            uuid: row.get(0)?,
-            name: row.get(1)?,
-            host: row.get(2)?,
-            mac_address: row.get(3)?,
-            memory: row.get(4)?,
-            disk_size: row.get(5)?,
-            zvol_name: row.get(6)?,
-            status: row.get(7)?,
-            distro: row.get(8)?,
-            join_tailnet: row.get(9)?,
-        })
-    })?;
+    let uri = state.exec.libvirt_uri(&instance.host);
+    let display = spawn_blocking(move || graphics::find(&uri, id)).await?;
+    let detail_link = format!("/admin/instances/{}", instance.uuid);
 
-    for instance in instances {
-        result.push(instance?);
-    }
+    Ok(base(
+        Some(format!("{} display", instance.name)),
+        Some(&[
+            ("Instances", Some("/admin/instances")),
+            (&instance.name, Some(&detail_link)),
+            ("Display", None),
+        ]),
+        user,
+        html! {
+            @match display {
+                Ok(display) => {
+                    (import_js("instance_graphics.js"))
+                    div #graphics data-instance=(instance.uuid.to_string()) data-kind=(display.kind.to_string()) {}
+                    div #app {"Loading..."}
+                }
+                Err(why) => p {(why.to_string())},
+            }
+        },
+    ))
+}
+
+pub async fn instances(
+    Extension(state): Extension<Arc<State>>,
+    Tailauth(user, _): Tailauth,
+) -> Result<Markup> {
+    let conn = state.pool.get().await?;
+
+    let result = Instance::get_all(&conn)?;
 
     Ok(base(
         Some("Instances".to_string()),
@@ -253,7 +297,7 @@ pub async fn instances(
                         td {(i.memory) " MB"}
                         td {(i.disk_size) " GB"}
                         td {(i.distro)}
-                        td {(i.status)}
+                        td {(i.status.to_string())}
                     }
                 }
             }
@@ -320,7 +364,7 @@ pub async fn distro_list(
     let conn = state.pool.get().await?;
 
     let mut stmt = conn.prepare(
-        "SELECT name, download_url, sha256sum, min_size, format FROM distros ORDER BY name ASC",
+        "SELECT name, download_url, sha256sum, min_size, format, family FROM distros ORDER BY name ASC",
     )?;
     let iter = stmt.query_map(params![], |row| {
         Ok(Distro {
@@ -329,6 +373,7 @@ pub async fn distro_list(
             sha256sum: row.get(2)?,
             min_size: row.get(3)?,
             format: row.get(4)?,
+            family: row.get(5)?,
         })
     })?;
     let mut result: Vec<Distro> = vec![];
@@ -345,11 +390,13 @@ pub async fn distro_list(
             table {
                 tr {
                     th {"Name"}
+                    th {"Family"}
                     th {"Min. Size (gb)"}
                 }
                 @for d in result {
                     tr {
                         td {(d.name)}
+                        td {(d.family)}
                         td {(d.min_size)}
                     }
                 }
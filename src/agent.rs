@@ -0,0 +1,288 @@
+//! Talking to the QEMU guest agent through the `org.qemu.guest_agent.0` channel every
+//! domain has.
+//!
+//! The functions that take a [`Domain`] block on libvirt, run them with
+//! [`with_domain`]. Guests without the agent installed fail every command with a
+//! libvirt error.
+
+use crate::{models::Instance, Error, Result, State};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+use tokio::task::spawn_blocking;
+use virt::{connect::Connect, domain::Domain};
+
+/// How long to wait for the agent to answer a single command, in seconds.
+const COMMAND_TIMEOUT: i32 = 10;
+
+#[derive(Deserialize)]
+struct Reply<T> {
+    #[serde(rename = "return")]
+    ret: T,
+}
+
+/// Sends one command to the agent and decodes what it returns.
+fn call<T: DeserializeOwned>(dom: &Domain, execute: &str, arguments: Option<Value>) -> Result<T> {
+    call_within(dom, execute, arguments, COMMAND_TIMEOUT)
+}
+
+/// Like [`call`], but gives up after `timeout` seconds.
+fn call_within<T: DeserializeOwned>(
+    dom: &Domain,
+    execute: &str,
+    arguments: Option<Value>,
+    timeout: i32,
+) -> Result<T> {
+    let mut cmd = json!({ "execute": execute });
+    if let Some(arguments) = arguments {
+        cmd["arguments"] = arguments;
+    }
+
+    let reply = dom.qemu_agent_command(&cmd.to_string(), timeout, 0)?;
+    let reply: Reply<T> = serde_json::from_str(&reply)?;
+
+    Ok(reply.ret)
+}
+
+/// Runs `f` against an instance's domain on a blocking thread, failing early if the
+/// instance isn't running since the agent can't answer then.
+pub async fn with_domain<T, F>(state: &State, i: &Instance, f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&Domain) -> Result<T> + Send + 'static,
+{
+    let uri = state.exec.libvirt_uri(&i.host);
+    let id = i.uuid;
+    let name = i.name.clone();
+
+    spawn_blocking(move || {
+        let mut conn = Connect::open(&uri)?;
+        let result = Domain::lookup_by_uuid_string(&conn, &id.to_string())
+            .map_err(Error::from)
+            .and_then(|dom| {
+                if !dom.is_active()? {
+                    return Err(Error::GuestAgent(name, "instance is not running".into()));
+                }
+                f(&dom)
+            });
+        conn.close()?;
+
+        result
+    })
+    .await?
+}
+
+/// What the guest says it is running, from `guest-get-osinfo`.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct OsInfo {
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub pretty_name: Option<String>,
+    pub version: Option<String>,
+    pub version_id: Option<String>,
+    pub kernel_release: Option<String>,
+    pub kernel_version: Option<String>,
+    pub machine: Option<String>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Interface {
+    pub name: String,
+    pub hardware_address: Option<String>,
+    #[serde(default)]
+    pub ip_addresses: Vec<IpAddress>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct IpAddress {
+    /// Either `ipv4` or `ipv6`.
+    pub ip_address_type: String,
+    pub ip_address: String,
+    pub prefix: u8,
+}
+
+/// A command to run inside the guest.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Exec {
+    /// Path to the program, the guest's `PATH` is not searched.
+    pub path: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// How long to wait for the command to exit, defaults to 30 seconds.
+    pub timeout_secs: Option<u64>,
+}
+
+/// What a command run inside the guest did.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExecOutput {
+    pub exit_code: Option<i32>,
+    /// Set if the command was killed by a signal.
+    pub signal: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+#[derive(Deserialize)]
+struct ExecStarted {
+    pid: i64,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ExecStatus {
+    exited: bool,
+    exitcode: Option<i32>,
+    signal: Option<i32>,
+    out_data: Option<String>,
+    err_data: Option<String>,
+}
+
+impl TryFrom<ExecStatus> for ExecOutput {
+    type Error = Error;
+
+    fn try_from(st: ExecStatus) -> Result<Self> {
+        let decode = |data: Option<String>| -> Result<String> {
+            let bytes = STANDARD.decode(data.unwrap_or_default())?;
+            Ok(String::from_utf8_lossy(&bytes).into_owned())
+        };
+
+        Ok(ExecOutput {
+            exit_code: st.exitcode,
+            signal: st.signal,
+            stdout: decode(st.out_data)?,
+            stderr: decode(st.err_data)?,
+        })
+    }
+}
+
+pub fn os_info(dom: &Domain) -> Result<OsInfo> {
+    call(dom, "guest-get-osinfo", None)
+}
+
+/// Every interface the guest has, including ones libvirt's DHCP server doesn't know
+/// about.
+pub fn interfaces(dom: &Domain) -> Result<Vec<Interface>> {
+    call(dom, "guest-network-get-interfaces", None)
+}
+
+/// Like [`interfaces`], but gives up after `timeout` seconds so listings aren't held
+/// up by guests whose agent is stuck.
+pub fn interfaces_within(dom: &Domain, timeout: i32) -> Result<Vec<Interface>> {
+    call_within(dom, "guest-network-get-interfaces", None, timeout)
+}
+
+/// Runs a command in the guest and waits for it to exit.
+pub fn exec(dom: &Domain, name: &str, cmd: &Exec) -> Result<ExecOutput> {
+    let started: ExecStarted = call(
+        dom,
+        "guest-exec",
+        Some(json!({
+            "path": cmd.path,
+            "arg": cmd.args,
+            "capture-output": true,
+        })),
+    )?;
+
+    let timeout = Duration::from_secs(cmd.timeout_secs.unwrap_or(30));
+    let deadline = Instant::now() + timeout;
+    loop {
+        let st: ExecStatus = call(
+            dom,
+            "guest-exec-status",
+            Some(json!({ "pid": started.pid })),
+        )?;
+        if st.exited {
+            return st.try_into();
+        }
+        if Instant::now() >= deadline {
+            return Err(Error::GuestAgent(
+                name.to_string(),
+                format!(
+                    "{} is still running after {} seconds",
+                    cmd.path,
+                    timeout.as_secs()
+                ),
+            ));
+        }
+
+        std::thread::sleep(Duration::from_millis(250));
+    }
+}
+
+/// Sets the password of a user in the guest.
+pub fn set_password(dom: &Domain, username: &str, password: &str) -> Result {
+    let _: Value = call(
+        dom,
+        "guest-set-user-password",
+        Some(json!({
+            "username": username,
+            "password": STANDARD.encode(password),
+            "crypted": false,
+        })),
+    )?;
+
+    Ok(())
+}
+
+/// Flushes and freezes every guest filesystem, returning how many were frozen. They
+/// must be thawed with [`thaw`] or the guest will hang on its next write.
+pub fn freeze(dom: &Domain) -> Result<u32> {
+    call(dom, "guest-fsfreeze-freeze", None)
+}
+
+pub fn thaw(dom: &Domain) -> Result<u32> {
+    call(dom, "guest-fsfreeze-thaw", None)
+}
+
+/// Pulls the first routable address out of what the agent reports, skipping
+/// loopback and link-local addresses.
+pub fn first_addr(ifaces: &[Interface]) -> Option<String> {
+    ifaces
+        .iter()
+        .filter(|iface| iface.name != "lo")
+        .flat_map(|iface| iface.ip_addresses.iter())
+        .map(|ip| ip.ip_address.as_str())
+        .find(|ip| !ip.starts_with("127.") && *ip != "::1" && !ip.starts_with("fe80:"))
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_interfaces() {
+        let reply = r#"{"return": [
+            {"name": "lo", "hardware-address": "00:00:00:00:00:00", "ip-addresses": [
+                {"ip-address-type": "ipv4", "ip-address": "127.0.0.1", "prefix": 8}
+            ]},
+            {"name": "eth0", "hardware-address": "52:54:00:12:34:56", "ip-addresses": [
+                {"ip-address-type": "ipv6", "ip-address": "fe80::5054:ff:fe12:3456", "prefix": 64},
+                {"ip-address-type": "ipv4", "ip-address": "10.77.128.5", "prefix": 24}
+            ]},
+            {"name": "docker0"}
+        ]}"#;
+
+        let ifaces: Reply<Vec<Interface>> = serde_json::from_str(reply).unwrap();
+        let ifaces = ifaces.ret;
+        assert_eq!(ifaces.len(), 3);
+        assert!(ifaces[2].ip_addresses.is_empty());
+        assert_eq!(first_addr(&ifaces), Some("10.77.128.5".into()));
+        assert_eq!(first_addr(&ifaces[..1]), None);
+    }
+
+    #[test]
+    fn decode_exec_status() {
+        let reply = r#"{"return": {"exitcode": 0, "out-data": "aGVsbG8K", "exited": true}}"#;
+
+        let st: Reply<ExecStatus> = serde_json::from_str(reply).unwrap();
+        let out = ExecOutput::try_from(st.ret).unwrap();
+        assert_eq!(out.exit_code, Some(0));
+        assert_eq!(out.stdout, "hello\n");
+        assert_eq!(out.stderr, "");
+    }
+}
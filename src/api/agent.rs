@@ -0,0 +1,127 @@
+use crate::{
+    agent::{self, Exec, ExecOutput, Interface, OsInfo},
+    models::Instance,
+    principal::{Actor, Principal},
+    Result, State,
+};
+use axum::{
+    extract::{Extension, Path},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetPassword {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Serialize)]
+struct ExecEvent<'a> {
+    #[serde(flatten)]
+    instance: &'a Instance,
+    path: &'a str,
+    args: &'a [String],
+}
+
+#[derive(Serialize)]
+struct PasswordEvent<'a> {
+    #[serde(flatten)]
+    instance: &'a Instance,
+    username: &'a str,
+}
+
+#[instrument(err, skip(state))]
+pub async fn os_info(
+    Path(id): Path<Uuid>,
+    Extension(state): Extension<Arc<State>>,
+    _: Principal,
+) -> Result<Json<OsInfo>> {
+    let conn = state.pool.get().await?;
+    let i = Instance::from_uuid(&conn, id)?;
+    drop(conn);
+
+    Ok(Json(agent::with_domain(&state, &i, agent::os_info).await?))
+}
+
+#[instrument(err, skip(state))]
+pub async fn interfaces(
+    Path(id): Path<Uuid>,
+    Extension(state): Extension<Arc<State>>,
+    _: Principal,
+) -> Result<Json<Vec<Interface>>> {
+    let conn = state.pool.get().await?;
+    let i = Instance::from_uuid(&conn, id)?;
+    drop(conn);
+
+    Ok(Json(
+        agent::with_domain(&state, &i, agent::interfaces).await?,
+    ))
+}
+
+/// Runs a command in the guest. Its output is returned but not written to the audit
+/// log.
+#[instrument(err, skip(state))]
+pub async fn exec(
+    Path(id): Path<Uuid>,
+    Extension(state): Extension<Arc<State>>,
+    who: Principal,
+    actor: Actor,
+    Json(cmd): Json<Exec>,
+) -> Result<Json<ExecOutput>> {
+    let conn = state.pool.get().await?;
+    let i = Instance::from_uuid(&conn, id)?;
+    who.check_owns(&i)?;
+
+    state.events.audit(
+        &conn,
+        &actor,
+        "instance",
+        "agent exec",
+        &ExecEvent {
+            instance: &i,
+            path: &cmd.path,
+            args: &cmd.args,
+        },
+    )?;
+    drop(conn);
+
+    let name = i.name.clone();
+    let output = agent::with_domain(&state, &i, move |dom| agent::exec(dom, &name, &cmd)).await?;
+
+    Ok(Json(output))
+}
+
+#[instrument(err, skip(state, details))]
+pub async fn set_password(
+    Path(id): Path<Uuid>,
+    Extension(state): Extension<Arc<State>>,
+    who: Principal,
+    actor: Actor,
+    Json(details): Json<SetPassword>,
+) -> Result {
+    let conn = state.pool.get().await?;
+    let i = Instance::from_uuid(&conn, id)?;
+    who.check_owns(&i)?;
+
+    let username = details.username.clone();
+    agent::with_domain(&state, &i, move |dom| {
+        agent::set_password(dom, &details.username, &details.password)
+    })
+    .await?;
+
+    state.events.audit(
+        &conn,
+        &actor,
+        "instance",
+        "agent set password",
+        &PasswordEvent {
+            instance: &i,
+            username: &username,
+        },
+    )?;
+
+    Ok(())
+}
@@ -1,25 +1,31 @@
-use crate::{models::AuditEvent, tailauth::Tailauth, Result, State};
+use crate::{
+    models::{AuditEvent, AuditPage, AuditQuery},
+    principal::Principal,
+    Result, State,
+};
 use axum::{
-    extract::{Extension, Path},
+    extract::{Extension, Path, Query},
     Json,
 };
 use std::sync::Arc;
 
+/// Searches the audit log a page at a time, see [`AuditQuery`] for the filters.
 #[instrument(err, skip(state))]
 pub async fn list(
+    Query(q): Query<AuditQuery>,
     Extension(state): Extension<Arc<State>>,
-    _: Tailauth,
-) -> Result<Json<Vec<AuditEvent>>> {
+    _: Principal,
+) -> Result<Json<AuditPage>> {
     let conn = state.pool.get().await?;
 
-    Ok(Json(AuditEvent::get_all(&conn)?))
+    Ok(Json(AuditEvent::query(&conn, &q)?))
 }
 
 #[instrument(err, skip(state))]
 pub async fn list_for_instance(
     Path(id): Path<uuid::Uuid>,
     Extension(state): Extension<Arc<State>>,
-    _: Tailauth,
+    _: Principal,
 ) -> Result<Json<Vec<AuditEvent>>> {
     let conn = state.pool.get().await?;
 
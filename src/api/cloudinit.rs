@@ -1,7 +1,14 @@
-use crate::{models::Instance, Error, State};
+use crate::{
+    ipam,
+    models::{BootStage, Instance, InstanceStatus},
+    principal::Actor,
+    sshkeys::SshKey,
+    userdata::Seed,
+    vendordata::{Family, PhoneHome},
+    Config, Error, State,
+};
 use axum::extract::{Extension, Path};
 use rusqlite::params;
-use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use uuid::Uuid;
 
@@ -11,12 +18,9 @@ pub async fn user_data(
     Extension(state): Extension<Arc<State>>,
 ) -> Result<String, Error> {
     let conn = state.pool.get().await?;
+    let ins = Instance::from_uuid(&conn, id)?;
 
-    Ok(conn.query_row(
-        "SELECT user_data FROM cloudconfig_seeds WHERE uuid = ?1",
-        params![id],
-        |row| Ok(row.get(0)?),
-    )?)
+    Seed::get(&conn, id)?.render(&conn, &ins)
 }
 
 #[instrument(err)]
@@ -31,16 +35,18 @@ pub async fn meta_data(
         params![id],
         |row| row.get(0),
     )?;
-    
-    conn.execute(
-        "UPDATE instances SET status = ?1 WHERE uuid = ?2",
-        params!["running", id],
-    )?;
-    let ins = Instance::from_uuid(&conn, id)?;
-    conn.execute(
-        "INSERT INTO audit_logs(kind, op, data) VALUES (?1, ?2, ?3)",
-        params!["instance", "running", serde_json::to_string(&ins)?],
-    )?;
+
+    // guests fetch this on every boot, only the first fetch while being set up counts
+    let mut ins = Instance::from_uuid(&conn, id)?;
+    if ins.is_booting() && ins.set_boot_stage(&conn, BootStage::SeedFetched)? {
+        state.events.audit(
+            &conn,
+            &Actor::system("cloudinit"),
+            "instance",
+            "seed fetched",
+            &ins,
+        )?;
+    }
 
     Ok(format!(
         "instance-id: {}
@@ -49,9 +55,54 @@ local-hostname: {}",
     ))
 }
 
-#[instrument(err, skip(ts))]
+/// Called by cloud-init's phone_home module once it has run everything else, which
+/// is when the instance is actually ready.
+#[instrument(err)]
+pub async fn phone_home(
+    Path(id): Path<Uuid>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<(), Error> {
+    let conn = state.pool.get().await?;
+    let mut ins = Instance::from_uuid(&conn, id)?;
+
+    if !ins.is_booting() {
+        warn!(status = %ins.status, stage = ?ins.boot_stage, "instance phoned home at a strange time");
+        return Ok(());
+    }
+
+    ins.set_boot_stage(&conn, BootStage::ModulesFinished)?;
+    ins.set_status(&conn, &state.events, InstanceStatus::Running, None)?;
+    state.events.audit(
+        &conn,
+        &Actor::system("cloudinit"),
+        "instance",
+        "running",
+        &ins,
+    )?;
+
+    Ok(())
+}
+
+/// Tells the instance its static address, or to use DHCP if it doesn't have one.
+#[instrument(err, skip(cfg))]
+pub async fn network_config(
+    Path(id): Path<Uuid>,
+    Extension(cfg): Extension<Arc<Config>>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<String, Error> {
+    let conn = state.pool.get().await?;
+    let ins = Instance::from_uuid(&conn, id)?;
+
+    Ok(serde_yaml::to_string(&ipam::network_config(
+        &cfg.subnets,
+        &ins,
+    )?)?)
+}
+
+#[instrument(err, skip(ts, cfg))]
 pub async fn vendor_data(
     Path(id): Path<Uuid>,
+    Extension(cfg): Extension<Arc<Config>>,
     Extension(ts): Extension<Arc<tailscale_client::Client>>,
     Extension(state): Extension<Arc<State>>,
 ) -> Result<String, Error> {
@@ -59,7 +110,7 @@ pub async fn vendor_data(
 
     let i: Instance = Instance::from_uuid(&conn, id)?;
 
-    if i.join_tailnet {
+    let authkey = if i.join_tailnet {
         let key_info = ts
             .create_key(tailscale_client::Capabilities {
                 reusable: false,
@@ -69,66 +120,33 @@ pub async fn vendor_data(
             })
             .await?;
 
-        conn.execute(
-            "INSERT INTO audit_logs(kind, op, data) VALUES (?1, ?2, ?3)",
-            params![
-                "tailnet authkey",
-                "create",
-                serde_json::to_string(&key_info)?
-            ],
+        state.events.audit(
+            &conn,
+            &Actor::system("cloudinit"),
+            "tailnet authkey",
+            "create",
+            &key_info,
         )?;
 
-        if i.distro == "ubuntu-20.04".to_string() || i.distro == "ubuntu-22.04".to_string() {
-            Ok(format!("#cloud-config\n{}", serde_yaml::to_string(&CloudConfig{
-                write_files: vec![
-                    File{
-                        owner: "root:root".to_string(),
-                        path: "/etc/update-motd.d/69-waifud".to_string(),
-                        permissions: "0755".to_string(),
-                        content: "#!/bin/sh\n#\n# This file is written by waifud.\necho \"\"\necho \"Welcome to waifud <3\"\n".to_string(),
-                    },
-                ],
-                runcmd: vec![
-                    vec!["sh".into(), "-c".into(), "curl -fsSL https://tailscale.com/install.sh | sh".into()],
-                    vec!["systemctl".into(), "enable".into(), "--now".into(), "tailscaled.service".into()],
-                    vec!["tailscale".into(), "up".into(), "--authkey".into(), key_info.key.unwrap(), "--ssh".into(), "--advertise-tags=tag:vm".into()],
-                    vec!["apt".into(), "install".into(), "-y".into(), "systemd-container".into()]
-                ],
-            })?))
-        } else {
-            Ok(format!("#cloud-config\n{}", serde_yaml::to_string(&CloudConfig{
-                write_files: vec![
-                    File{
-                        owner: "root:root".into(),
-                        path: "/etc/update-motd.d/69-waifud".into(),
-                        permissions: "0755".into(),
-                        content: "#!/bin/sh\n#\n# This file is written by waifud.\necho \"\"\necho \"Welcome to waifud <3\"\n".into(),
-                    },
-                ],
-                runcmd: vec![
-                    vec!["sh".into(), "-c".into(), "curl -fsSL https://tailscale.com/install.sh | sh".into()],
-                    vec!["systemctl".into(), "enable".into(), "--now".into(), "tailscaled.service".into()],
-                    vec!["tailscale".into(), "up".into(), "--authkey".into(), key_info.key.unwrap(), "--ssh".into()],
-                ],
-            })?))
-        }
+        Some(
+            key_info
+                .key
+                .ok_or_else(|| Error::Catchall("tailscale didn't return an authkey".into()))?,
+        )
     } else {
-        Ok(include_str!("./vendor-data").to_string())
-    }
-}
+        None
+    };
 
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct CloudConfig {
-    #[serde(rename = "write_files")]
-    pub write_files: Vec<File>,
-    pub runcmd: Vec<Vec<String>>,
-}
+    let family = Family::of_distro(&conn, &i.distro)?;
+    let mut cc = family.strategy().cloud_config(authkey.as_deref());
+    cc.phone_home = Some(PhoneHome::new(format!(
+        "{}/api/cloudinit/{}/phone-home",
+        cfg.base_url, i.uuid
+    )));
+
+    if let Some(owner) = &i.owner {
+        cc.ssh_authorized_keys = SshKey::public_keys_of(&conn, owner)?;
+    }
 
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub struct File {
-    pub owner: String,
-    pub path: String,
-    pub permissions: String,
-    pub content: String,
+    Ok(format!("#cloud-config\n{}", serde_yaml::to_string(&cc)?))
 }
@@ -1,4 +1,8 @@
-use crate::{models::Distro, tailauth::Tailauth, Result, State};
+use crate::{
+    models::Distro,
+    principal::{Actor, Principal},
+    Result, State,
+};
 use axum::{
     extract::{Extension, Path},
     Json,
@@ -9,7 +13,7 @@ use std::sync::Arc;
 #[instrument(err)]
 pub async fn create(
     Extension(state): Extension<Arc<State>>,
-    _: Tailauth,
+    actor: Actor,
     Json(distro): Json<Distro>,
 ) -> Result<Json<Distro>> {
     let conn = state.pool.get().await?;
@@ -28,6 +32,7 @@ pub async fn create(
                    , sha256sum
                    , min_size
                    , format
+                   , family
                    )
              VALUES
                  ( ?1
@@ -35,15 +40,22 @@ pub async fn create(
                  , ?3
                  , ?4
                  , ?5
+                 , ?6
                  )",
-            params![d.name, d.download_url, d.sha256sum, d.min_size, d.format],
+            params![
+                d.name,
+                d.download_url,
+                d.sha256sum,
+                d.min_size,
+                d.format,
+                d.family
+            ],
         )?;
     }
 
-    conn.execute(
-        "INSERT INTO audit_logs(kind, op, data) VALUES (?1, ?2, ?3)",
-        params!["distro", "create", serde_json::to_string(&distro)?],
-    )?;
+    state
+        .events
+        .audit(&conn, &actor, "distro", "create", &distro)?;
 
     Ok(Json(distro))
 }
@@ -52,7 +64,7 @@ pub async fn create(
 pub async fn update(
     Path(name): Path<String>,
     Extension(state): Extension<Arc<State>>,
-    _: Tailauth,
+    actor: Actor,
     Json(distro): Json<Distro>,
 ) -> Result<Json<Distro>> {
     let conn = state.pool.get().await?;
@@ -71,26 +83,33 @@ INSERT INTO
          , sha256sum
          , min_size
          , format
+         , family
          )
 VALUES ( ?5
        , ?1
        , ?2
        , ?3
        , ?4
+       , ?6
        )
 ON CONFLICT DO
   UPDATE SET download_url=?1
            , sha256sum=?2
            , min_size=?3
            , format=?4
+           , family=?6
 ",
-        params![d.download_url, d.sha256sum, d.min_size, d.format, d.name],
+        params![
+            d.download_url,
+            d.sha256sum,
+            d.min_size,
+            d.format,
+            d.name,
+            d.family
+        ],
     )?;
 
-    conn.execute(
-        "INSERT INTO audit_logs(kind, op, data) VALUES (?1, ?2, ?3)",
-        params!["distro", "update", serde_json::to_string(&d)?],
-    )?;
+    state.events.audit(&conn, &actor, "distro", "update", &d)?;
 
     Ok(Json(distro))
 }
@@ -99,17 +118,14 @@ ON CONFLICT DO
 pub async fn delete(
     Extension(state): Extension<Arc<State>>,
     Path(name): Path<String>,
-    _: Tailauth,
+    actor: Actor,
 ) -> Result<()> {
     let conn = state.pool.get().await?;
 
     let d = Distro::from_name(&conn, name)?;
     conn.execute("DELETE FROM distros WHERE name = ?1", params![d.name])?;
 
-    conn.execute(
-        "INSERT INTO audit_logs(kind, op, data) VALUES (?1, ?2, ?3)",
-        params!["distro", "update", serde_json::to_string(&d)?],
-    )?;
+    state.events.audit(&conn, &actor, "distro", "update", &d)?;
 
     Ok(())
 }
@@ -118,7 +134,7 @@ pub async fn delete(
 pub async fn get(
     Extension(state): Extension<Arc<State>>,
     Path(name): Path<String>,
-    _: Tailauth,
+    _: Principal,
 ) -> Result<Json<Distro>> {
     let conn = state.pool.get().await?;
 
@@ -129,6 +145,7 @@ pub async fn get(
          , sha256sum
          , min_size
          , format
+         , family
          FROM distros
          WHERE name = ?1",
         params![name],
@@ -139,6 +156,7 @@ pub async fn get(
                 sha256sum: row.get(2)?,
                 min_size: row.get(3)?,
                 format: row.get(4)?,
+                family: row.get(5)?,
             })
         },
     )?))
@@ -147,12 +165,12 @@ pub async fn get(
 #[instrument(err)]
 pub async fn list(
     Extension(state): Extension<Arc<State>>,
-    _: Tailauth,
+    _: Principal,
 ) -> Result<Json<Vec<Distro>>> {
     let conn = state.pool.get().await?;
 
     let mut stmt = conn.prepare(
-        "SELECT name, download_url, sha256sum, min_size, format FROM distros ORDER BY name ASC",
+        "SELECT name, download_url, sha256sum, min_size, format, family FROM distros ORDER BY name ASC",
     )?;
     let iter = stmt.query_map(params![], |row| {
         Ok(Distro {
@@ -161,6 +179,7 @@ pub async fn list(
             sha256sum: row.get(2)?,
             min_size: row.get(3)?,
             format: row.get(4)?,
+            family: row.get(5)?,
         })
     })?;
     let mut result: Vec<Distro> = vec![];
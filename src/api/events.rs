@@ -0,0 +1,51 @@
+use crate::{events::Event, principal::Principal, State};
+use axum::{
+    extract::{Extension, Query},
+    response::sse::{self, KeepAlive, Sse},
+};
+use futures::{stream, Stream};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use uuid::Uuid;
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct EventParams {
+    /// Only send events about this instance.
+    pub instance: Option<Uuid>,
+}
+
+/// Streams events as they happen using Server-Sent Events. Each event's data is a
+/// JSON [`Event`]. Clients that fall too far behind are sent [`Event::Lagged`].
+#[instrument(skip(state))]
+pub async fn stream(
+    Query(params): Query<EventParams>,
+    Extension(state): Extension<Arc<State>>,
+    _: Principal,
+) -> Sse<impl Stream<Item = Result<sse::Event, axum::Error>>> {
+    let rx = state.events.subscribe();
+    let instance = params.instance;
+
+    let events = stream::unfold(rx, move |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(ev) => {
+                    if instance.is_some() && ev.instance() != instance {
+                        continue;
+                    }
+                    let sent = sse::Event::default().event(ev.name()).json_data(&ev);
+                    return Some((sent, rx));
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("event subscriber missed {} events", missed);
+                    let ev = Event::Lagged { missed };
+                    let sent = sse::Event::default().event(ev.name()).json_data(&ev);
+                    return Some((sent, rx));
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
@@ -1,20 +1,36 @@
 use crate::{
-    api::libvirt::Machine,
-    libvirt::{random_mac, NewInstance},
-    models::{Distro, Instance},
-    tailauth::Tailauth,
+    api::{libvirt::Machine, snapshots},
+    console, graphics, ipam,
+    jobs::{
+        self,
+        migrate::{self, Migrate},
+        provision::{self, Provision},
+    },
+    libvirt::{
+        random_mac, CloneInstance, MigrateInstance, NewInstance, ResizeInstance, TransferInstance,
+    },
+    models::{Distro, Instance, InstanceStatus, Job},
+    principal::{Actor, Principal},
+    quota::{self, Usage},
+    scheduler,
+    userdata::Seed,
     Config, Error, State,
 };
 use axum::{
-    extract::{Extension, Path},
+    extract::{ws::WebSocketUpgrade, Extension, Path, Query},
+    response::Response,
     Json,
 };
+use bb8::PooledConnection;
+use bb8_rusqlite::RusqliteConnectionManager;
 use rusqlite::params;
-use std::{
-    convert::TryFrom, net::SocketAddr, os::unix::prelude::ExitStatusExt, process::ExitStatus,
-    sync::Arc, time::Duration,
+use serde::{Deserialize, Serialize};
+use std::{convert::TryFrom, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    net::{lookup_host, TcpStream},
+    task::spawn_blocking,
+    time::sleep,
 };
-use tokio::{net::lookup_host, process::Command, task::spawn_blocking, time::sleep};
 use uuid::Uuid;
 use virt::{connect::Connect, domain::Domain};
 
@@ -22,72 +38,33 @@ use virt::{connect::Connect, domain::Domain};
 #[axum_macros::debug_handler]
 pub async fn reinit(
     Path(id): Path<Uuid>,
+    Query(params): Query<snapshots::RollbackParams>,
     Extension(state): Extension<Arc<State>>,
-    _: Tailauth,
+    who: Principal,
+    actor: Actor,
 ) -> Result<(), Error> {
     let conn = state.pool.get().await?;
 
     let mut i = Instance::from_uuid(&conn, id)?;
+    who.check_owns(&i)?;
+    i.status.check_transition(InstanceStatus::Reinit)?;
+
+    snapshots::rollback_to(
+        &state,
+        &i,
+        "init",
+        snapshots::RollbackOptions {
+            destroy_newer: params.destroy_newer,
+            start: true,
+        },
+    )
+    .await?;
 
-    let nuke: Result<(), Error> = {
-        let host = i.host.clone();
-        let id = i.uuid.clone();
-
-        spawn_blocking(move || {
-            let conn = Connect::open(&format!("qemu+ssh://root@{}/system", host))?;
-
-            let dom = Domain::lookup_by_uuid_string(&conn, &id.to_string())?;
-
-            dom.destroy()?;
-            Ok(())
-        })
-        .await?
-    };
-    nuke?;
-
-    sleep(Duration::from_millis(500)).await;
-
-    debug!("rolling back zvol");
-    let output = Command::new("ssh")
-        .args([
-            "-lroot",
-            &i.host,
-            "zfs",
-            "rollback",
-            &format!("{}@init", i.zvol_name),
-        ])
-        .output()
-        .await?;
-    if output.status != ExitStatus::from_raw(0) {
-        let stderr = String::from_utf8(output.stderr).unwrap();
-        return Err(Error::CantRollbackZvol(
-            i.host.clone(),
-            "init".to_string(),
-            stderr,
-        ));
-    }
-
-    let nuke: Result<(), Error> = {
-        let host = i.host.clone();
-        let id = i.uuid.clone();
-
-        spawn_blocking(move || {
-            let conn = Connect::open(&format!("qemu+ssh://root@{}/system", host))?;
-
-            let dom = Domain::lookup_by_uuid_string(&conn, &id.to_string())?;
-
-            dom.create()?;
-            Ok(())
-        })
-        .await?
-    };
-    nuke?;
-
-    i.status = "reinit".to_string();
-    conn.execute(
-        "INSERT INTO audit_logs(kind, op, data) VALUES (?1, ?2, ?3)",
-        params!["instance", "reinit", serde_json::to_string(&i)?],
-    )?;
+    i.set_status(&conn, &state.events, InstanceStatus::Reinit, None)?;
+    i.start_boot(&conn)?;
+    state
+        .events
+        .audit(&conn, &actor, "instance", "reinit", &i)?;
 
     Ok(())
 }
@@ -97,18 +74,24 @@ pub async fn reinit(
 pub async fn delete(
     Path(id): Path<Uuid>,
     Extension(state): Extension<Arc<State>>,
-    _: Tailauth,
+    who: Principal,
+    actor: Actor,
 ) -> Result<(), Error> {
     let conn = state.pool.get().await?;
 
     let i = Instance::from_uuid(&conn, id)?;
+    who.check_owns(&i)?;
+    if i.status == InstanceStatus::Migrating {
+        return Err(Error::InstanceBusy(i.status));
+    }
+    provision::check_clones(&*state.exec, &i.host, &i.zvol_name).await?;
 
     let nuke: Result<(), Error> = {
-        let host = i.host.clone();
+        let uri = state.exec.libvirt_uri(&i.host);
         let id = i.uuid.clone();
 
         spawn_blocking(move || {
-            let conn = Connect::open(&format!("qemu+ssh://root@{}/system", host))?;
+            let conn = Connect::open(&uri)?;
 
             let dom = Domain::lookup_by_uuid_string(&conn, &id.to_string())?;
 
@@ -123,21 +106,18 @@ pub async fn delete(
     sleep(Duration::from_millis(500)).await;
 
     debug!("destroying zvol");
-    let output = Command::new("ssh")
-        .args(["-lroot", &i.host, "zfs", "destroy", "-rf", &i.zvol_name])
-        .output()
-        .await?;
-    if output.status != ExitStatus::from_raw(0) {
-        let stderr = String::from_utf8(output.stderr).unwrap();
-        return Err(Error::CantDeleteZvol(i.host.clone(), stderr));
-    }
+    state
+        .exec
+        .run(&i.host, &["zfs", "destroy", "-rf", &i.zvol_name])
+        .await?
+        .check(|stderr| Error::CantDeleteZvol(i.host.clone(), stderr))?;
 
+    jobs::cancel_for_instance(&conn, id)?;
     conn.execute("DELETE FROM instances WHERE uuid = ?1", params![id])?;
     conn.execute("DELETE FROM cloudconfig_seeds WHERE uuid = ?1", params![id])?;
-    conn.execute(
-        "INSERT INTO audit_logs(kind, op, data) VALUES (?1, ?2, ?3)",
-        params!["instance", "delete", serde_json::to_string(&i)?],
-    )?;
+    state
+        .events
+        .audit(&conn, &actor, "instance", "delete", &i)?;
 
     Ok(())
 }
@@ -147,17 +127,74 @@ pub async fn delete(
 pub async fn get_machine(
     Path(id): Path<Uuid>,
     Extension(state): Extension<Arc<State>>,
-    _: Tailauth,
+    _: Principal,
 ) -> Result<Json<Machine>, Error> {
     let conn = state.pool.get().await?;
 
     let mut stmt = conn.prepare("SELECT host FROM instances WHERE uuid = ?1")?;
     let host: String = stmt.query_row(params![id], |row| row.get(0))?;
 
-    let conn = Connect::open(&format!("qemu+ssh://root@{}/system", host))?;
+    let uri = state.exec.libvirt_uri(&host);
+    let machine: Result<Machine, Error> = spawn_blocking(move || {
+        let conn = Connect::open(&uri)?;
+        let dom = Domain::lookup_by_uuid_string(&conn, &id.to_string())?;
+        Machine::try_from(dom)
+    })
+    .await?;
+
+    Ok(Json(machine?))
+}
+
+/// Attaches to an instance's serial console over a WebSocket.
+#[instrument(err, skip(state, ws))]
+pub async fn console(
+    Path(id): Path<Uuid>,
+    Extension(state): Extension<Arc<State>>,
+    who: Principal,
+    actor: Actor,
+    ws: WebSocketUpgrade,
+) -> Result<Response, Error> {
+    let conn = state.pool.get().await?;
+
+    let i = Instance::from_uuid(&conn, id)?;
+    who.check_owns(&i)?;
+
+    // opened before upgrading so failures come back as HTTP errors
+    let uri = state.exec.libvirt_uri(&i.host);
+    let console = spawn_blocking(move || console::open(&uri, id)).await??;
+
+    state
+        .events
+        .audit(&conn, &actor, "instance", "console", &i)?;
+
+    Ok(ws.on_upgrade(move |socket| console::bridge(socket, console)))
+}
+
+/// Proxies an instance's SPICE or VNC display over a WebSocket.
+#[instrument(err, skip(state, ws))]
+pub async fn graphics(
+    Path(id): Path<Uuid>,
+    Extension(state): Extension<Arc<State>>,
+    who: Principal,
+    actor: Actor,
+    ws: WebSocketUpgrade,
+) -> Result<Response, Error> {
+    let conn = state.pool.get().await?;
+
+    let i = Instance::from_uuid(&conn, id)?;
+    who.check_owns(&i)?;
+
+    // connected before upgrading so failures come back as HTTP errors
+    let uri = state.exec.libvirt_uri(&i.host);
+    let display = spawn_blocking(move || graphics::find(&uri, id)).await??;
+    let addr = display.addr(&i.host);
+    let stream = TcpStream::connect(&addr).await?;
+
+    state
+        .events
+        .audit(&conn, &actor, "instance", "graphics", &i)?;
 
-    let dom = Domain::lookup_by_uuid_string(&conn, &id.to_string())?;
-    Ok(Json(Machine::try_from(dom)?))
+    Ok(ws.on_upgrade(move |socket| graphics::bridge(socket, stream, addr)))
 }
 
 #[instrument(err)]
@@ -165,26 +202,25 @@ pub async fn get_machine(
 pub async fn hard_reboot(
     Path(id): Path<Uuid>,
     Extension(state): Extension<Arc<State>>,
-    _: Tailauth,
+    who: Principal,
+    actor: Actor,
 ) -> Result<(), Error> {
     let conn = state.pool.get().await?;
 
-    let i = Instance::from_uuid(&conn, id)?;
-    let vc = Connect::open(&format!("qemu+ssh://root@{}/system", i.host))?;
+    let mut i = Instance::from_uuid(&conn, id)?;
+    who.check_owns(&i)?;
+    i.status.check_transition(InstanceStatus::Rebooting)?;
+    let vc = Connect::open(&state.exec.libvirt_uri(&i.host))?;
 
     let dom = Domain::lookup_by_uuid_string(&vc, &id.to_string())?;
 
     dom.destroy()?;
     dom.create()?;
 
-    conn.execute(
-        "UPDATE instances SET status = ?1 WHERE uuid = ?2",
-        params!["rebooting", id],
-    )?;
-    conn.execute(
-        "INSERT INTO audit_logs(kind, op, data) VALUES (?1, ?2, ?3)",
-        params!["instance", "hard reboot", serde_json::to_string(&i)?],
-    )?;
+    i.set_status(&conn, &state.events, InstanceStatus::Rebooting, None)?;
+    state
+        .events
+        .audit(&conn, &actor, "instance", "hard reboot", &i)?;
 
     Ok(())
 }
@@ -194,24 +230,23 @@ pub async fn hard_reboot(
 pub async fn shutdown(
     Path(id): Path<Uuid>,
     Extension(state): Extension<Arc<State>>,
-    _: Tailauth,
+    who: Principal,
+    actor: Actor,
 ) -> Result<(), Error> {
     let conn = state.pool.get().await?;
 
-    let i = Instance::from_uuid(&conn, id)?;
-    let vc = Connect::open(&format!("qemu+ssh://root@{}/system", i.host))?;
+    let mut i = Instance::from_uuid(&conn, id)?;
+    who.check_owns(&i)?;
+    i.status.check_transition(InstanceStatus::Off)?;
+    let vc = Connect::open(&state.exec.libvirt_uri(&i.host))?;
 
     let dom = Domain::lookup_by_uuid_string(&vc, &id.to_string())?;
     dom.shutdown()?;
 
-    conn.execute(
-        "UPDATE instances SET status = ?1 WHERE uuid = ?2",
-        params!["off", id],
-    )?;
-    conn.execute(
-        "INSERT INTO audit_logs(kind, op, data) VALUES (?1, ?2, ?3)",
-        params!["instance", "shutdown", serde_json::to_string(&i)?],
-    )?;
+    i.set_status(&conn, &state.events, InstanceStatus::Off, None)?;
+    state
+        .events
+        .audit(&conn, &actor, "instance", "shutdown", &i)?;
 
     Ok(())
 }
@@ -221,24 +256,21 @@ pub async fn shutdown(
 pub async fn start(
     Path(id): Path<Uuid>,
     Extension(state): Extension<Arc<State>>,
-    _: Tailauth,
+    who: Principal,
+    actor: Actor,
 ) -> Result<(), Error> {
     let conn = state.pool.get().await?;
 
-    let i = Instance::from_uuid(&conn, id)?;
-    let vc = Connect::open(&format!("qemu+ssh://root@{}/system", i.host))?;
+    let mut i = Instance::from_uuid(&conn, id)?;
+    who.check_owns(&i)?;
+    i.status.check_transition(InstanceStatus::Starting)?;
+    let vc = Connect::open(&state.exec.libvirt_uri(&i.host))?;
 
     let dom = Domain::lookup_by_uuid_string(&vc, &id.to_string())?;
     dom.create()?;
 
-    conn.execute(
-        "UPDATE instances SET status = ?1 WHERE uuid = ?2",
-        params!["starting", id],
-    )?;
-    conn.execute(
-        "INSERT INTO audit_logs(kind, op, data) VALUES (?1, ?2, ?3)",
-        params!["instance", "start", serde_json::to_string(&i)?],
-    )?;
+    i.set_status(&conn, &state.events, InstanceStatus::Starting, None)?;
+    state.events.audit(&conn, &actor, "instance", "start", &i)?;
 
     Ok(())
 }
@@ -248,26 +280,387 @@ pub async fn start(
 pub async fn reboot(
     Path(id): Path<Uuid>,
     Extension(state): Extension<Arc<State>>,
-    _: Tailauth,
+    who: Principal,
+    actor: Actor,
 ) -> Result<(), Error> {
     let conn = state.pool.get().await?;
 
-    let i = Instance::from_uuid(&conn, id)?;
-    let vc = Connect::open(&format!("qemu+ssh://root@{}/system", i.host))?;
+    let mut i = Instance::from_uuid(&conn, id)?;
+    who.check_owns(&i)?;
+    i.status.check_transition(InstanceStatus::Rebooting)?;
+    let vc = Connect::open(&state.exec.libvirt_uri(&i.host))?;
 
     let dom = Domain::lookup_by_uuid_string(&vc, &id.to_string())?;
     dom.reboot(0)?;
 
+    i.set_status(&conn, &state.events, InstanceStatus::Rebooting, None)?;
+    state
+        .events
+        .audit(&conn, &actor, "instance", "reboot", &i)?;
+
+    Ok(())
+}
+
+/// Changes the memory, vCPU count or disk size of an instance. Memory and vCPU
+/// changes are applied to the running domain when libvirt allows it, otherwise they
+/// take effect the next time the instance boots.
+#[instrument(err, skip(cfg, state))]
+#[axum_macros::debug_handler]
+pub async fn resize(
+    Path(id): Path<Uuid>,
+    Extension(cfg): Extension<Arc<Config>>,
+    Extension(state): Extension<Arc<State>>,
+    who: Principal,
+    actor: Actor,
+    Json(details): Json<ResizeInstance>,
+) -> Result<Json<Instance>, Error> {
+    let conn = state.pool.get().await?;
+
+    let mut i = Instance::from_uuid(&conn, id)?;
+    who.check_owns(&i)?;
+    i.status.check_idle()?;
+
+    for (what, val) in [
+        ("memory_mb", details.memory_mb),
+        ("cpus", details.cpus),
+        ("disk_size_gb", details.disk_size_gb),
+    ] {
+        if matches!(val, Some(val) if val <= 0) {
+            return Err(Error::InvalidResize(what.to_string()));
+        }
+    }
+
+    if let Some(disk_size) = details.disk_size_gb {
+        if disk_size < i.disk_size {
+            return Err(Error::CantShrinkDisk(i.disk_size, disk_size));
+        }
+    }
+
+    if let Some(owner) = &i.owner {
+        quota::enforce(
+            &cfg,
+            &conn,
+            owner,
+            Usage {
+                instances: 0,
+                memory_mb: (details.memory_mb.unwrap_or(i.memory) - i.memory) as i64,
+                disk_gb: (details.disk_size_gb.unwrap_or(i.disk_size) - i.disk_size) as i64,
+                cpus: (details.cpus.unwrap_or(i.cpus) - i.cpus) as i64,
+            },
+        )?;
+    }
+
+    if details.memory_mb.is_some() || details.cpus.is_some() {
+        let uri = state.exec.libvirt_uri(&i.host);
+        let memory = details.memory_mb.map(|mb| mb as u64 * 1024);
+        let cpus = details.cpus.map(|cpus| (cpus as u32, i.cpus as u32));
+
+        let result: Result<(), Error> = spawn_blocking(move || {
+            use virt::sys::{
+                VIR_DOMAIN_AFFECT_CONFIG as CONFIG, VIR_DOMAIN_AFFECT_LIVE as LIVE,
+                VIR_DOMAIN_MEM_MAXIMUM, VIR_DOMAIN_VCPU_MAXIMUM,
+            };
+
+            let conn = Connect::open(&uri)?;
+            let dom = Domain::lookup_by_uuid_string(&conn, &id.to_string())?;
+            let active = dom.is_active()?;
+
+            if let Some(kib) = memory {
+                dom.set_memory_flags(kib, VIR_DOMAIN_MEM_MAXIMUM | CONFIG)?;
+                dom.set_memory_flags(kib, CONFIG)?;
+
+                // The balloon can only shrink a running guest, growing it needs a reboot.
+                if active && kib <= dom.get_max_memory()? {
+                    if let Err(why) = dom.set_memory_flags(kib, LIVE) {
+                        debug!("can't change memory live, will apply on next boot: {}", why);
+                    }
+                }
+            }
+
+            if let Some((want, have)) = cpus {
+                if want > have {
+                    dom.set_vcpus_flags(want, VIR_DOMAIN_VCPU_MAXIMUM | CONFIG)?;
+                    dom.set_vcpus_flags(want, CONFIG)?;
+                } else {
+                    dom.set_vcpus_flags(want, CONFIG)?;
+                    dom.set_vcpus_flags(want, VIR_DOMAIN_VCPU_MAXIMUM | CONFIG)?;
+                }
+
+                if active {
+                    if let Err(why) = dom.set_vcpus_flags(want, LIVE) {
+                        debug!("can't change vcpus live, will apply on next boot: {}", why);
+                    }
+                }
+            }
+
+            Ok(())
+        })
+        .await?;
+        result?;
+    }
+
+    if let Some(disk_size) = details.disk_size_gb {
+        if disk_size != i.disk_size {
+            debug!("growing zvol");
+            state
+                .exec
+                .run(
+                    &i.host,
+                    &[
+                        "zfs",
+                        "set",
+                        &format!("volsize={}G", disk_size),
+                        &i.zvol_name,
+                    ],
+                )
+                .await?
+                .check(|stderr| Error::CantResizeZvol(i.host.clone(), stderr))?;
+        }
+    }
+
+    i.memory = details.memory_mb.unwrap_or(i.memory);
+    i.cpus = details.cpus.unwrap_or(i.cpus);
+    i.disk_size = details.disk_size_gb.unwrap_or(i.disk_size);
+
     conn.execute(
-        "UPDATE instances SET status = ?1 WHERE uuid = ?2",
-        params!["rebooting", id],
+        "UPDATE instances SET memory = ?1, cpus = ?2, disk_size = ?3 WHERE uuid = ?4",
+        params![i.memory, i.cpus, i.disk_size, id],
+    )?;
+    state
+        .events
+        .audit(&conn, &actor, "instance", "resize", &i)?;
+
+    Ok(Json(i))
+}
+
+/// Returns if an instance's domain uses a SATA disk and if it is running.
+async fn domain_info(state: &State, i: &Instance) -> Result<(bool, bool), Error> {
+    let uri = state.exec.libvirt_uri(&i.host);
+    let id = i.uuid;
+
+    spawn_blocking(move || {
+        let conn = Connect::open(&uri)?;
+        let dom = Domain::lookup_by_uuid_string(&conn, &id.to_string())?;
+
+        Ok((
+            dom.get_xml_desc(0)?.contains(r#"bus="sata""#),
+            dom.is_active()?,
+        ))
+    })
+    .await?
+}
+
+/// Makes a new instance from a snapshot of an existing one. The clone gets its own
+/// UUID, MAC address and cloud-init seed, so it boots up with a new identity.
+#[instrument(err, skip(cfg, state))]
+#[axum_macros::debug_handler]
+pub async fn clone(
+    Path(id): Path<Uuid>,
+    Extension(cfg): Extension<Arc<Config>>,
+    Extension(state): Extension<Arc<State>>,
+    who: Principal,
+    actor: Actor,
+    Json(details): Json<CloneInstance>,
+) -> Result<Json<Instance>, Error> {
+    let conn = state.pool.get().await?;
+
+    let src = Instance::from_uuid(&conn, id)?;
+    who.check_owns(&src)?;
+    src.status.check_idle()?;
+    quota::enforce(
+        &cfg,
+        &conn,
+        &who.login_name,
+        Usage::instance(src.memory, src.disk_size, src.cpus),
     )?;
+    let snapshot = details.snapshot.unwrap_or("init".into());
+
+    if !snapshots::list_for(&state, &src)
+        .await?
+        .iter()
+        .any(|s| s.name == snapshot)
+    {
+        return Err(Error::SnapshotDoesntExist(snapshot));
+    }
+
+    let distro = Distro::from_name(&conn, src.distro.clone())?;
+
+    let src_seed = Seed::get(&conn, id)?;
+    let seed = Seed {
+        user_data: details.user_data.unwrap_or(src_seed.user_data),
+        vars: details.vars.unwrap_or(src_seed.vars),
+    };
+
+    let (sata, _) = domain_info(&state, &src).await?;
+    let zvol_prefix = src
+        .zvol_name
+        .rsplit_once('/')
+        .map(|(prefix, _)| prefix.to_string())
+        .ok_or_else(|| Error::Catchall(format!("zvol name {} has no prefix", src.zvol_name)))?;
+
+    let details = NewInstance {
+        name: details.name.or(rotbart::unique_monster()),
+        memory_mb: Some(src.memory),
+        cpus: Some(src.cpus),
+        host: Some(src.host.clone()),
+        disk_size_gb: Some(src.disk_size),
+        zvol_prefix: Some(zvol_prefix),
+        distro: src.distro.clone(),
+        sata: Some(sata),
+        user_data: Some(seed.user_data.clone()),
+        join_tailnet: src.join_tailnet,
+        vars: seed.vars.clone(),
+    };
+
+    let mut ins = Instance {
+        uuid: Uuid::new_v4(),
+        name: details.name.clone().unwrap(),
+        host: src.host.clone(),
+        mac_address: random_mac(),
+        memory: src.memory,
+        disk_size: src.disk_size,
+        zvol_name: format!(
+            "{}/{}",
+            details.zvol_prefix.clone().unwrap(),
+            details.name.clone().unwrap()
+        ),
+        status: InstanceStatus::Init,
+        status_reason: None,
+        distro: src.distro.clone(),
+        join_tailnet: src.join_tailnet,
+        cpus: src.cpus,
+        owner: Some(who.login_name.clone()),
+        ip_address: None,
+        subnet: None,
+        boot_stage: None,
+        boot_stage_at: None,
+        boot_started_at: None,
+    };
+    ipam::assign(&cfg.subnets, &conn, &mut ins)?;
+    // fail now rather than when the clone boots
+    seed.render(&conn, &ins)?;
+
     conn.execute(
-        "INSERT INTO audit_logs(kind, op, data) VALUES (?1, ?2, ?3)",
-        params!["instance", "reboot", serde_json::to_string(&i)?],
+        "INSERT INTO instances(uuid, name, host, mac_address, memory, disk_size, zvol_name, status, distro, join_tailnet, cpus, owner, ip_address, subnet) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+        params![
+            ins.uuid,
+            ins.name,
+            ins.host,
+            ins.mac_address,
+            ins.memory,
+            ins.disk_size,
+            ins.zvol_name,
+            ins.status,
+            ins.distro,
+            ins.join_tailnet,
+            ins.cpus,
+            ins.owner,
+            ins.ip_address,
+            ins.subnet,
+        ],
+    )?;
+    state.events.audit(
+        &conn,
+        &actor,
+        "instance",
+        "clone",
+        &serde_json::json!({
+            "uuid": ins.uuid.to_string(),
+            "name": ins.name,
+            "source": src.uuid.to_string(),
+            "snapshot": snapshot,
+        }),
+    )?;
+    seed.insert(&conn, ins.uuid)?;
+
+    jobs::enqueue(
+        &conn,
+        &state.events,
+        &actor,
+        ins.uuid,
+        provision::KIND,
+        &provision::Step::CloneZvol.to_string(),
+        &Provision {
+            details,
+            distro,
+            mac_addr: ins.mac_address.clone(),
+            clone_from: Some(format!("{}@{}", src.zvol_name, snapshot)),
+        },
     )?;
 
-    Ok(())
+    Ok(Json(ins))
+}
+
+/// Moves an instance to another host in the background, returning the job doing it.
+#[instrument(err, skip(cfg, state))]
+#[axum_macros::debug_handler]
+pub async fn migrate(
+    Path(id): Path<Uuid>,
+    Extension(cfg): Extension<Arc<Config>>,
+    Extension(state): Extension<Arc<State>>,
+    who: Principal,
+    actor: Actor,
+    Json(details): Json<MigrateInstance>,
+) -> Result<Json<Job>, Error> {
+    let conn = state.pool.get().await?;
+
+    let i = Instance::from_uuid(&conn, id)?;
+    who.check_owns(&i)?;
+    i.status.check_transition(InstanceStatus::Migrating)?;
+
+    if !cfg.hosts.contains(&details.host) {
+        return Err(Error::HostDoesntExist(details.host));
+    }
+    if details.host == i.host {
+        return Err(Error::AlreadyOnHost(details.host));
+    }
+    ipam::check_host(&cfg.subnets, &i, &details.host)?;
+    // the source zvol is destroyed at the end, which clones of it would stop
+    provision::check_clones(&*state.exec, &i.host, &i.zvol_name).await?;
+
+    let (sata, was_active) = domain_info(&state, &i).await?;
+
+    let live = details.live.unwrap_or(false) && was_active;
+    let m = Migrate {
+        from: i.host.clone(),
+        to: details.host.clone(),
+        live,
+        sata,
+        was_active,
+    };
+
+    // done in one transaction so two requests can't both start moving the instance
+    conn.execute_batch("BEGIN IMMEDIATE")?;
+    let job = start_migration(&conn, &state, &actor, id, &m);
+    conn.execute_batch(if job.is_ok() { "COMMIT" } else { "ROLLBACK" })?;
+
+    Ok(Json(Job::get(&conn, job?)?))
+}
+
+/// Marks an instance as migrating and queues the job that moves it, unless it
+/// already has a job that hasn't finished.
+fn start_migration(
+    conn: &PooledConnection<'_, RusqliteConnectionManager>,
+    state: &State,
+    actor: &Actor,
+    id: Uuid,
+    m: &Migrate,
+) -> Result<i64, Error> {
+    let mut i = Instance::from_uuid(conn, id)?;
+    if jobs::has_unfinished(conn, id)? {
+        return Err(Error::InstanceBusy(i.status));
+    }
+    i.set_status(conn, &state.events, InstanceStatus::Migrating, None)?;
+
+    jobs::enqueue(
+        conn,
+        &state.events,
+        actor,
+        id,
+        migrate::KIND,
+        &migrate::Step::FIRST.to_string(),
+        m,
+    )
 }
 
 #[instrument(err)]
@@ -275,7 +668,7 @@ pub async fn reboot(
 pub async fn get_by_name(
     Path(name): Path<String>,
     Extension(state): Extension<Arc<State>>,
-    _: Tailauth,
+    _: Principal,
 ) -> Result<Json<Instance>, Error> {
     let conn = state.pool.get().await?;
 
@@ -287,68 +680,90 @@ pub async fn get_by_name(
 pub async fn get(
     Path(id): Path<Uuid>,
     Extension(state): Extension<Arc<State>>,
-    _: Tailauth,
+    _: Principal,
 ) -> Result<Json<Instance>, Error> {
     let conn = state.pool.get().await?;
 
     Ok(Json(Instance::from_uuid(&conn, id)?))
 }
 
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct ListParams {
+    /// Only list instances owned by this Tailscale login name.
+    pub owner: Option<String>,
+}
+
 #[instrument(err)]
 #[axum_macros::debug_handler]
 pub async fn list(
     Extension(state): Extension<Arc<State>>,
-    _: Tailauth,
+    Query(params): Query<ListParams>,
+    _: Principal,
 ) -> Result<Json<Vec<Instance>>, Error> {
     let conn = state.pool.get().await?;
 
-    let mut result: Vec<Instance> = Vec::new();
+    let result = match params.owner {
+        Some(owner) => Instance::get_owned_by(&conn, &owner)?,
+        None => Instance::get_all(&conn)?,
+    };
 
-    let mut stmt = conn.prepare(
-        "SELECT uuid, name, host, mac_address, memory, disk_size, zvol_name, status, distro, join_tailnet FROM instances",
-    )?;
-    let instances = stmt.query_map(params![], |row| {
-        Ok(Instance {
-            uuid: row.get(0)?,
-            name: row.get(1)?,
-            host: row.get(2)?,
-            mac_address: row.get(3)?,
-            memory: row.get(4)?,
-            disk_size: row.get(5)?,
-            zvol_name: row.get(6)?,
-            status: row.get(7)?,
-            distro: row.get(8)?,
-            join_tailnet: row.get(9)?,
-        })
-    })?;
+    Ok(Json(result))
+}
+
+/// Hands an instance over to another user. The instance counts against the new
+/// owner's quota from then on, so it has to fit in it.
+#[instrument(err, skip(cfg, state))]
+#[axum_macros::debug_handler]
+pub async fn transfer(
+    Path(id): Path<Uuid>,
+    Extension(cfg): Extension<Arc<Config>>,
+    Extension(state): Extension<Arc<State>>,
+    who: Principal,
+    actor: Actor,
+    Json(details): Json<TransferInstance>,
+) -> Result<Json<Instance>, Error> {
+    let conn = state.pool.get().await?;
 
-    for instance in instances {
-        result.push(instance?);
+    let mut i = Instance::from_uuid(&conn, id)?;
+    who.check_owns(&i)?;
+
+    if i.owner.as_deref() != Some(details.owner.as_str()) {
+        quota::enforce(
+            &cfg,
+            &conn,
+            &details.owner,
+            Usage::instance(i.memory, i.disk_size, i.cpus),
+        )?;
     }
 
-    Ok(Json(result))
+    conn.execute(
+        "UPDATE instances SET owner = ?1 WHERE uuid = ?2",
+        params![details.owner, id],
+    )?;
+    i.owner = Some(details.owner);
+    state
+        .events
+        .audit(&conn, &actor, "instance", "transfer", &i)?;
+
+    Ok(Json(i))
 }
 
-#[instrument(err)]
+/// Makes a new instance. If no host is given, the scheduler picks one with
+/// `placementStrategy`.
+#[instrument(err, skip(cfg, state))]
 pub async fn create(
+    Extension(cfg): Extension<Arc<Config>>,
     Extension(state): Extension<Arc<State>>,
-    Extension(config): Extension<Arc<Config>>,
-    _: Tailauth,
+    who: Principal,
+    actor: Actor,
     Json(details): Json<NewInstance>,
 ) -> Result<Json<Instance>, Error> {
     let id = Uuid::new_v4();
 
-    let addrs: Vec<SocketAddr> = lookup_host(details.host.clone() + ":22".into())
-        .await?
-        .collect();
-    if addrs.len() == 0 {
-        return Err(Error::HostDoesntExist(details.host));
-    }
-
     let conn = state.pool.get().await?;
 
     let distro = conn.query_row(
-        "SELECT name, download_url, sha256sum, min_size, format FROM distros WHERE name = ?1",
+        "SELECT name, download_url, sha256sum, min_size, format, family FROM distros WHERE name = ?1",
         params![details.distro.clone()],
         |row| {
             Ok(Distro {
@@ -357,23 +772,55 @@ pub async fn create(
                 sha256sum: row.get(2)?,
                 min_size: row.get(3)?,
                 format: row.get(4)?,
+                family: row.get(5)?,
             })
         },
     )?;
 
+    let memory_mb = details.memory_mb.unwrap_or(512);
+    let disk_size_gb = details.disk_size_gb.unwrap_or(distro.min_size);
+    let cpus = details.cpus.unwrap_or(2);
+
+    quota::enforce(
+        &cfg,
+        &conn,
+        &who.login_name,
+        Usage::instance(memory_mb, disk_size_gb, cpus),
+    )?;
+
+    let host = match details.host.clone() {
+        Some(host) => host,
+        None => {
+            let strategy = scheduler::strategy(&cfg.placement_strategy)?;
+            let placement =
+                scheduler::place(&cfg, &state, &*strategy, memory_mb, disk_size_gb).await?;
+            debug!(host = ?placement.host, strategy = %placement.strategy, "placed instance");
+            placement
+                .host
+                .clone()
+                .ok_or_else(|| Error::NoHostFits(placement.explain()))?
+        }
+    };
+
+    let addrs: Vec<SocketAddr> = lookup_host(host.clone() + ":22".into()).await?.collect();
+    if addrs.len() == 0 {
+        return Err(Error::HostDoesntExist(host));
+    }
+
     let details = NewInstance {
         name: details.name.or(rotbart::unique_monster()),
-        memory_mb: details.memory_mb.or(Some(512)),
-        host: details.host.clone(),
-        disk_size_gb: details.disk_size_gb.or(Some(distro.min_size)),
+        memory_mb: Some(memory_mb),
+        host: Some(host),
+        disk_size_gb: Some(disk_size_gb),
         zvol_prefix: details.zvol_prefix.or(Some("rpool/safe/vms".into())),
         distro: distro.name.clone(),
         sata: details.sata.or(Some(false)),
-        cpus: details.cpus.or(Some(2)),
+        cpus: Some(cpus),
         user_data: details
             .user_data
             .or(Some(include_str!("../../var/xe-base.yaml").into())),
         join_tailnet: details.join_tailnet.clone(),
+        vars: details.vars,
     };
 
     let mac_addr = random_mac();
@@ -383,23 +830,39 @@ pub async fn create(
         details.name.clone().unwrap()
     );
 
-    let ins = Instance {
+    let mut ins = Instance {
         uuid: id,
         name: details.name.clone().unwrap(),
-        host: details.host.clone(),
+        host: details.host.clone().unwrap(),
         memory: details.memory_mb.unwrap(),
         disk_size: details.disk_size_gb.unwrap(),
         mac_address: mac_addr.clone(),
         zvol_name: zvol_name.clone(),
-        status: "init".into(),
+        status: InstanceStatus::Init,
+        status_reason: None,
         distro: details.distro.clone(),
         join_tailnet: details.join_tailnet.clone(),
+        cpus: details.cpus.unwrap(),
+        owner: Some(who.login_name.clone()),
+        ip_address: None,
+        subnet: None,
+        boot_stage: None,
+        boot_stage_at: None,
+        boot_started_at: None,
     };
+    ipam::assign(&cfg.subnets, &conn, &mut ins)?;
+
+    let seed = Seed {
+        user_data: details.user_data.clone().unwrap(),
+        vars: details.vars.clone(),
+    };
+    // fail now rather than when the instance boots
+    seed.render(&conn, &ins)?;
 
     {
         let ins = ins.clone();
         conn.execute(
-            "INSERT INTO instances(uuid, name, host, mac_address, memory, disk_size, zvol_name, status, distro, join_tailnet) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            "INSERT INTO instances(uuid, name, host, mac_address, memory, disk_size, zvol_name, status, distro, join_tailnet, cpus, owner, ip_address, subnet) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
             params![
                 ins.uuid,
                 ins.name,
@@ -411,221 +874,33 @@ pub async fn create(
                 ins.status,
                 ins.distro,
                 ins.join_tailnet,
+                ins.cpus,
+                ins.owner,
+                ins.ip_address,
+                ins.subnet,
             ],
         )?;
-        conn.execute(
-            "INSERT INTO audit_logs(kind, op, data) VALUES (?1, ?2, ?3)",
-            params!["instance", "create", serde_json::to_string(&ins)?],
-        )?;
-
-        conn.execute(
-            "INSERT INTO cloudconfig_seeds(uuid, user_data) VALUES (?1, ?2)",
-            params![id.clone(), details.user_data.clone().unwrap()],
-        )?;
-    }
+        state
+            .events
+            .audit(&conn, &actor, "instance", "create", &ins)?;
 
-    drop(conn);
-
-    {
-        let ins = ins.clone();
-        tokio::spawn(async move {
-            if let Err(why) = make_instance(config, state, details, ins, distro, mac_addr, id).await
-            {
-                error!("can't make instance: {}", why);
-            }
-        });
-    }
-
-    Ok(Json(ins))
-}
-
-#[instrument(ret, level = "debug", err, skip(config, state, details, id, mac_addr))]
-async fn make_instance(
-    config: Arc<Config>,
-    state: Arc<State>,
-    details: NewInstance,
-    ins: Instance,
-    distro: Distro,
-    mac_addr: String,
-    id: Uuid,
-) -> Result<(), Error> {
-    let conn = state.pool.get().await?;
-    let mut ins = ins.clone();
-
-    debug!("name: {}", details.name.as_ref().unwrap());
-
-    debug!("checking if image exists");
-    let output = Command::new("ssh")
-        .args([
-            "-oStrictHostKeyChecking=accept-new",
-            &details.host.clone(),
-            "stat",
-            &format!("$HOME/.cache/within/mkvm/qcow2/{}", distro.sha256sum),
-        ])
-        .output()
-        .await?;
-    if output.status != ExitStatus::from_raw(0) {
-        debug!("downloading image");
-        ins.status = "downloading image".into();
-        conn.execute(
-            "UPDATE instances SET status = ?1 WHERE uuid = ?2",
-            params![ins.status, id],
-        )?;
-        conn.execute(
-            "INSERT INTO audit_logs(kind, op, data) VALUES (?1, ?2, ?3)",
-            params!["instance", ins.status, serde_json::to_string(&ins)?],
-        )?;
-        let output = Command::new("ssh")
-            .args([
-                &details.host.clone(),
-                "wget",
-                "-O",
-                &format!("$HOME/.cache/within/mkvm/qcow2/{}", distro.sha256sum),
-                &distro.download_url,
-            ])
-            .output()
-            .await?;
-        if output.status != ExitStatus::from_raw(0) {
-            let stderr = String::from_utf8(output.stderr).unwrap();
-
-            Command::new("ssh")
-                .args([
-                    "-oStrictHostKeyChecking=accept-new",
-                    &details.host.clone(),
-                    "rm",
-                    &format!("$HOME/.cache/within/mkvm/qcow2/{}", distro.sha256sum),
-                ])
-                .status()
-                .await?;
-
-            return Err(Error::CantDownloadImage(
-                distro.download_url.clone(),
-                stderr,
-            ));
-        }
-    }
-    debug!("making zvol");
-    let output = Command::new("ssh")
-        .args([
-            "-lroot",
-            "-oStrictHostKeyChecking=accept-new",
-            &details.host.clone(),
-            "zfs",
-            "create",
-            "-V",
-            &format!("{}G", details.disk_size_gb.unwrap()),
-            &format!(
-                "{}/{}",
-                details.zvol_prefix.as_ref().unwrap(),
-                &details.name.as_ref().unwrap()
-            ),
-        ])
-        .output()
-        .await?;
-    if output.status != ExitStatus::from_raw(0) {
-        let stderr = String::from_utf8(output.stderr).unwrap();
-        return Err(Error::CantMakeZvol(details.host.clone(), stderr));
-    }
-    debug!("hydrating zvol");
-    ins.status = "hydrating zvol".into();
-    conn.execute(
-        "UPDATE instances SET status = ?1 WHERE uuid = ?2",
-        params![ins.status, id],
-    )?;
-    conn.execute(
-        "INSERT INTO audit_logs(kind, op, data) VALUES (?1, ?2, ?3)",
-        params!["instance", ins.status, serde_json::to_string(&ins)?],
-    )?;
-    let output = Command::new("ssh")
-        .args([
-            "-lroot",
-            "-oStrictHostKeyChecking=accept-new",
-            &details.host.clone(),
-            "qemu-img",
-            "convert",
-            "-O",
-            "raw",
-            &format!("/home/cadey/.cache/within/mkvm/qcow2/{}", distro.sha256sum),
-            &format!(
-                "/dev/zvol/{}/{}",
-                details.zvol_prefix.as_ref().unwrap(),
-                &details.name.as_ref().unwrap()
-            ),
-        ])
-        .output()
-        .await?;
-    if output.status != ExitStatus::from_raw(0) {
-        let stderr = String::from_utf8(output.stderr).unwrap();
-        return Err(Error::CantHydrateZvol(details.host.clone(), stderr));
-    }
-    debug!("making init snapshot");
-    let output = Command::new("ssh")
-        .args([
-            "-lroot",
-            "-oStrictHostKeyChecking=accept-new",
-            &details.host.clone(),
-            "zfs",
-            "snapshot",
-            &format!(
-                "{}/{}@init",
-                details.zvol_prefix.as_ref().unwrap(),
-                &details.name.as_ref().unwrap()
-            ),
-        ])
-        .output()
-        .await?;
-    if output.status != ExitStatus::from_raw(0) {
-        let stderr = String::from_utf8(output.stderr).unwrap();
-        return Err(Error::CantMakeInitSnapshot(details.host.clone(), stderr));
+        seed.insert(&conn, id)?;
     }
 
-    let mut buf: Vec<u8> = vec![];
-
-    debug!("rendering xml");
-    crate::templates::base_xml(
-        &mut buf,
-        details.name.clone().unwrap(),
-        id.to_string(),
-        mac_addr.clone(),
-        details.zvol_prefix.clone().unwrap(),
-        details.sata.unwrap(),
-        details.memory_mb.unwrap() * 1024,
-        details.cpus.unwrap(),
-        format!("{}/api/cloudinit/{}/", config.clone().base_url, id),
-        config.qemu_path.clone(),
-    )?;
-
-    let buf = String::from_utf8(buf).unwrap();
-    trace!("libvirt xml:\n{}", buf);
-
-    let addr: Result<(), Error> = {
-        let details = details.clone();
-        spawn_blocking(move || {
-            debug!("connecting to host");
-            let lc = Connect::open(&format!("qemu+ssh://root@{}/system", details.host.clone()))?;
-
-            debug!("defining domain");
-            let dom = Domain::define_xml(&lc, &buf)?;
-
-            debug!("starting domain");
-            dom.create()?;
-            Ok(())
-        })
-        .await?
-    };
-    let addr = addr?;
-
-    debug!("ip: {:?}", addr);
-
-    ins.status = "waiting for cloud-init".into();
-    conn.execute(
-        "UPDATE instances SET status = ?1 WHERE uuid = ?2",
-        params![ins.status, id],
-    )?;
-    conn.execute(
-        "INSERT INTO audit_logs(kind, op, data) VALUES (?1, ?2, ?3)",
-        params!["instance", ins.status, serde_json::to_string(&ins)?],
+    jobs::enqueue(
+        &conn,
+        &state.events,
+        &actor,
+        id,
+        provision::KIND,
+        &provision::Step::FIRST.to_string(),
+        &Provision {
+            details,
+            distro,
+            mac_addr,
+            clone_from: None,
+        },
     )?;
 
-    Ok(())
+    Ok(Json(ins))
 }
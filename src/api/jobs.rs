@@ -0,0 +1,71 @@
+use crate::{
+    models::{Instance, Job},
+    principal::{Actor, Principal},
+    Error, Result, State,
+};
+use axum::{
+    extract::{Extension, Path, Query},
+    Json,
+};
+use chrono::Utc;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use uuid::Uuid;
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct ListParams {
+    /// Only show jobs in this state (pending, running, done, failed or cancelled).
+    pub state: Option<String>,
+    /// Only show jobs for this instance.
+    pub uuid: Option<Uuid>,
+}
+
+#[instrument(err, skip(state))]
+pub async fn list(
+    Extension(state): Extension<Arc<State>>,
+    Query(params): Query<ListParams>,
+    _: Principal,
+) -> Result<Json<Vec<Job>>> {
+    let conn = state.pool.get().await?;
+
+    Ok(Json(Job::list(&conn, params.state, params.uuid)?))
+}
+
+#[instrument(err, skip(state))]
+pub async fn get(
+    Path(id): Path<i64>,
+    Extension(state): Extension<Arc<State>>,
+    _: Principal,
+) -> Result<Json<Job>> {
+    let conn = state.pool.get().await?;
+
+    Ok(Json(Job::get(&conn, id)?))
+}
+
+/// Puts a failed job back into the queue at the step it failed on.
+#[instrument(err, skip(state))]
+pub async fn retry(
+    Path(id): Path<i64>,
+    Extension(state): Extension<Arc<State>>,
+    who: Principal,
+    actor: Actor,
+) -> Result<Json<Job>> {
+    let conn = state.pool.get().await?;
+
+    let job = Job::get(&conn, id)?;
+    who.check_owns(&Instance::from_uuid(&conn, job.uuid)?)?;
+
+    let retried = conn.execute(
+        "UPDATE jobs SET state = 'pending', attempts = 0, run_after = ?2, updated_at = ?2 WHERE id = ?1 AND state = 'failed'",
+        params![id, Utc::now().timestamp()],
+    )?;
+    if retried != 1 {
+        return Err(Error::JobNotFailed(id, Job::get(&conn, id)?.state));
+    }
+    let job = Job::get(&conn, id)?;
+
+    state.events.audit(&conn, &actor, "job", "retry", &job)?;
+
+    Ok(Json(job))
+}
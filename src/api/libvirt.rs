@@ -1,9 +1,13 @@
-use crate::{Config, Error, Result};
+use crate::{Config, Error, Result, State};
 use axum::{extract::Extension, Json};
 use serde::{Deserialize, Serialize};
 use std::{convert::TryFrom, sync::Arc};
+use tokio::task::spawn_blocking;
 use virt::{connect::Connect, domain::Domain};
 
+/// How long to wait for each guest agent when looking up addresses, in seconds.
+const AGENT_TIMEOUT: i32 = 1;
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Machine {
     pub name: String,
@@ -15,23 +19,20 @@ pub struct Machine {
     pub cpus: u32,
 }
 
+/// Talks to libvirt and the guest agent, so run it on a blocking thread.
 impl TryFrom<Domain> for Machine {
     type Error = Error;
 
     fn try_from(dom: Domain) -> Result<Self, Self::Error> {
+        // the guest agent knows about every interface, fall back to DHCP leases for
+        // guests that don't run it
         let addr: Option<String> = if dom.is_active()? {
-            let mut addr: Vec<String> = dom
-                .interface_addresses(virt_sys::VIR_DOMAIN_INTERFACE_ADDRESSES_SRC_LEASE, 0)?
-                .into_iter()
-                .map(|iface| iface.addrs.clone())
-                .filter(|addrs| addrs.get(0).is_some())
-                .map(|addrs| addrs.get(0).unwrap().clone().addr)
-                .collect();
-
-            if addr.get(0).is_none() {
-                None
-            } else {
-                Some(addr.swap_remove(0))
+            match crate::agent::interfaces_within(&dom, AGENT_TIMEOUT)
+                .ok()
+                .and_then(|ifaces| crate::agent::first_addr(&ifaces))
+            {
+                Some(addr) => Some(addr),
+                None => lease_addr(&dom)?,
             }
         } else {
             None
@@ -54,14 +55,41 @@ impl TryFrom<Domain> for Machine {
     }
 }
 
-#[instrument(err, skip(cfg))]
-pub async fn get_machines(Extension(cfg): Extension<Arc<Config>>) -> Result<Json<Vec<Machine>>> {
+fn lease_addr(dom: &Domain) -> Result<Option<String>> {
+    let mut addr: Vec<String> = dom
+        .interface_addresses(virt_sys::VIR_DOMAIN_INTERFACE_ADDRESSES_SRC_LEASE, 0)?
+        .into_iter()
+        .map(|iface| iface.addrs.clone())
+        .filter(|addrs| addrs.get(0).is_some())
+        .map(|addrs| addrs.get(0).unwrap().clone().addr)
+        .collect();
+
+    if addr.get(0).is_none() {
+        Ok(None)
+    } else {
+        Ok(Some(addr.swap_remove(0)))
+    }
+}
+
+#[instrument(err, skip(cfg, state))]
+pub async fn get_machines(
+    Extension(cfg): Extension<Arc<Config>>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<Json<Vec<Machine>>> {
+    // every host is listed at once, each on its own blocking thread
+    let listings: Vec<_> = cfg
+        .hosts
+        .iter()
+        .map(|host| {
+            let uri = state.exec.libvirt_uri(host);
+            let host = host.to_string();
+            spawn_blocking(move || list_all_vms(&uri, host))
+        })
+        .collect();
+
     let mut result = Vec::new();
-    for host in &cfg.hosts {
-        result.extend_from_slice(&list_all_vms(
-            &format!("qemu+ssh://root@{}/system", host),
-            host.to_string(),
-        )?);
+    for listing in listings {
+        result.extend(listing.await??);
     }
     Ok(Json(result))
 }
@@ -1,5 +1,15 @@
+pub mod agent;
 pub mod audit;
 pub mod cloudinit;
 pub mod distros;
+pub mod events;
 pub mod instances;
+pub mod jobs;
 pub mod libvirt;
+pub mod placement;
+pub mod quotas;
+pub mod reconcile;
+pub mod snapshots;
+pub mod sshkeys;
+pub mod tokens;
+pub mod users;
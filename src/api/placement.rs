@@ -0,0 +1,61 @@
+use crate::{
+    models::Distro,
+    principal::Principal,
+    scheduler::{self, Placement},
+    Config, Error, Result, State,
+};
+use axum::{
+    extract::{Extension, Query},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct PlacementParams {
+    /// Memory the instance would have, defaults to 512 MB.
+    pub memory_mb: Option<i32>,
+    /// Disk size the instance would have, defaults to the distro's minimum size like
+    /// it does when making one.
+    pub disk_size_gb: Option<i32>,
+    /// Distro the instance would use. Needed if `disk_size_gb` isn't set.
+    pub distro: Option<String>,
+    /// Strategy to rank hosts with, defaults to the configured `placementStrategy`.
+    pub strategy: Option<String>,
+}
+
+/// Shows which host a new instance would be put on and why, without making it.
+#[instrument(err, skip(cfg, state))]
+pub async fn dry_run(
+    Extension(cfg): Extension<Arc<Config>>,
+    Extension(state): Extension<Arc<State>>,
+    Query(params): Query<PlacementParams>,
+    _: Principal,
+) -> Result<Json<Placement>> {
+    let strategy = scheduler::strategy(
+        params
+            .strategy
+            .as_deref()
+            .unwrap_or(&cfg.placement_strategy),
+    )?;
+
+    let disk_size_gb = match (params.disk_size_gb, params.distro) {
+        (Some(size), _) => size,
+        (None, Some(distro)) => {
+            let conn = state.pool.get().await?;
+            Distro::from_name(&conn, distro)?.min_size
+        }
+        (None, None) => return Err(Error::UnknownDiskSize),
+    };
+
+    Ok(Json(
+        scheduler::place(
+            &cfg,
+            &state,
+            &*strategy,
+            params.memory_mb.unwrap_or(512),
+            disk_size_gb,
+        )
+        .await?,
+    ))
+}
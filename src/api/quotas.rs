@@ -0,0 +1,95 @@
+use crate::{
+    config::Quota,
+    principal::{Actor, Principal},
+    quota::{self, Report},
+    Config, Result, State,
+};
+use axum::{
+    extract::{Extension, Path},
+    Json,
+};
+use std::sync::Arc;
+
+/// Shows the caller's own quota and usage.
+#[instrument(err, skip(cfg, state))]
+pub async fn me(
+    Extension(cfg): Extension<Arc<Config>>,
+    Extension(state): Extension<Arc<State>>,
+    who: Principal,
+) -> Result<Json<Report>> {
+    let conn = state.pool.get().await?;
+
+    Ok(Json(quota::report(&cfg, &conn, &who.login_name)?))
+}
+
+#[instrument(err, skip(cfg, state))]
+pub async fn list(
+    Extension(cfg): Extension<Arc<Config>>,
+    Extension(state): Extension<Arc<State>>,
+    _: Principal,
+) -> Result<Json<Vec<Report>>> {
+    let conn = state.pool.get().await?;
+
+    Ok(Json(quota::report_all(&cfg, &conn)?))
+}
+
+#[instrument(err, skip(cfg, state))]
+pub async fn get(
+    Path(login_name): Path<String>,
+    Extension(cfg): Extension<Arc<Config>>,
+    Extension(state): Extension<Arc<State>>,
+    _: Principal,
+) -> Result<Json<Report>> {
+    let conn = state.pool.get().await?;
+
+    Ok(Json(quota::report(&cfg, &conn, &login_name)?))
+}
+
+/// Overrides the configured quota for a user. Limits left out are unlimited.
+#[instrument(err, skip(cfg, state))]
+pub async fn set(
+    Path(login_name): Path<String>,
+    Extension(cfg): Extension<Arc<Config>>,
+    Extension(state): Extension<Arc<State>>,
+    actor: Actor,
+    Json(over): Json<Quota>,
+) -> Result<Json<Report>> {
+    let conn = state.pool.get().await?;
+
+    quota::set_override(&conn, &login_name, &over)?;
+    state.events.audit(
+        &conn,
+        &actor,
+        "quota",
+        "override",
+        &serde_json::json!({
+            "name": login_name,
+            "quota": over,
+        }),
+    )?;
+
+    Ok(Json(quota::report(&cfg, &conn, &login_name)?))
+}
+
+/// Removes a user's override so the configured quota applies again.
+#[instrument(err, skip(cfg, state))]
+pub async fn clear(
+    Path(login_name): Path<String>,
+    Extension(cfg): Extension<Arc<Config>>,
+    Extension(state): Extension<Arc<State>>,
+    actor: Actor,
+) -> Result<Json<Report>> {
+    let conn = state.pool.get().await?;
+
+    if quota::clear_override(&conn, &login_name)? {
+        state.events.audit(
+            &conn,
+            &actor,
+            "quota",
+            "clear",
+            &serde_json::json!({ "name": login_name }),
+        )?;
+    }
+
+    Ok(Json(quota::report(&cfg, &conn, &login_name)?))
+}
@@ -0,0 +1,27 @@
+use crate::{principal::Principal, reconcile::Report, Config, Result, State};
+use axum::{extract::Extension, Json};
+use std::sync::Arc;
+
+/// The last drift report, checking the hosts now if the reconciler hasn't run yet.
+#[instrument(err, skip(cfg, state))]
+pub async fn get(
+    Extension(cfg): Extension<Arc<Config>>,
+    Extension(state): Extension<Arc<State>>,
+    _: Principal,
+) -> Result<Json<Report>> {
+    if let Some(report) = state.drift.read().await.clone() {
+        return Ok(Json(report));
+    }
+
+    Ok(Json(crate::reconcile::reconcile(&cfg, &state).await?))
+}
+
+/// Checks every host right now instead of waiting for the next run.
+#[instrument(err, skip(cfg, state))]
+pub async fn run(
+    Extension(cfg): Extension<Arc<Config>>,
+    Extension(state): Extension<Arc<State>>,
+    _: Principal,
+) -> Result<Json<Report>> {
+    Ok(Json(crate::reconcile::reconcile(&cfg, &state).await?))
+}
@@ -0,0 +1,436 @@
+use crate::{
+    agent,
+    jobs::provision,
+    models::{Instance, InstanceStatus},
+    principal::{Actor, Principal},
+    Error, Result, State,
+};
+use axum::{
+    extract::{Extension, Path, Query},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+use tokio::{task::spawn_blocking, time::sleep};
+use uuid::Uuid;
+use virt::{connect::Connect, domain::Domain};
+
+/// A ZFS snapshot of an instance's zvol.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub name: String,
+    /// Space used by the snapshot, in bytes.
+    pub size: u64,
+    /// Unix timestamp of when the snapshot was taken.
+    pub created_at: i64,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NewSnapshot {
+    pub name: String,
+}
+
+#[derive(Serialize)]
+struct SnapshotEvent<'a> {
+    #[serde(flatten)]
+    instance: &'a Instance,
+    snapshot: &'a str,
+}
+
+fn validate_name(name: &str) -> Result {
+    if name.is_empty()
+        || name.len() > 64
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c))
+    {
+        return Err(Error::InvalidSnapshotName(name.to_string()));
+    }
+
+    Ok(())
+}
+
+/// Parses the output of `zfs list -Hp -t snapshot -o name,used,creation`.
+fn parse_snapshots(zvol: &str, output: &str) -> Vec<Snapshot> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let name = fields.next()?.strip_prefix(zvol)?.strip_prefix('@')?;
+            let size = fields.next()?.parse().ok()?;
+            let created_at = fields.next()?.parse().ok()?;
+
+            Some(Snapshot {
+                name: name.to_string(),
+                size,
+                created_at,
+            })
+        })
+        .collect()
+}
+
+pub async fn list_for(state: &State, i: &Instance) -> Result<Vec<Snapshot>> {
+    let output = state
+        .exec
+        .run(
+            &i.host,
+            &[
+                "zfs",
+                "list",
+                "-H",
+                "-p",
+                "-t",
+                "snapshot",
+                "-o",
+                "name,used,creation",
+                "-s",
+                "creation",
+                "-d",
+                "1",
+                &i.zvol_name,
+            ],
+        )
+        .await?
+        .check(|stderr| Error::CantListSnapshots(i.host.clone(), stderr))?;
+
+    Ok(parse_snapshots(&i.zvol_name, &output))
+}
+
+/// How [`rollback_to`] treats newer snapshots and the domain.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct RollbackOptions {
+    /// Destroys snapshots newer than the one being rolled back to, rolling back
+    /// fails if there are any otherwise.
+    pub destroy_newer: bool,
+    /// Starts the domain afterwards even if it was off.
+    pub start: bool,
+}
+
+/// The names of snapshots taken after `name`, oldest first.
+fn newer_than(snapshots: &[Snapshot], name: &str) -> Vec<String> {
+    snapshots
+        .iter()
+        .skip_while(|s| s.name != name)
+        .skip(1)
+        .map(|s| s.name.clone())
+        .collect()
+}
+
+/// Turns the instance off and rolls its zvol back to `snapshot`. The domain is started
+/// again if it was running before (or `opts.start` is set), returning if it was.
+pub async fn rollback_to(
+    state: &State,
+    i: &Instance,
+    snapshot: &str,
+    opts: RollbackOptions,
+) -> Result<bool> {
+    let newer = newer_than(&list_for(state, i).await?, snapshot);
+    if !newer.is_empty() && !opts.destroy_newer {
+        return Err(Error::NewerSnapshots(
+            snapshot.to_string(),
+            newer.join(", "),
+        ));
+    }
+
+    let uri = state.exec.libvirt_uri(&i.host);
+
+    let was_active: Result<bool> = {
+        let uri = uri.clone();
+        let id = i.uuid;
+
+        spawn_blocking(move || {
+            let conn = Connect::open(&uri)?;
+
+            let dom = Domain::lookup_by_uuid_string(&conn, &id.to_string())?;
+
+            let active = dom.is_active()?;
+            if active {
+                dom.destroy()?;
+            }
+            Ok(active)
+        })
+        .await?
+    };
+    let was_active = was_active?;
+
+    sleep(Duration::from_millis(500)).await;
+
+    debug!("rolling back zvol to {}", snapshot);
+    let target = format!("{}@{}", i.zvol_name, snapshot);
+    let mut args = vec!["zfs", "rollback"];
+    if !newer.is_empty() {
+        args.push("-r");
+    }
+    args.push(&target);
+    state
+        .exec
+        .run(&i.host, &args)
+        .await?
+        .check(|stderr| Error::CantRollbackZvol(i.host.clone(), snapshot.to_string(), stderr))?;
+
+    let start = was_active || opts.start;
+    if start {
+        let nuke: Result = {
+            let id = i.uuid;
+
+            spawn_blocking(move || {
+                let conn = Connect::open(&uri)?;
+
+                let dom = Domain::lookup_by_uuid_string(&conn, &id.to_string())?;
+
+                dom.create()?;
+                Ok(())
+            })
+            .await?
+        };
+        nuke?;
+    }
+
+    Ok(start)
+}
+
+/// Query parameters for [`rollback`] and reinit, which rolls back to `init`.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct RollbackParams {
+    /// Destroy snapshots newer than the one being rolled back to.
+    #[serde(default)]
+    pub destroy_newer: bool,
+}
+
+#[instrument(err)]
+pub async fn list(
+    Path(id): Path<Uuid>,
+    Extension(state): Extension<Arc<State>>,
+    _: Principal,
+) -> Result<Json<Vec<Snapshot>>> {
+    let conn = state.pool.get().await?;
+    let i = Instance::from_uuid(&conn, id)?;
+    drop(conn);
+
+    Ok(Json(list_for(&state, &i).await?))
+}
+
+#[instrument(err)]
+pub async fn create(
+    Path(id): Path<Uuid>,
+    Extension(state): Extension<Arc<State>>,
+    who: Principal,
+    actor: Actor,
+    Json(details): Json<NewSnapshot>,
+) -> Result<Json<Snapshot>> {
+    validate_name(&details.name)?;
+
+    let conn = state.pool.get().await?;
+    let i = Instance::from_uuid(&conn, id)?;
+    who.check_owns(&i)?;
+    i.status.check_idle()?;
+
+    // a running guest's filesystems are frozen so the snapshot is consistent, guests
+    // without the agent get a crash-consistent snapshot instead
+    let frozen = i.status == InstanceStatus::Running
+        && match agent::with_domain(&state, &i, agent::freeze).await {
+            Ok(n) => {
+                debug!("froze {} filesystems", n);
+                true
+            }
+            Err(why) => {
+                warn!(
+                    "can't freeze filesystems, snapshot may not be consistent: {}",
+                    why
+                );
+                false
+            }
+        };
+
+    let made = state
+        .exec
+        .run(
+            &i.host,
+            &[
+                "zfs",
+                "snapshot",
+                &format!("{}@{}", i.zvol_name, details.name),
+            ],
+        )
+        .await;
+
+    let thawed = if frozen {
+        agent::with_domain(&state, &i, agent::thaw).await.map(drop)
+    } else {
+        Ok(())
+    };
+    if let Err(why) = &thawed {
+        error!("can't thaw filesystems: {}", why);
+    }
+
+    made?.check(|stderr| Error::CantMakeSnapshot(i.host.clone(), details.name.clone(), stderr))?;
+
+    state.events.audit(
+        &conn,
+        &actor,
+        "instance",
+        "snapshot create",
+        &SnapshotEvent {
+            instance: &i,
+            snapshot: &details.name,
+        },
+    )?;
+    thawed.map_err(|why| Error::CantThaw(i.name.clone(), details.name.clone(), why.to_string()))?;
+
+    list_for(&state, &i)
+        .await?
+        .into_iter()
+        .find(|s| s.name == details.name)
+        .map(Json)
+        .ok_or(Error::SnapshotDoesntExist(details.name))
+}
+
+#[instrument(err)]
+pub async fn rollback(
+    Path((id, name)): Path<(Uuid, String)>,
+    Query(params): Query<RollbackParams>,
+    Extension(state): Extension<Arc<State>>,
+    who: Principal,
+    actor: Actor,
+) -> Result {
+    validate_name(&name)?;
+
+    let conn = state.pool.get().await?;
+    let mut i = Instance::from_uuid(&conn, id)?;
+    who.check_owns(&i)?;
+    i.status.check_idle()?;
+
+    if !list_for(&state, &i).await?.iter().any(|s| s.name == name) {
+        return Err(Error::SnapshotDoesntExist(name));
+    }
+
+    let running = rollback_to(
+        &state,
+        &i,
+        &name,
+        RollbackOptions {
+            destroy_newer: params.destroy_newer,
+            start: false,
+        },
+    )
+    .await?;
+
+    // cloud-init runs again when the disk goes back to before it first booted
+    let status = match (running, name.as_str()) {
+        (true, "init") => InstanceStatus::Reinit,
+        (true, _) => InstanceStatus::Running,
+        (false, _) => InstanceStatus::Off,
+    };
+    if i.status.can_become(status) {
+        i.set_status(&conn, &state.events, status, None)?;
+        if status == InstanceStatus::Reinit {
+            i.start_boot(&conn)?;
+        }
+    }
+
+    state.events.audit(
+        &conn,
+        &actor,
+        "instance",
+        "snapshot rollback",
+        &SnapshotEvent {
+            instance: &i,
+            snapshot: &name,
+        },
+    )?;
+
+    Ok(())
+}
+
+#[instrument(err)]
+pub async fn delete(
+    Path((id, name)): Path<(Uuid, String)>,
+    Extension(state): Extension<Arc<State>>,
+    who: Principal,
+    actor: Actor,
+) -> Result {
+    validate_name(&name)?;
+    if name == "init" {
+        return Err(Error::SnapshotProtected(name));
+    }
+
+    let conn = state.pool.get().await?;
+    let i = Instance::from_uuid(&conn, id)?;
+    who.check_owns(&i)?;
+    i.status.check_idle()?;
+
+    let snapshot = format!("{}@{}", i.zvol_name, name);
+    provision::check_clones(&*state.exec, &i.host, &snapshot).await?;
+
+    state
+        .exec
+        .run(&i.host, &["zfs", "destroy", &snapshot])
+        .await?
+        .check(|stderr| Error::CantDeleteSnapshot(i.host.clone(), name.clone(), stderr))?;
+
+    state.events.audit(
+        &conn,
+        &actor,
+        "instance",
+        "snapshot delete",
+        &SnapshotEvent {
+            instance: &i,
+            snapshot: &name,
+        },
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_zfs_list() {
+        let output = "rpool/local/vms/foo@init\t8192\t1660000000\nrpool/local/vms/foo@before-upgrade\t1048576\t1660003600\nrpool/local/vms/foobar@init\t0\t1660000000\n";
+
+        assert_eq!(
+            parse_snapshots("rpool/local/vms/foo", output),
+            vec![
+                Snapshot {
+                    name: "init".into(),
+                    size: 8192,
+                    created_at: 1660000000,
+                },
+                Snapshot {
+                    name: "before-upgrade".into(),
+                    size: 1048576,
+                    created_at: 1660003600,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn newer_snapshots() {
+        let snapshots: Vec<Snapshot> = ["init", "before-upgrade", "after-upgrade"]
+            .iter()
+            .map(|name| Snapshot {
+                name: name.to_string(),
+                ..Snapshot::default()
+            })
+            .collect();
+
+        assert_eq!(
+            newer_than(&snapshots, "init"),
+            vec!["before-upgrade", "after-upgrade"]
+        );
+        assert!(newer_than(&snapshots, "after-upgrade").is_empty());
+        assert!(newer_than(&snapshots, "missing").is_empty());
+    }
+
+    #[test]
+    fn snapshot_names() {
+        assert!(validate_name("before-upgrade_2022.08.14").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("a b").is_err());
+        assert!(validate_name("foo@bar").is_err());
+        assert!(validate_name("../etc").is_err());
+    }
+}
@@ -0,0 +1,71 @@
+use crate::{
+    principal::{Actor, Principal},
+    rbac::Role,
+    sshkeys::{NewSshKey, SshKey},
+    Error, Result, State,
+};
+use axum::{
+    extract::{Extension, Path},
+    Json,
+};
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Adds an SSH public key for the caller, it goes in every instance they make from now
+/// on.
+#[instrument(err, skip(state))]
+pub async fn create(
+    Extension(state): Extension<Arc<State>>,
+    who: Principal,
+    actor: Actor,
+    Json(details): Json<NewSshKey>,
+) -> Result<Json<SshKey>> {
+    let conn = state.pool.get().await?;
+
+    let key = SshKey::create(&conn, &who.login_name, details)?;
+    state
+        .events
+        .audit(&conn, &actor, "sshkey", "create", &key)?;
+
+    Ok(Json(key))
+}
+
+/// Lists the caller's SSH keys, or everyone's for admins.
+#[instrument(err, skip(state))]
+pub async fn list(
+    Extension(state): Extension<Arc<State>>,
+    who: Principal,
+) -> Result<Json<Vec<SshKey>>> {
+    let conn = state.pool.get().await?;
+
+    let owner = if who.role == Role::Admin {
+        None
+    } else {
+        Some(who.login_name.as_str())
+    };
+
+    Ok(Json(SshKey::list(&conn, owner)?))
+}
+
+/// Removes an SSH key. Instances that already have it keep it.
+#[instrument(err, skip(state))]
+pub async fn delete(
+    Path(id): Path<Uuid>,
+    Extension(state): Extension<Arc<State>>,
+    who: Principal,
+    actor: Actor,
+) -> Result {
+    let conn = state.pool.get().await?;
+
+    let key = SshKey::from_id(&conn, id)?;
+    if key.owner != who.login_name && who.role != Role::Admin {
+        return Err(Error::Unauthorized);
+    }
+
+    key.delete(&conn)?;
+    state
+        .events
+        .audit(&conn, &actor, "sshkey", "delete", &key)?;
+
+    Ok(())
+}
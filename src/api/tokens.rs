@@ -0,0 +1,75 @@
+use crate::{
+    principal::{Actor, Principal},
+    rbac::Role,
+    tokens::{CreatedToken, NewToken, Token},
+    Error, Result, State,
+};
+use axum::{
+    extract::{Extension, Path},
+    Json,
+};
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Makes a token that acts as the caller. Tokens can't be used to make more tokens.
+#[instrument(err, skip(state))]
+pub async fn create(
+    Extension(state): Extension<Arc<State>>,
+    who: Principal,
+    actor: Actor,
+    Json(details): Json<NewToken>,
+) -> Result<Json<CreatedToken>> {
+    if who.token.is_some() {
+        return Err(Error::CantMakeToken(
+            "tokens can't make more tokens, use the tailnet".into(),
+        ));
+    }
+
+    let conn = state.pool.get().await?;
+
+    let created = Token::create(&conn, &who.login_name, who.role, details)?;
+    state
+        .events
+        .audit(&conn, &actor, "token", "create", &created.token)?;
+
+    Ok(Json(created))
+}
+
+/// Lists the caller's tokens, or every token for admins.
+#[instrument(err, skip(state))]
+pub async fn list(
+    Extension(state): Extension<Arc<State>>,
+    who: Principal,
+) -> Result<Json<Vec<Token>>> {
+    let conn = state.pool.get().await?;
+
+    let owner = if who.role == Role::Admin {
+        None
+    } else {
+        Some(who.login_name.as_str())
+    };
+
+    Ok(Json(Token::list(&conn, owner)?))
+}
+
+#[instrument(err, skip(state))]
+pub async fn delete(
+    Path(id): Path<Uuid>,
+    Extension(state): Extension<Arc<State>>,
+    who: Principal,
+    actor: Actor,
+) -> Result {
+    let conn = state.pool.get().await?;
+
+    let token = Token::from_id(&conn, id)?;
+    if token.owner != who.login_name && who.role != Role::Admin {
+        return Err(Error::Unauthorized);
+    }
+
+    token.revoke(&conn)?;
+    state
+        .events
+        .audit(&conn, &actor, "token", "revoke", &token)?;
+
+    Ok(())
+}
@@ -0,0 +1,23 @@
+use crate::{principal::Principal, rbac::Role, Result};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Who waifud thinks the caller is.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Whoami {
+    pub login_name: String,
+    pub role: Role,
+    /// Set when the caller used an API token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<Uuid>,
+}
+
+#[instrument(err)]
+pub async fn whoami(who: Principal) -> Result<Json<Whoami>> {
+    Ok(Json(Whoami {
+        login_name: who.login_name,
+        role: who.role,
+        token: who.token,
+    }))
+}
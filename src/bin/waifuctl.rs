@@ -8,21 +8,32 @@ extern crate tracing;
 use chrono::prelude::*;
 use clap::{Args, Parser, Subcommand};
 use clap_complete::{generate, Shell};
+use crossterm::terminal;
+use futures::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
 use serde_dhall::StaticType;
 use std::{
     convert::TryInto,
-    fs,
+    env, fs,
     io::{self, stdout, Write},
     path::PathBuf,
     process::exit,
-    time::Duration,
 };
 use tabular::{row, Table};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_tungstenite::tungstenite::Message;
+use uuid::Uuid;
 use waifud::{
+    agent::Exec,
     client::Client,
-    libvirt::NewInstance,
-    models::{Distro, Instance},
+    config::Quota,
+    events::Event,
+    libvirt::{CloneInstance, MigrateInstance, NewInstance, ResizeInstance},
+    models::{AuditEvent, AuditQuery, Distro, Instance, InstanceStatus},
+    rbac::Role,
+    sshkeys::NewSshKey,
+    tokens::NewToken,
+    vendordata::Family,
     Error, Result,
 };
 
@@ -35,6 +46,10 @@ struct Opt {
     #[clap(short = 'H', long)]
     pub host: Option<String>,
 
+    /// API token to use instead of Tailscale, defaults to $WAIFUD_TOKEN
+    #[clap(long)]
+    pub token: Option<String>,
+
     #[clap(subcommand)]
     cmd: Command,
 }
@@ -65,19 +80,61 @@ enum ConfigCmd {
 
 #[derive(Subcommand, Debug)]
 enum Command {
-    /// Manage audit logs
-    Audit {
-        /// Format all audit logs in JSON
-        #[clap(long)]
-        json: bool,
+    /// Talk to an instance's QEMU guest agent
+    Agent {
+        #[clap(subcommand)]
+        cmd: AgentCmd,
     },
+    /// Search audit logs, newest last
+    Audit(AuditOpts),
     /// Manage waifuctl configuration
     Config {
         #[clap(subcommand)]
         cmd: ConfigCmd,
     },
+    /// Attach to an instance's serial console, press ^] to detach
+    Console {
+        /// Instance name
+        name: String,
+    },
+    /// Make a new instance from a snapshot of an existing one
+    Clone {
+        /// Instance name to clone
+        name: String,
+        /// Snapshot to clone from
+        #[clap(short, long, default_value = "init")]
+        snapshot: String,
+        /// Name of the new instance, a random one is picked if this is not set
+        #[clap(short = 'N', long = "new-name")]
+        new_name: Option<String>,
+    },
+    /// Show where instances have drifted from what their hosts are doing
+    Drift {
+        /// Check the hosts now instead of showing the last report
+        #[clap(long)]
+        now: bool,
+    },
+    /// Inspect background jobs
+    Job {
+        #[clap(subcommand)]
+        cmd: JobCmd,
+    },
     /// List all instances
-    List,
+    List {
+        /// Only list instances you own
+        #[clap(long)]
+        mine: bool,
+    },
+    /// Move an instance to another host
+    Migrate {
+        /// Instance name
+        name: String,
+        /// Host to move the instance to
+        host: String,
+        /// Pause the instance while it moves instead of shutting it down
+        #[clap(long)]
+        live: bool,
+    },
     Create(CreateOpts),
     /// Delete an instance by name
     Delete {
@@ -88,10 +145,56 @@ enum Command {
         #[clap(subcommand)]
         cmd: DistroCmd,
     },
+    Resize(ResizeOpts),
+    /// Show which host a new instance would be put on and why
+    Place {
+        /// Memory in megabytes
+        #[clap(short, long, default_value = "512")]
+        memory: i32,
+        /// Disk size in GB, defaults to the distro's minimum size
+        #[clap(short = 's', long = "disk-size")]
+        disk_size: Option<i32>,
+        /// Distribution the instance would use
+        #[clap(short, long, required_unless_present = "disk_size")]
+        distro: Option<String>,
+        /// Placement strategy (spread or pack), defaults to the server's
+        #[clap(long)]
+        strategy: Option<String>,
+    },
+    /// Show how much of your quota you are using, or manage other users' quotas
+    Quota {
+        #[clap(subcommand)]
+        cmd: Option<QuotaCmd>,
+    },
     /// Reset a VM back to factory settings
     Reinit {
         /// Instance name
         name: String,
+        /// Destroy any snapshots taken since the instance was made
+        #[clap(long)]
+        destroy_newer: bool,
+    },
+    /// Manage instance snapshots
+    Snapshot {
+        #[clap(subcommand)]
+        cmd: SnapshotCmd,
+    },
+    /// Hand an instance over to another user
+    Transfer {
+        /// Instance name
+        name: String,
+        /// Tailscale login name of the new owner
+        owner: String,
+    },
+    /// Manage the SSH public keys put on your instances
+    Sshkey {
+        #[clap(subcommand)]
+        cmd: SshkeyCmd,
+    },
+    /// Manage API tokens for scripts and CI jobs outside the tailnet
+    Token {
+        #[clap(subcommand)]
+        cmd: TokenCmd,
     },
     /// Turn an instance on
     Start {
@@ -134,9 +237,9 @@ struct CreateOpts {
     #[clap(short, long, default_value = "2")]
     cpus: i32,
 
-    /// Host to put the VM on
+    /// Host to put the VM on, leave blank to let waifud pick one
     #[clap(short = 'H', long)]
-    host: String,
+    host: Option<String>,
 
     /// Disk size in GB, leave blank to use distribution default
     #[clap(short = 's', long = "disk-size")]
@@ -157,6 +260,17 @@ struct CreateOpts {
     /// Automagically join the tailnet
     #[clap(short, long)]
     join_tailnet: bool,
+
+    /// Set a variable for user-data that starts with `## template: waifud`, such as
+    /// --var role=builder
+    #[clap(long = "var", value_name = "NAME=VALUE", value_parser = parse_var)]
+    vars: Vec<(String, String)>,
+}
+
+fn parse_var(var: &str) -> Result<(String, String), String> {
+    var.split_once('=')
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .ok_or_else(|| format!("{} should look like NAME=VALUE", var))
 }
 
 impl TryInto<NewInstance> for CreateOpts {
@@ -179,10 +293,55 @@ impl TryInto<NewInstance> for CreateOpts {
             sata: Some(false),
             user_data,
             join_tailnet: self.join_tailnet,
+            vars: self.vars.into_iter().collect(),
         })
     }
 }
 
+/// Change the memory, CPU count or disk size of an instance
+///
+/// Memory and CPU changes are applied live when possible, otherwise they take
+/// effect on the next boot. Disks can only grow.
+#[derive(Args, Debug)]
+struct AuditOpts {
+    /// Format audit logs in JSON, one event per line when following
+    #[clap(long)]
+    json: bool,
+    /// Only show events of this kind, such as instance, distro or job
+    #[clap(short, long)]
+    kind: Option<String>,
+    /// Only show events about this instance
+    #[clap(short, long)]
+    instance: Option<String>,
+    /// Only show events newer than this, such as 30m, 12h, 7d or an RFC 3339 timestamp
+    #[clap(short, long)]
+    since: Option<String>,
+    /// How many of the newest events to show
+    #[clap(short = 'n', long, default_value = "50")]
+    limit: u32,
+    /// Keep printing events as they happen
+    #[clap(short, long)]
+    follow: bool,
+}
+
+#[derive(Args, Debug)]
+struct ResizeOpts {
+    /// Instance name
+    name: String,
+
+    /// Memory in megabytes
+    #[clap(short, long)]
+    memory: Option<i32>,
+
+    /// CPU cores
+    #[clap(short, long)]
+    cpus: Option<i32>,
+
+    /// Disk size in GB
+    #[clap(short = 's', long = "disk-size")]
+    disk_size: Option<i32>,
+}
+
 /// Manage distribution images in waifud
 #[derive(Subcommand, Debug)]
 enum DistroCmd {
@@ -224,11 +383,21 @@ struct CreateDistroOpts {
     /// The format of the disk image
     #[clap(short, long, default_value = "waifud://qcow2")]
     pub format: String,
+
+    /// Which vendor-data to give instances (ubuntu, nixos, arch, amazon-linux,
+    /// rocky-linux or generic), guessed from the name if not set
+    #[clap(long, value_parser = parse_family)]
+    pub family: Option<Family>,
+}
+
+fn parse_family(family: &str) -> Result<Family, String> {
+    family.parse().map_err(|why: Error| why.to_string())
 }
 
 impl Into<Distro> for CreateDistroOpts {
     fn into(self) -> Distro {
         Distro {
+            family: self.family.unwrap_or_else(|| Family::guess(&self.name)),
             name: self.name,
             download_url: self.download_url,
             sha256sum: self.sha256sum,
@@ -238,6 +407,172 @@ impl Into<Distro> for CreateDistroOpts {
     }
 }
 
+/// Manage ZFS snapshots of an instance's disk
+#[derive(Subcommand, Debug)]
+enum SnapshotCmd {
+    /// Snapshot an instance's disk
+    Create {
+        /// Instance name
+        instance: String,
+        /// Snapshot name
+        name: String,
+    },
+    /// List an instance's snapshots, oldest first
+    List {
+        /// Instance name
+        instance: String,
+    },
+    /// Roll an instance back to a snapshot
+    Rollback {
+        /// Instance name
+        instance: String,
+        /// Snapshot name
+        name: String,
+        /// Destroy any snapshots newer than this one
+        #[clap(long)]
+        destroy_newer: bool,
+    },
+    /// Delete a snapshot
+    Delete {
+        /// Instance name
+        instance: String,
+        /// Snapshot name
+        name: String,
+    },
+}
+
+/// Inspect the jobs waifud runs in the background, such as provisioning
+#[derive(Subcommand, Debug)]
+enum AgentCmd {
+    /// Show the OS an instance is running
+    Info {
+        /// Instance name
+        name: String,
+    },
+    /// List every network interface and address in an instance
+    Addrs {
+        /// Instance name
+        name: String,
+    },
+    /// Run a command in an instance and print its output
+    Exec {
+        /// Instance name
+        name: String,
+        /// Seconds to wait for the command to exit
+        #[clap(short, long)]
+        timeout: Option<u64>,
+        /// Full path to the program
+        path: String,
+        /// Arguments to pass to the program
+        #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// Set a user's password in an instance, the password is read from stdin
+    Password {
+        /// Instance name
+        name: String,
+        /// User to set the password of
+        user: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum JobCmd {
+    /// List jobs, newest first
+    List {
+        /// Only show jobs in this state (pending, running, done, failed, cancelled)
+        #[clap(short, long)]
+        state: Option<String>,
+    },
+    /// Show a job and every step it has run
+    Get {
+        /// Job ID
+        id: i64,
+    },
+    /// Retry a failed job from the step it failed on
+    Retry {
+        /// Job ID
+        id: i64,
+    },
+}
+
+/// Manage the SSH public keys every instance you make trusts
+#[derive(Subcommand, Debug)]
+enum SshkeyCmd {
+    /// Add keys, defaults to every ~/.ssh/*.pub file
+    Add {
+        /// Public key files or keys in authorized_keys format
+        keys: Vec<String>,
+        /// Name for the key, defaults to its comment
+        #[clap(short, long)]
+        name: Option<String>,
+    },
+    /// List your keys
+    List,
+    /// Remove a key, instances that already have it keep it
+    Rm {
+        /// Key ID
+        id: Uuid,
+    },
+}
+
+/// Manage API tokens that act as you
+#[derive(Subcommand, Debug)]
+enum TokenCmd {
+    /// Make a token, it is only shown once
+    Create {
+        /// What the token is for
+        description: String,
+        /// Role the token may act with (viewer, operator or admin), can be repeated
+        #[clap(short, long = "scope")]
+        scopes: Vec<String>,
+        /// Days until the token stops working, it never expires if this is not set
+        #[clap(short, long = "expires-in")]
+        expires_in_days: Option<i64>,
+    },
+    /// List your tokens
+    List,
+    /// Revoke a token so it can't be used any more
+    Revoke {
+        /// Token ID
+        id: Uuid,
+    },
+}
+
+/// Inspect and override per-user resource quotas
+#[derive(Subcommand, Debug)]
+enum QuotaCmd {
+    /// Show a user's quota and usage
+    Show {
+        /// Tailscale login name
+        user: String,
+    },
+    /// Show the quota and usage of everyone with instances or a quota
+    List,
+    /// Override a user's quota, limits that are left out are unlimited
+    Set {
+        /// Tailscale login name
+        user: String,
+        /// Maximum number of instances
+        #[clap(short, long)]
+        instances: Option<i64>,
+        /// Maximum total memory in megabytes
+        #[clap(short, long)]
+        memory: Option<i64>,
+        /// Maximum total disk size in GB
+        #[clap(short = 's', long = "disk-size")]
+        disk_size: Option<i64>,
+        /// Maximum total vCPUs
+        #[clap(short, long)]
+        cpus: Option<i64>,
+    },
+    /// Remove a user's override so the configured quota applies again
+    Clear {
+        /// Tailscale login name
+        user: String,
+    },
+}
+
 #[derive(Subcommand, Debug)]
 enum UtilsCmd {
     /// Generate shell completions
@@ -249,26 +584,184 @@ enum UtilsCmd {
     Manpage { path: PathBuf },
 }
 
-async fn list_instances(cli: Client) -> Result {
-    let instances = cli.list_instances().await?;
+async fn agent_info(cli: Client, name: String) -> Result {
+    let i = cli.get_instance_by_name(name).await?;
+    let info = cli.agent_info(i.uuid).await?;
+
+    let mut table = Table::new("{:<}  {:<}");
+    for (key, val) in [
+        ("name", info.pretty_name.or(info.name)),
+        ("version", info.version),
+        ("kernel", info.kernel_release),
+        ("arch", info.machine),
+    ] {
+        table.add_row(row!(key, val.unwrap_or_default()));
+    }
+    println!("{}", table);
 
-    let mut table = Table::new("{:>}  {:<}  {:<}  {:<}  {:<}  {:<}  {:<}");
+    Ok(())
+}
+
+async fn agent_addrs(cli: Client, name: String) -> Result {
+    let i = cli.get_instance_by_name(name).await?;
+    let ifaces = cli.agent_interfaces(i.uuid).await?;
+
+    let mut table = Table::new("{:<}  {:<}  {:<}");
+    table.add_row(row!("interface", "mac", "address"));
+    for iface in ifaces {
+        for ip in &iface.ip_addresses {
+            table.add_row(row!(
+                &iface.name,
+                iface.hardware_address.clone().unwrap_or_default(),
+                format!("{}/{}", ip.ip_address, ip.prefix)
+            ));
+        }
+    }
+    println!("{}", table);
+
+    Ok(())
+}
+
+async fn agent_exec(
+    cli: Client,
+    name: String,
+    path: String,
+    args: Vec<String>,
+    timeout_secs: Option<u64>,
+) -> Result {
+    let i = cli.get_instance_by_name(name).await?;
+    let output = cli
+        .agent_exec(
+            i.uuid,
+            Exec {
+                path,
+                args,
+                timeout_secs,
+            },
+        )
+        .await?;
+
+    print!("{}", output.stdout);
+    eprint!("{}", output.stderr);
+
+    if let Some(signal) = output.signal {
+        eprintln!("killed by signal {}", signal);
+        exit(128 + signal);
+    }
+    match output.exit_code {
+        Some(0) | None => Ok(()),
+        Some(code) => exit(code),
+    }
+}
+
+async fn agent_set_password(cli: Client, name: String, user: String) -> Result {
+    let i = cli.get_instance_by_name(name).await?;
+
+    eprint!("new password for {} on {}: ", user, i.name);
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    let password = password.trim_end_matches(&['\r', '\n'][..]).to_string();
+
+    cli.agent_set_password(i.uuid, user, password).await?;
+
+    Ok(())
+}
+
+/// Ctrl-], the same detach key as `virsh console`.
+const DETACH_KEY: u8 = 0x1d;
+
+async fn attach_console(cli: Client, name: String) -> Result {
+    let i = cli.get_instance_by_name(name).await?;
+    let (mut tx, mut rx) = cli.console(i.uuid).await?.split();
+
+    eprintln!("connected to {}, press ^] to detach", i.name);
+    terminal::enable_raw_mode()?;
+
+    let result: Result = async {
+        let mut stdin = tokio::io::stdin();
+        let mut stdout = tokio::io::stdout();
+        let mut buf = [0u8; 1024];
+
+        loop {
+            tokio::select! {
+                n = stdin.read(&mut buf) => {
+                    let n = n?;
+                    if n == 0 {
+                        return Ok(());
+                    }
+                    match buf[..n].iter().position(|&b| b == DETACH_KEY) {
+                        Some(pos) => {
+                            if pos != 0 {
+                                tx.send(Message::Binary(buf[..pos].to_vec())).await?;
+                            }
+                            return Ok(());
+                        }
+                        None => tx.send(Message::Binary(buf[..n].to_vec())).await?,
+                    }
+                }
+                msg = rx.next() => match msg {
+                    Some(Ok(Message::Binary(data))) => {
+                        stdout.write_all(&data).await?;
+                        stdout.flush().await?;
+                    }
+                    Some(Ok(Message::Text(text))) => {
+                        stdout.write_all(text.as_bytes()).await?;
+                        stdout.flush().await?;
+                    }
+                    Some(Ok(Message::Close(_))) | None => return Ok(()),
+                    Some(Ok(_)) => {}
+                    Some(Err(why)) => return Err(why.into()),
+                },
+            }
+        }
+    }
+    .await;
+
+    terminal::disable_raw_mode()?;
+    let _ = tx.close().await;
+    eprintln!("\r\ndetached from {}", i.name);
+
+    result
+}
+
+async fn transfer_instance(cli: Client, name: String, owner: String) -> Result {
+    let i = cli.get_instance_by_name(name).await?;
+    let i = cli.transfer_instance(i.uuid, owner).await?;
+    println!("{} now belongs to {}", i.name, i.owner.unwrap_or_default());
+
+    Ok(())
+}
+
+async fn list_instances(cli: Client, mine: bool) -> Result {
+    let owner = if mine {
+        Some(cli.whoami().await?.login_name)
+    } else {
+        None
+    };
+    let instances = cli.list_instances(owner).await?;
+
+    let mut table = Table::new("{:>}  {:<}  {:<}  {:<}  {:<}  {:<}  {:<}  {:<}");
     table.add_row(row!(
-        "name", "host", "distro", "memory", "ip", "status", "id"
+        "name", "host", "distro", "memory", "ip", "status", "owner", "id"
     ));
     for instance in instances {
-        let m = cli.get_instance_machine(instance.uuid).await;
+        // only instances using DHCP need to be looked up
+        let ip = match instance.ip_address {
+            Some(ip) => ip,
+            None => match cli.get_instance_machine(instance.uuid).await {
+                Ok(m) => m.addr.unwrap_or("".into()),
+                Err(_) => "".to_string(),
+            },
+        };
 
         table.add_row(row!(
             instance.name,
             instance.host,
             instance.distro,
             instance.memory,
-            match m {
-                Ok(m) => m.addr.unwrap_or("".into()),
-                Err(_) => "".to_string(),
-            },
+            ip,
             instance.status,
+            instance.owner.unwrap_or("".into()),
             instance.uuid,
         ));
     }
@@ -278,29 +771,65 @@ async fn list_instances(cli: Client) -> Result {
     Ok(())
 }
 
-async fn wait_until_status<T>(cli: &Client, i: Instance, want: T) -> Result
-where
-    T: Into<String>,
-{
-    let want = want.into();
-    let mut i = i.clone();
+/// Follows an instance's status changes until it reaches `want`, giving up if it fails
+/// along the way.
+async fn wait_until_status(cli: &Client, i: Instance, want: InstanceStatus) -> Result {
+    // subscribe before looking so no change can slip by in between
+    let events = cli.events(Some(i.uuid)).await?;
+    futures::pin_mut!(events);
+
+    let uuid = i.uuid;
+    let i = cli.get_instance(uuid).await?;
+    let name = i.name;
+    let mut status = i.status;
+    let mut reason = i.status_reason;
 
     loop {
-        i = cli.get_instance(i.uuid).await?;
-        io::stdout().flush()?;
         print!(
             "{}: {}                                        \r",
-            i.name, i.status
+            name, status
         );
-        if i.status == want {
+        io::stdout().flush()?;
+        if status == want {
             break;
         }
+        if status.is_failed() {
+            print!("\n");
+            return Err(Error::InstanceFailed(
+                name,
+                status,
+                reason.unwrap_or_default(),
+            ));
+        }
 
-        tokio::time::sleep(Duration::from_millis(1000)).await;
+        match events.next().await {
+            Some(Ok(Event::Status {
+                status: s,
+                reason: r,
+                ..
+            })) => {
+                status = s;
+                reason = r;
+            }
+            // missed some, catch up by asking
+            Some(Ok(Event::Lagged { .. })) => {
+                let i = cli.get_instance(uuid).await?;
+                status = i.status;
+                reason = i.status_reason;
+            }
+            Some(Ok(_)) => {}
+            Some(Err(why)) => return Err(why),
+            None => {
+                return Err(Error::Catchall(format!(
+                    "lost connection to waifud while waiting for {} to be {}",
+                    name, want
+                )))
+            }
+        }
     }
 
-    io::stdout().flush()?;
     print!("\n");
+    io::stdout().flush()?;
     Ok(())
 }
 
@@ -309,7 +838,7 @@ async fn start_instance(cli: Client, name: String) -> Result {
 
     cli.start_instance(i.uuid).await?;
 
-    wait_until_status(&cli, i.clone(), "running").await?;
+    wait_until_status(&cli, i.clone(), InstanceStatus::Running).await?;
     println!("{} is running", i.name);
 
     Ok(())
@@ -334,7 +863,7 @@ async fn reboot_instance(cli: Client, name: String, hard: bool) -> Result {
         cli.reboot_instance(i.uuid).await
     }?;
 
-    wait_until_status(&cli, i, "running").await?;
+    wait_until_status(&cli, i, InstanceStatus::Running).await?;
 
     Ok(())
 }
@@ -351,16 +880,18 @@ async fn create_instance(cli: Client, cfg: Config, opts: CreateOpts) -> Result {
 
     println!("created instance {} on {}", i.name, i.host);
 
-    wait_until_status(&cli, i.clone(), "running").await?;
+    wait_until_status(&cli, i.clone(), InstanceStatus::Running).await?;
 
-    let m = cli.get_instance_machine(i.uuid).await?;
+    let ip = match i.ip_address.clone() {
+        Some(ip) => ip,
+        None => cli
+            .get_instance_machine(i.uuid)
+            .await?
+            .addr
+            .unwrap_or_default(),
+    };
 
-    println!(
-        "\r{}: {}: IP address: {}",
-        i.name,
-        i.status,
-        m.addr.unwrap()
-    );
+    println!("\r{}: {}: IP address: {}", i.name, i.status, ip);
 
     Ok(())
 }
@@ -379,9 +910,130 @@ async fn delete_instance(cli: Client, name: String) -> Result {
     Ok(())
 }
 
-async fn reinit_instance(cli: Client, name: String) -> Result<()> {
+async fn resize_instance(cli: Client, opts: ResizeOpts) -> Result {
+    let i = cli.get_instance_by_name(opts.name).await?;
+
+    let i = cli
+        .resize_instance(
+            i.uuid,
+            ResizeInstance {
+                memory_mb: opts.memory,
+                cpus: opts.cpus,
+                disk_size_gb: opts.disk_size,
+            },
+        )
+        .await?;
+
+    println!(
+        "{}: {} MB memory, {} CPUs, {} GB disk",
+        i.name, i.memory, i.cpus, i.disk_size
+    );
+
+    Ok(())
+}
+
+async fn clone_instance(
+    cli: Client,
+    name: String,
+    snapshot: String,
+    new_name: Option<String>,
+) -> Result {
+    let i = cli.get_instance_by_name(name).await?;
+
+    let clone = cli
+        .clone_instance(
+            i.uuid,
+            CloneInstance {
+                snapshot: Some(snapshot.clone()),
+                name: new_name,
+                user_data: None,
+                vars: None,
+            },
+        )
+        .await?;
+
+    println!(
+        "created {} ({}) from {}@{}",
+        clone.name, clone.uuid, i.name, snapshot
+    );
+
+    Ok(())
+}
+
+async fn migrate_instance(cli: Client, name: String, host: String, live: bool) -> Result {
+    let i = cli.get_instance_by_name(name).await?;
+
+    let job = cli
+        .migrate_instance(
+            i.uuid,
+            MigrateInstance {
+                host: host.clone(),
+                live: Some(live),
+            },
+        )
+        .await?;
+
+    println!(
+        "moving {} from {} to {} in job {}, follow it with `waifuctl job get {}`",
+        i.name, i.host, host, job.id, job.id
+    );
+
+    Ok(())
+}
+
+async fn reinit_instance(cli: Client, name: String, destroy_newer: bool) -> Result<()> {
     let i = cli.get_instance_by_name(name.clone()).await?;
-    cli.reinit_instance(i.uuid).await?;
+    cli.reinit_instance(i.uuid, destroy_newer).await?;
+
+    Ok(())
+}
+
+async fn create_snapshot(cli: Client, instance: String, name: String) -> Result {
+    let i = cli.get_instance_by_name(instance).await?;
+    let snap = cli.create_snapshot(i.uuid, name).await?;
+    println!("created snapshot {}@{}", i.name, snap.name);
+
+    Ok(())
+}
+
+async fn list_snapshots(cli: Client, instance: String) -> Result {
+    let i = cli.get_instance_by_name(instance).await?;
+    let snapshots = cli.list_snapshots(i.uuid).await?;
+
+    let mut table = Table::new("{:>}  {:<}  {:<}");
+    table.add_row(row!("name", "size", "created"));
+    for snap in snapshots {
+        let ts = NaiveDateTime::from_timestamp(snap.created_at, 0);
+        table.add_row(row!(
+            snap.name,
+            format!("{:.1} MB", snap.size as f64 / (1024.0 * 1024.0)),
+            ts.to_string(),
+        ));
+    }
+
+    println!("{}", table);
+
+    Ok(())
+}
+
+async fn rollback_snapshot(
+    cli: Client,
+    instance: String,
+    name: String,
+    destroy_newer: bool,
+) -> Result {
+    let i = cli.get_instance_by_name(instance).await?;
+    cli.rollback_snapshot(i.uuid, name.clone(), destroy_newer)
+        .await?;
+    println!("rolled {} back to {}", i.name, name);
+
+    Ok(())
+}
+
+async fn delete_snapshot(cli: Client, instance: String, name: String) -> Result {
+    let i = cli.get_instance_by_name(instance).await?;
+    cli.delete_snapshot(i.uuid, name.clone()).await?;
+    println!("deleted snapshot {}@{}", i.name, name);
 
     Ok(())
 }
@@ -421,11 +1073,12 @@ async fn list_distros(cli: Client, verbose: bool) -> Result {
     let distros = cli.list_distros().await?;
 
     if verbose {
-        let mut table = Table::new("{:>}  {:<}  {:<}  {:<}");
-        table.add_row(row!("name", "min size", "sha256", "url"));
+        let mut table = Table::new("{:>}  {:<}  {:<}  {:<}  {:<}");
+        table.add_row(row!("name", "family", "min size", "sha256", "url"));
         for distro in distros {
             table.add_row(row!(
                 distro.name,
+                distro.family,
                 distro.min_size,
                 distro.sha256sum,
                 distro.download_url,
@@ -450,24 +1103,427 @@ async fn delete_distro(cli: Client, name: String) -> Result<()> {
     Ok(())
 }
 
-async fn audit_list(cli: Client, json: bool) -> Result<()> {
-    let logs = cli.audit_logs().await?;
+/// Turns `30m`, `12h`, `7d` and friends into how many seconds ago that was, or reads
+/// an RFC 3339 timestamp.
+fn parse_since(since: &str) -> Result<i64> {
+    if let Ok(ts) = DateTime::parse_from_rfc3339(since) {
+        return Ok(ts.timestamp());
+    }
+
+    let unit = since.trim_start_matches(|c: char| c.is_ascii_digit());
+    let n: i64 = since[..since.len() - unit.len()]
+        .parse()
+        .map_err(|_| Error::Catchall(format!("can't understand --since {}", since)))?;
+    let secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => {
+            return Err(Error::Catchall(format!(
+                "unknown unit {:?} in --since, use s, m, h, d or w",
+                unit
+            )))
+        }
+    };
+
+    Ok(Utc::now().timestamp() - n * secs)
+}
+
+fn audit_row(log: &AuditEvent) -> [String; 5] {
+    [
+        NaiveDateTime::from_timestamp(log.ts, 0).to_string(),
+        log.kind.clone(),
+        log.name.clone().unwrap_or_default(),
+        log.op.clone(),
+        log.actor.clone().unwrap_or_default(),
+    ]
+}
+
+async fn audit_list(cli: Client, opts: AuditOpts) -> Result<()> {
+    let instance = match opts.instance {
+        Some(name) => Some(cli.get_instance_by_name(name).await?.uuid),
+        None => None,
+    };
+
+    // subscribe before reading so nothing can happen in between
+    let events = if opts.follow {
+        Some(cli.events(instance).await?)
+    } else {
+        None
+    };
+
+    let q = AuditQuery {
+        kind: opts.kind.clone(),
+        instance,
+        since: opts.since.as_deref().map(parse_since).transpose()?,
+        limit: Some(opts.limit),
+        ..AuditQuery::default()
+    };
+    let mut logs = cli.audit_logs(&q).await?.events;
+    logs.reverse();
+    let mut last_id = logs.last().map(|log| log.id).unwrap_or_default();
+
+    if opts.json {
+        if opts.follow {
+            for log in &logs {
+                println!("{}", serde_json::to_string(log)?);
+            }
+        } else {
+            serde_json::to_writer(stdout(), &logs)?;
+        }
+    } else {
+        let mut table = Table::new("{:>}  {:<}  {:<}  {:<}  {:<}");
+        table.add_row(row!("timestamp", "kind", "name", "op", "actor"));
+        for log in &logs {
+            let [ts, kind, name, op, actor] = audit_row(log);
+            table.add_row(row!(ts, kind, name, op, actor));
+        }
+        println!("{}", table);
+    }
+
+    let events = match events {
+        Some(events) => events,
+        None => return Ok(()),
+    };
+    futures::pin_mut!(events);
+
+    while let Some(ev) = events.next().await {
+        let log = match ev? {
+            Event::Audit(log) => log,
+            Event::Lagged { missed } => {
+                eprintln!("missed {} events, use waifuctl audit to see them", missed);
+                continue;
+            }
+            _ => continue,
+        };
+        if log.id <= last_id || opts.kind.as_ref().map_or(false, |kind| *kind != log.kind) {
+            continue;
+        }
+        last_id = log.id;
+
+        if opts.json {
+            println!("{}", serde_json::to_string(&log)?);
+        } else {
+            println!("{}", audit_row(&log).join("  "));
+        }
+    }
+
+    Ok(())
+}
+
+async fn plan_placement(
+    cli: Client,
+    memory: i32,
+    disk_size: Option<i32>,
+    distro: Option<String>,
+    strategy: Option<String>,
+) -> Result {
+    let p = cli
+        .plan_placement(memory, disk_size, distro, strategy)
+        .await?;
+
+    let mut table = Table::new("{:<}  {:>}  {:>}  {:>}  {:<}");
+    table.add_row(row!(
+        "host",
+        "free memory",
+        "free disk",
+        "instances",
+        "verdict"
+    ));
+    for c in p.candidates {
+        let verdict = match (&c.rejected, &p.host) {
+            (Some(why), _) => why.clone(),
+            (None, Some(host)) if host == &c.host => "picked".to_string(),
+            (None, _) => "fits".to_string(),
+        };
+        match c.stats {
+            Some(s) => table.add_row(row!(
+                c.host,
+                format!("{} MB", s.free_memory_mb),
+                format!("{} GB", s.free_disk_gb),
+                s.instances,
+                verdict
+            )),
+            None => table.add_row(row!(c.host, "", "", "", verdict)),
+        };
+    }
+
+    println!("{}", table);
+    match p.host {
+        Some(host) => println!("{} would put the instance on {}", p.strategy, host),
+        None => println!("no host has room for this instance"),
+    }
+
+    Ok(())
+}
+
+async fn create_token(
+    cli: Client,
+    description: String,
+    scopes: Vec<String>,
+    expires_in_days: Option<i64>,
+) -> Result {
+    let scopes = scopes
+        .iter()
+        .map(|s| s.parse())
+        .collect::<Result<Vec<Role>>>()?;
+    let created = cli
+        .create_token(NewToken {
+            description,
+            scopes,
+            expires_in_days,
+        })
+        .await?;
+
+    eprintln!(
+        "created token {}, it won't be shown again:",
+        created.token.id
+    );
+    println!("{}", created.secret);
+
+    Ok(())
+}
+
+async fn list_tokens(cli: Client) -> Result {
+    let fmt_ts = |ts: Option<i64>| {
+        ts.map(|ts| NaiveDateTime::from_timestamp(ts, 0).to_string())
+            .unwrap_or("never".into())
+    };
+
+    let mut table = Table::new("{:<}  {:<}  {:<}  {:<}  {:<}  {:<}");
+    table.add_row(row!(
+        "id",
+        "description",
+        "owner",
+        "scopes",
+        "expires",
+        "last used"
+    ));
+    for t in cli.list_tokens().await? {
+        table.add_row(row!(
+            t.id,
+            t.description,
+            t.owner,
+            t.scopes
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+            fmt_ts(t.expires_at),
+            fmt_ts(t.last_used_at),
+        ));
+    }
+    println!("{}", table);
 
-    if json {
-        serde_json::to_writer(stdout(), &logs)?;
+    Ok(())
+}
+
+async fn add_ssh_keys(cli: Client, keys: Vec<String>, name: Option<String>) -> Result {
+    let keys = if keys.is_empty() {
+        let mut dir = dirs::home_dir()
+            .ok_or_else(|| Error::Catchall("can't find your home directory".into()))?;
+        dir.push(".ssh");
+
+        let mut found = vec![];
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().map(|ext| ext == "pub").unwrap_or(false) {
+                found.push(path.to_string_lossy().to_string());
+            }
+        }
+        if found.is_empty() {
+            return Err(Error::Catchall(format!(
+                "no public keys in {}",
+                dir.display()
+            )));
+        }
+        found.sort();
+        found
+    } else {
+        keys
+    };
+
+    if name.is_some() && keys.len() > 1 {
+        return Err(Error::Catchall(
+            "--name can only be used with one key".into(),
+        ));
+    }
+
+    for key in keys {
+        // anything that isn't a file is taken to be a key itself
+        let (source, public_key) = match fs::read_to_string(&key) {
+            Ok(contents) => (key, contents),
+            Err(_) => ("key".to_string(), key),
+        };
+
+        match cli
+            .add_ssh_key(NewSshKey {
+                name: name.clone(),
+                public_key,
+            })
+            .await
+        {
+            Ok(added) => println!(
+                "added {} ({}) from {}",
+                added.name, added.fingerprint, source
+            ),
+            Err(Error::Reqwest(why)) if why.status() == Some(reqwest::StatusCode::CONFLICT) => {
+                println!("{} is already added", source)
+            }
+            Err(why) => return Err(why),
+        }
+    }
+
+    Ok(())
+}
+
+async fn list_ssh_keys(cli: Client) -> Result {
+    let mut table = Table::new("{:<}  {:<}  {:<}  {:<}  {:<}");
+    table.add_row(row!("id", "name", "fingerprint", "owner", "added"));
+    for key in cli.list_ssh_keys().await? {
+        table.add_row(row!(
+            key.id,
+            key.name,
+            key.fingerprint,
+            key.owner,
+            NaiveDateTime::from_timestamp(key.created_at, 0),
+        ));
+    }
+    println!("{}", table);
+
+    Ok(())
+}
+
+fn print_quota(report: waifud::quota::Report) {
+    let limit = |max: Option<i64>| max.map(|max| max.to_string()).unwrap_or("unlimited".into());
+
+    println!("quota for {} ({})", report.login_name, report.source);
+    let mut table = Table::new("{:<}  {:>}  {:>}");
+    table.add_row(row!("resource", "used", "limit"));
+    table.add_row(row!(
+        "instances",
+        report.usage.instances,
+        limit(report.quota.max_instances)
+    ));
+    table.add_row(row!(
+        "memory (MB)",
+        report.usage.memory_mb,
+        limit(report.quota.max_memory_mb)
+    ));
+    table.add_row(row!(
+        "disk (GB)",
+        report.usage.disk_gb,
+        limit(report.quota.max_disk_gb)
+    ));
+    table.add_row(row!(
+        "vCPUs",
+        report.usage.cpus,
+        limit(report.quota.max_cpus)
+    ));
+    println!("{}", table);
+}
+
+async fn list_quotas(cli: Client) -> Result {
+    let limit = |used: i64, max: Option<i64>| match max {
+        Some(max) => format!("{}/{}", used, max),
+        None => used.to_string(),
+    };
+
+    let mut table = Table::new("{:<}  {:<}  {:>}  {:>}  {:>}  {:>}");
+    table.add_row(row!(
+        "user",
+        "source",
+        "instances",
+        "memory MB",
+        "disk GB",
+        "vCPUs"
+    ));
+    for r in cli.list_quotas().await? {
+        table.add_row(row!(
+            r.login_name,
+            r.source,
+            limit(r.usage.instances, r.quota.max_instances),
+            limit(r.usage.memory_mb, r.quota.max_memory_mb),
+            limit(r.usage.disk_gb, r.quota.max_disk_gb),
+            limit(r.usage.cpus, r.quota.max_cpus),
+        ));
+    }
+    println!("{}", table);
+
+    Ok(())
+}
+
+async fn show_drift(cli: Client, now: bool) -> Result {
+    let report = cli.drift_report(now).await?;
+
+    println!(
+        "checked at {}",
+        NaiveDateTime::from_timestamp(report.generated_at, 0)
+    );
+    if !report.has_drift() {
+        println!("everything matches");
         return Ok(());
     }
 
-    let mut table = Table::new("{:>}  {:<}  {:<}  {:<}");
-    table.add_row(row!("timestamp", "kind", "name", "op"));
+    let mut table = Table::new("{:<}  {:<}  {:<}  {:<}");
+    table.add_row(row!("host", "problem", "what", "detail"));
+    for host in report.hosts {
+        if let Some(why) = host.error {
+            table.add_row(row!(
+                &host.host,
+                "unreachable",
+                "",
+                why.lines().next().unwrap_or("")
+            ));
+        }
+        for c in host.corrected {
+            table.add_row(row!(
+                &host.host,
+                "corrected",
+                c.name,
+                format!("{} -> {}", c.from, c.to)
+            ));
+        }
+        for d in host.missing_domains {
+            table.add_row(row!(&host.host, "missing domain", d.name, d.uuid));
+        }
+        for d in host.orphaned_domains {
+            table.add_row(row!(
+                &host.host,
+                "orphaned domain",
+                d.name,
+                if d.active { "running" } else { "off" }
+            ));
+        }
+        for z in host.orphaned_zvols {
+            table.add_row(row!(&host.host, "orphaned zvol", z, ""));
+        }
+    }
+
+    println!("{}", table);
 
-    for log in logs {
-        let ts = NaiveDateTime::from_timestamp(log.ts, 0);
+    Ok(())
+}
+
+async fn list_jobs(cli: Client, state: Option<String>) -> Result {
+    let jobs = cli.list_jobs(state).await?;
+
+    let mut table = Table::new("{:>}  {:<}  {:<}  {:<}  {:<}  {:<}  {:<}");
+    table.add_row(row!(
+        "id", "kind", "instance", "step", "state", "attempts", "updated"
+    ));
+    for job in jobs {
+        let ts = NaiveDateTime::from_timestamp(job.updated_at, 0);
         table.add_row(row!(
+            job.id,
+            job.kind,
+            job.uuid,
+            job.step,
+            job.state,
+            job.attempts,
             ts.to_string(),
-            log.kind,
-            log.name.unwrap_or("".into()),
-            log.op
         ));
     }
 
@@ -476,6 +1532,38 @@ async fn audit_list(cli: Client, json: bool) -> Result<()> {
     Ok(())
 }
 
+async fn get_job(cli: Client, id: i64) -> Result {
+    let job = cli.get_job(id).await?;
+
+    println!("job {}: {} {} ({})", job.id, job.kind, job.uuid, job.state);
+    if let Some(why) = &job.last_error {
+        println!("last error: {}", why);
+    }
+
+    let mut table = Table::new("{:>}  {:<}  {:<}  {:<}");
+    table.add_row(row!("timestamp", "step", "attempt", "error"));
+    for step in job.steps {
+        let ts = NaiveDateTime::from_timestamp(step.ts, 0);
+        table.add_row(row!(
+            ts.to_string(),
+            step.step,
+            step.attempt,
+            step.error.unwrap_or("".into())
+        ));
+    }
+
+    println!("{}", table);
+
+    Ok(())
+}
+
+async fn retry_job(cli: Client, id: i64) -> Result {
+    let job = cli.retry_job(id).await?;
+    println!("job {} is now {} at step {}", job.id, job.state, job.step);
+
+    Ok(())
+}
+
 fn config_show(cfg: Config) -> Result {
     println!("waifud host: {}", cfg.host);
     println!("default cloudconfig:\n\n{}", cfg.userdata);
@@ -618,13 +1706,26 @@ async fn main() -> Result<()> {
     if let None = opt.host {
         opt.host = Some(cfg.host.clone());
     }
+    // taken out before logging so it doesn't end up in debug output
+    let token = opt.token.take().or(env::var("WAIFUD_TOKEN").ok());
 
     debug!("{:?}", opt);
 
-    let cli = Client::new(opt.host.unwrap())?;
+    let cli = Client::with_token(opt.host.unwrap(), token)?;
 
     if let Err(why) = match opt.cmd {
-        Command::Audit { json } => audit_list(cli, json).await,
+        Command::Agent { cmd } => match cmd {
+            AgentCmd::Info { name } => agent_info(cli, name).await,
+            AgentCmd::Addrs { name } => agent_addrs(cli, name).await,
+            AgentCmd::Exec {
+                name,
+                timeout,
+                path,
+                args,
+            } => agent_exec(cli, name, path, args, timeout).await,
+            AgentCmd::Password { name, user } => agent_set_password(cli, name, user).await,
+        },
+        Command::Audit(opts) => audit_list(cli, opts).await,
         Command::Distro { cmd } => match cmd {
             DistroCmd::Create(opts) => create_distro(cli, opts).await,
             DistroCmd::Delete { name } => delete_distro(cli, name).await,
@@ -632,11 +1733,83 @@ async fn main() -> Result<()> {
             DistroCmd::Scrape => scrape_distros(cli).await,
             DistroCmd::Update(opts) => update_distro(cli, opts).await,
         },
-        Command::List => list_instances(cli).await,
+        Command::Clone {
+            name,
+            snapshot,
+            new_name,
+        } => clone_instance(cli, name, snapshot, new_name).await,
+        Command::Console { name } => attach_console(cli, name).await,
+        Command::Drift { now } => show_drift(cli, now).await,
+        Command::Job { cmd } => match cmd {
+            JobCmd::List { state } => list_jobs(cli, state).await,
+            JobCmd::Get { id } => get_job(cli, id).await,
+            JobCmd::Retry { id } => retry_job(cli, id).await,
+        },
+        Command::List { mine } => list_instances(cli, mine).await,
+        Command::Migrate { name, host, live } => migrate_instance(cli, name, host, live).await,
         Command::Create(opts) => create_instance(cli, cfg, opts).await,
         Command::Delete { name } => delete_instance(cli, name).await,
         Command::Reboot { name, hard } => reboot_instance(cli, name, hard).await,
-        Command::Reinit { name } => reinit_instance(cli, name).await,
+        Command::Resize(opts) => resize_instance(cli, opts).await,
+        Command::Place {
+            memory,
+            disk_size,
+            distro,
+            strategy,
+        } => plan_placement(cli, memory, disk_size, distro, strategy).await,
+        Command::Quota { cmd } => match cmd {
+            None => cli.my_quota().await.map(print_quota),
+            Some(QuotaCmd::Show { user }) => cli.get_quota(user).await.map(print_quota),
+            Some(QuotaCmd::List) => list_quotas(cli).await,
+            Some(QuotaCmd::Set {
+                user,
+                instances,
+                memory,
+                disk_size,
+                cpus,
+            }) => cli
+                .set_quota(
+                    user,
+                    Quota {
+                        max_instances: instances,
+                        max_memory_mb: memory,
+                        max_disk_gb: disk_size,
+                        max_cpus: cpus,
+                    },
+                )
+                .await
+                .map(print_quota),
+            Some(QuotaCmd::Clear { user }) => cli.clear_quota(user).await.map(print_quota),
+        },
+        Command::Reinit {
+            name,
+            destroy_newer,
+        } => reinit_instance(cli, name, destroy_newer).await,
+        Command::Snapshot { cmd } => match cmd {
+            SnapshotCmd::Create { instance, name } => create_snapshot(cli, instance, name).await,
+            SnapshotCmd::List { instance } => list_snapshots(cli, instance).await,
+            SnapshotCmd::Rollback {
+                instance,
+                name,
+                destroy_newer,
+            } => rollback_snapshot(cli, instance, name, destroy_newer).await,
+            SnapshotCmd::Delete { instance, name } => delete_snapshot(cli, instance, name).await,
+        },
+        Command::Transfer { name, owner } => transfer_instance(cli, name, owner).await,
+        Command::Sshkey { cmd } => match cmd {
+            SshkeyCmd::Add { keys, name } => add_ssh_keys(cli, keys, name).await,
+            SshkeyCmd::List => list_ssh_keys(cli).await,
+            SshkeyCmd::Rm { id } => cli.delete_ssh_key(id).await,
+        },
+        Command::Token { cmd } => match cmd {
+            TokenCmd::Create {
+                description,
+                scopes,
+                expires_in_days,
+            } => create_token(cli, description, scopes, expires_in_days).await,
+            TokenCmd::List => list_tokens(cli).await,
+            TokenCmd::Revoke { id } => cli.revoke_token(id).await,
+        },
         Command::Start { name } => start_instance(cli, name).await,
         Command::Shutdown { name } => shutdown_instance(cli, name).await,
         Command::Config { cmd } => match cmd {
@@ -1,44 +1,77 @@
 use crate::{
-    api::libvirt::Machine,
-    libvirt::NewInstance,
-    models::{AuditEvent, Distro, Instance},
-    Result,
+    agent::{Exec, ExecOutput, Interface, OsInfo},
+    api::{
+        agent::SetPassword,
+        libvirt::Machine,
+        snapshots::{NewSnapshot, Snapshot},
+        users::Whoami,
+    },
+    config::Quota,
+    events::{self, Event},
+    libvirt::{CloneInstance, MigrateInstance, NewInstance, ResizeInstance, TransferInstance},
+    models::{AuditPage, AuditQuery, Distro, Instance, Job},
+    quota,
+    reconcile::Report,
+    scheduler::Placement,
+    sshkeys::{NewSshKey, SshKey},
+    tokens::{CreatedToken, NewToken, Token},
+    Error, Result,
 };
+use futures::{stream, Stream, StreamExt};
 use reqwest::header;
-use std::time::Duration;
+use std::{collections::VecDeque, time::Duration};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{tungstenite::client::IntoClientRequest, MaybeTlsStream, WebSocketStream};
 use url::Url;
 use uuid::Uuid;
 
 pub struct Client {
     base_url: Url,
     cli: reqwest::Client,
+    /// Sent with WebSocket requests too, which don't go through `cli`.
+    headers: header::HeaderMap,
 }
 
 impl Client {
     pub fn new(base_url: String) -> Result<Self> {
+        Self::with_token(base_url, None)
+    }
+
+    /// Makes a client that sends an API token with every request, for use outside
+    /// the tailnet.
+    pub fn with_token(base_url: String, token: Option<String>) -> Result<Self> {
         let mut headers = header::HeaderMap::new();
         headers.insert(
             header::USER_AGENT,
             header::HeaderValue::from_str(crate::APPLICATION_NAME)?,
         );
+        if let Some(token) = token {
+            let mut val = header::HeaderValue::from_str(&format!("Bearer {}", token))?;
+            val.set_sensitive(true);
+            headers.insert(header::AUTHORIZATION, val);
+        }
 
         let cli = reqwest::Client::builder()
-            .default_headers(headers)
+            .default_headers(headers.clone())
             .connect_timeout(Duration::from_millis(500))
             .build()?;
 
         Ok(Client {
             base_url: Url::parse(&base_url)?,
             cli,
+            headers,
         })
     }
 
-    pub async fn audit_logs(&self) -> Result<Vec<AuditEvent>> {
+    /// Fetches one page of the audit log. Pass the page's `next` as `q.cursor` to get
+    /// the one after it.
+    pub async fn audit_logs(&self, q: &AuditQuery) -> Result<AuditPage> {
         let mut u = self.base_url.clone();
         u.set_path("/api/v1/auditlogs");
         Ok(self
             .cli
             .get(u)
+            .query(q)
             .send()
             .await?
             .error_for_status()?
@@ -67,16 +100,67 @@ impl Client {
         Ok(())
     }
 
-    pub async fn reinit_instance(&self, id: Uuid) -> Result {
+    pub async fn resize_instance(&self, id: Uuid, ri: ResizeInstance) -> Result<Instance> {
+        let mut u = self.base_url.clone();
+        u.set_path(&format!("/api/v1/instances/{}", id));
+        Ok(self
+            .cli
+            .patch(u)
+            .json(&ri)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    pub async fn clone_instance(&self, id: Uuid, ci: CloneInstance) -> Result<Instance> {
+        let mut u = self.base_url.clone();
+        u.set_path(&format!("/api/v1/instances/{}/clone", id));
+        Ok(self
+            .cli
+            .post(u)
+            .json(&ci)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    pub async fn migrate_instance(&self, id: Uuid, mi: MigrateInstance) -> Result<Job> {
+        let mut u = self.base_url.clone();
+        u.set_path(&format!("/api/v1/instances/{}/migrate", id));
+        Ok(self
+            .cli
+            .post(u)
+            .json(&mi)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// Fails if the instance has snapshots newer than `init` unless `destroy_newer`
+    /// is set.
+    pub async fn reinit_instance(&self, id: Uuid, destroy_newer: bool) -> Result {
         let mut u = self.base_url.clone();
         u.set_path(&format!("/api/v1/instances/{}/reinit", id));
+        if destroy_newer {
+            u.query_pairs_mut().append_pair("destroy_newer", "true");
+        }
         self.cli.post(u).send().await?.error_for_status()?;
         Ok(())
     }
 
-    pub async fn list_instances(&self) -> Result<Vec<Instance>> {
+    /// Lists instances, only the ones owned by `owner` if it is set.
+    pub async fn list_instances(&self, owner: Option<String>) -> Result<Vec<Instance>> {
         let mut u = self.base_url.clone();
         u.set_path("/api/v1/instances");
+        if let Some(owner) = owner {
+            u.query_pairs_mut().append_pair("owner", &owner);
+        }
         Ok(self
             .cli
             .get(u)
@@ -154,6 +238,54 @@ impl Client {
         Ok(())
     }
 
+    pub async fn list_snapshots(&self, id: Uuid) -> Result<Vec<Snapshot>> {
+        let mut u = self.base_url.clone();
+        u.set_path(&format!("/api/v1/instances/{}/snapshots", id));
+        Ok(self
+            .cli
+            .get(u)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    pub async fn create_snapshot(&self, id: Uuid, name: String) -> Result<Snapshot> {
+        let mut u = self.base_url.clone();
+        u.set_path(&format!("/api/v1/instances/{}/snapshots", id));
+        Ok(self
+            .cli
+            .post(u)
+            .json(&NewSnapshot { name })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// Fails if there are newer snapshots unless `destroy_newer` is set.
+    pub async fn rollback_snapshot(&self, id: Uuid, name: String, destroy_newer: bool) -> Result {
+        let mut u = self.base_url.clone();
+        u.set_path(&format!(
+            "/api/v1/instances/{}/snapshots/{}/rollback",
+            id, name
+        ));
+        if destroy_newer {
+            u.query_pairs_mut().append_pair("destroy_newer", "true");
+        }
+        self.cli.post(u).send().await?.error_for_status()?;
+        Ok(())
+    }
+
+    pub async fn delete_snapshot(&self, id: Uuid, name: String) -> Result {
+        let mut u = self.base_url.clone();
+        u.set_path(&format!("/api/v1/instances/{}/snapshots/{}", id, name));
+        self.cli.delete(u).send().await?.error_for_status()?;
+        Ok(())
+    }
+
     pub async fn create_distro(&self, d: Distro) -> Result<Distro> {
         let mut u = self.base_url.clone();
         u.set_path("/api/v1/distros");
@@ -214,4 +346,371 @@ impl Client {
         self.cli.delete(u).send().await?.error_for_status()?;
         Ok(())
     }
+
+    /// Asks where an instance of this size would be put, without making it.
+    pub async fn plan_placement(
+        &self,
+        memory_mb: i32,
+        disk_size_gb: Option<i32>,
+        distro: Option<String>,
+        strategy: Option<String>,
+    ) -> Result<Placement> {
+        let mut u = self.base_url.clone();
+        u.set_path("/api/v1/placement");
+        u.query_pairs_mut()
+            .append_pair("memory_mb", &memory_mb.to_string());
+        if let Some(disk_size_gb) = disk_size_gb {
+            u.query_pairs_mut()
+                .append_pair("disk_size_gb", &disk_size_gb.to_string());
+        }
+        if let Some(distro) = distro {
+            u.query_pairs_mut().append_pair("distro", &distro);
+        }
+        if let Some(strategy) = strategy {
+            u.query_pairs_mut().append_pair("strategy", &strategy);
+        }
+        Ok(self
+            .cli
+            .get(u)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// Asks waifud who it thinks we are.
+    pub async fn whoami(&self) -> Result<Whoami> {
+        let mut u = self.base_url.clone();
+        u.set_path("/api/v1/whoami");
+        Ok(self
+            .cli
+            .get(u)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    pub async fn create_token(&self, details: NewToken) -> Result<CreatedToken> {
+        let mut u = self.base_url.clone();
+        u.set_path("/api/v1/tokens");
+        Ok(self
+            .cli
+            .post(u)
+            .json(&details)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    pub async fn list_tokens(&self) -> Result<Vec<Token>> {
+        let mut u = self.base_url.clone();
+        u.set_path("/api/v1/tokens");
+        Ok(self
+            .cli
+            .get(u)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    pub async fn revoke_token(&self, id: Uuid) -> Result {
+        let mut u = self.base_url.clone();
+        u.set_path(&format!("/api/v1/tokens/{}", id));
+        self.cli.delete(u).send().await?.error_for_status()?;
+        Ok(())
+    }
+
+    pub async fn add_ssh_key(&self, details: NewSshKey) -> Result<SshKey> {
+        let mut u = self.base_url.clone();
+        u.set_path("/api/v1/sshkeys");
+        Ok(self
+            .cli
+            .post(u)
+            .json(&details)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    pub async fn list_ssh_keys(&self) -> Result<Vec<SshKey>> {
+        let mut u = self.base_url.clone();
+        u.set_path("/api/v1/sshkeys");
+        Ok(self
+            .cli
+            .get(u)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    pub async fn delete_ssh_key(&self, id: Uuid) -> Result {
+        let mut u = self.base_url.clone();
+        u.set_path(&format!("/api/v1/sshkeys/{}", id));
+        self.cli.delete(u).send().await?.error_for_status()?;
+        Ok(())
+    }
+
+    /// Follows events as they happen, only ones about `instance` if it is set. The
+    /// stream ends when waifud hangs up.
+    pub async fn events(
+        &self,
+        instance: Option<Uuid>,
+    ) -> Result<impl Stream<Item = Result<Event>>> {
+        let mut u = self.base_url.clone();
+        u.set_path("/api/v1/events");
+        if let Some(id) = instance {
+            u.query_pairs_mut().append_pair("instance", &id.to_string());
+        }
+
+        let body = self
+            .cli
+            .get(u)
+            .header(header::ACCEPT, "text/event-stream")
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes_stream();
+
+        Ok(stream::unfold(
+            (Box::pin(body), Vec::new(), VecDeque::new()),
+            |(mut body, mut buf, mut pending)| async move {
+                loop {
+                    if let Some(data) = pending.pop_front() {
+                        let ev = serde_json::from_str::<Event>(&data).map_err(Error::from);
+                        return Some((ev, (body, buf, pending)));
+                    }
+
+                    match body.next().await? {
+                        Ok(chunk) => {
+                            buf.extend_from_slice(&chunk);
+                            pending.extend(events::take_sse_data(&mut buf));
+                        }
+                        Err(why) => return Some((Err(why.into()), (body, buf, pending))),
+                    }
+                }
+            },
+        ))
+    }
+
+    /// Attaches to an instance's serial console. Output from the guest comes in as
+    /// binary messages, and binary messages sent are typed into the console.
+    pub async fn console(&self, id: Uuid) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+        let mut u = self.base_url.clone();
+        u.set_path(&format!("/api/v1/instances/{}/console", id));
+        let scheme = if u.scheme() == "https" { "wss" } else { "ws" };
+        u.set_scheme(scheme)
+            .map_err(|_| Error::Catchall(format!("can't connect to {} with a websocket", u)))?;
+
+        let mut req = u.as_str().into_client_request()?;
+        req.headers_mut().extend(self.headers.clone());
+        let (ws, _) = tokio_tungstenite::connect_async(req).await?;
+
+        Ok(ws)
+    }
+
+    /// Hands an instance over to another user.
+    pub async fn transfer_instance(&self, id: Uuid, owner: String) -> Result<Instance> {
+        let mut u = self.base_url.clone();
+        u.set_path(&format!("/api/v1/instances/{}/owner", id));
+        Ok(self
+            .cli
+            .post(u)
+            .json(&TransferInstance { owner })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// Asks an instance's guest agent what OS it is running.
+    pub async fn agent_info(&self, id: Uuid) -> Result<OsInfo> {
+        let mut u = self.base_url.clone();
+        u.set_path(&format!("/api/v1/instances/{}/agent/info", id));
+        Ok(self
+            .cli
+            .get(u)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// Lists every network interface the guest agent can see.
+    pub async fn agent_interfaces(&self, id: Uuid) -> Result<Vec<Interface>> {
+        let mut u = self.base_url.clone();
+        u.set_path(&format!("/api/v1/instances/{}/agent/interfaces", id));
+        Ok(self
+            .cli
+            .get(u)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// Runs a command in an instance through its guest agent.
+    pub async fn agent_exec(&self, id: Uuid, cmd: Exec) -> Result<ExecOutput> {
+        let mut u = self.base_url.clone();
+        u.set_path(&format!("/api/v1/instances/{}/agent/exec", id));
+        Ok(self
+            .cli
+            .post(u)
+            .json(&cmd)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// Sets the password of a user in an instance through its guest agent.
+    pub async fn agent_set_password(&self, id: Uuid, username: String, password: String) -> Result {
+        let mut u = self.base_url.clone();
+        u.set_path(&format!("/api/v1/instances/{}/agent/password", id));
+        self.cli
+            .post(u)
+            .json(&SetPassword { username, password })
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    /// Fetches the caller's own quota and usage.
+    pub async fn my_quota(&self) -> Result<quota::Report> {
+        let mut u = self.base_url.clone();
+        u.set_path("/api/v1/quota");
+        Ok(self
+            .cli
+            .get(u)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    pub async fn list_quotas(&self) -> Result<Vec<quota::Report>> {
+        let mut u = self.base_url.clone();
+        u.set_path("/api/v1/quotas");
+        Ok(self
+            .cli
+            .get(u)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    pub async fn get_quota(&self, login_name: String) -> Result<quota::Report> {
+        let mut u = self.base_url.clone();
+        u.set_path(&format!("/api/v1/quotas/{}", login_name));
+        Ok(self
+            .cli
+            .get(u)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// Overrides the configured quota for a user.
+    pub async fn set_quota(&self, login_name: String, q: Quota) -> Result<quota::Report> {
+        let mut u = self.base_url.clone();
+        u.set_path(&format!("/api/v1/quotas/{}", login_name));
+        Ok(self
+            .cli
+            .put(u)
+            .json(&q)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// Removes a user's quota override.
+    pub async fn clear_quota(&self, login_name: String) -> Result<quota::Report> {
+        let mut u = self.base_url.clone();
+        u.set_path(&format!("/api/v1/quotas/{}", login_name));
+        Ok(self
+            .cli
+            .delete(u)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// Fetches the last drift report, or makes a new one if `now` is set.
+    pub async fn drift_report(&self, now: bool) -> Result<Report> {
+        let mut u = self.base_url.clone();
+        u.set_path("/api/v1/reconcile");
+        let req = if now {
+            self.cli.post(u)
+        } else {
+            self.cli.get(u)
+        };
+        Ok(req.send().await?.error_for_status()?.json().await?)
+    }
+
+    pub async fn list_jobs(&self, state: Option<String>) -> Result<Vec<Job>> {
+        let mut u = self.base_url.clone();
+        u.set_path("/api/v1/jobs");
+        if let Some(state) = state {
+            u.query_pairs_mut().append_pair("state", &state);
+        }
+        Ok(self
+            .cli
+            .get(u)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    pub async fn get_job(&self, id: i64) -> Result<Job> {
+        let mut u = self.base_url.clone();
+        u.set_path(&format!("/api/v1/jobs/{}", id));
+        Ok(self
+            .cli
+            .get(u)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    pub async fn retry_job(&self, id: i64) -> Result<Job> {
+        let mut u = self.base_url.clone();
+        u.set_path(&format!("/api/v1/jobs/{}/retry", id));
+        Ok(self
+            .cli
+            .post(u)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
 }
@@ -1,3 +1,4 @@
+use crate::rbac::Roles;
 use serde::{Deserialize, Serialize};
 use std::{fmt, net::IpAddr};
 
@@ -15,6 +16,39 @@ pub struct Config {
     pub qemu_path: String,
     #[serde(skip_serializing)]
     pub tailscale: Tailscale,
+    #[serde(default)]
+    pub ssh: Ssh,
+    /// How often, in seconds, instances are checked against their hosts.
+    #[serde(rename = "reconcileInterval", default = "default_reconcile_interval")]
+    pub reconcile_interval: u64,
+    /// How long, in seconds, cloud-init gets to phone home before the instance is
+    /// marked as failed.
+    #[serde(rename = "cloudInitTimeout", default = "default_cloud_init_timeout")]
+    pub cloud_init_timeout: u64,
+    /// How hosts are picked for instances created without one, "spread" or "pack".
+    #[serde(rename = "placementStrategy", default = "default_placement_strategy")]
+    pub placement_strategy: String,
+    #[serde(default)]
+    pub quotas: Quotas,
+    #[serde(default)]
+    pub roles: Roles,
+    #[serde(default)]
+    pub audit: Audit,
+    /// Static addresses for instances, hosts without a subnet use DHCP.
+    #[serde(default)]
+    pub subnets: Vec<Subnet>,
+}
+
+fn default_reconcile_interval() -> u64 {
+    60
+}
+
+fn default_cloud_init_timeout() -> u64 {
+    30 * 60
+}
+
+fn default_placement_strategy() -> String {
+    "spread".into()
 }
 
 impl fmt::Debug for Config {
@@ -29,3 +63,162 @@ pub struct Tailscale {
     pub api_key: String,
     pub tailnet: String,
 }
+
+/// How waifud connects to the hosts in `hosts`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ssh {
+    pub user: String,
+    #[serde(rename = "identityFile")]
+    pub identity_file: Option<String>,
+    /// Passed to ssh as `StrictHostKeyChecking`: "yes", "accept-new" or "no".
+    #[serde(rename = "knownHosts")]
+    pub known_hosts: String,
+    #[serde(rename = "knownHostsFile")]
+    pub known_hosts_file: Option<String>,
+    /// Where distro images are cached on each host, must be an absolute path.
+    #[serde(rename = "cacheDir")]
+    pub cache_dir: String,
+    #[serde(rename = "hostCacheDirs")]
+    pub host_cache_dirs: Vec<HostCacheDir>,
+}
+
+impl Default for Ssh {
+    fn default() -> Self {
+        Ssh {
+            user: "root".into(),
+            identity_file: None,
+            known_hosts: "accept-new".into(),
+            known_hosts_file: None,
+            cache_dir: "/var/cache/waifud/qcow2".into(),
+            host_cache_dirs: vec![],
+        }
+    }
+}
+
+/// Overrides the image cache directory for a single host.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostCacheDir {
+    pub host: String,
+    #[serde(rename = "cacheDir")]
+    pub cache_dir: String,
+}
+
+/// How much each tailnet user may use. Admins can override these per user with the
+/// quota API.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct Quotas {
+    /// Applies to anyone not listed in `users`.
+    pub default: Quota,
+    pub users: Vec<UserQuota>,
+}
+
+/// Limits on what a user's instances may add up to, unset limits are unlimited.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Quota {
+    #[serde(rename = "maxInstances")]
+    pub max_instances: Option<i64>,
+    #[serde(rename = "maxMemoryMB")]
+    pub max_memory_mb: Option<i64>,
+    #[serde(rename = "maxDiskGB")]
+    pub max_disk_gb: Option<i64>,
+    #[serde(rename = "maxCpus")]
+    pub max_cpus: Option<i64>,
+}
+
+/// The quota for a single user, keyed by their Tailscale login name.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserQuota {
+    #[serde(rename = "loginName")]
+    pub login_name: String,
+    pub quota: Quota,
+}
+
+/// Where audit events are forwarded to as they are written, see [`crate::sinks`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Audit {
+    pub sinks: Vec<AuditSink>,
+    /// How many events each sink can fall behind before new ones are dropped for it.
+    #[serde(rename = "bufferSize")]
+    pub buffer_size: usize,
+    /// How many times sending an event is retried before giving up on it.
+    #[serde(rename = "maxRetries")]
+    pub max_retries: u32,
+}
+
+impl Default for Audit {
+    fn default() -> Self {
+        Audit {
+            sinks: vec![],
+            buffer_size: 1024,
+            max_retries: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AuditSink {
+    File(FileSink),
+    Syslog(SyslogSink),
+    Webhook(WebhookSink),
+}
+
+impl fmt::Display for AuditSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditSink::File(sink) => write!(f, "file {}", sink.path),
+            AuditSink::Syslog(sink) => write!(f, "syslog {}://{}", sink.transport, sink.address),
+            AuditSink::Webhook(sink) => write!(f, "webhook {}", sink.url),
+        }
+    }
+}
+
+/// Appends every event to a file as a line of JSON. The file is opened again for
+/// each event, so it can be rotated out from under waifud.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileSink {
+    pub path: String,
+}
+
+/// Sends every event to a syslog server as an RFC 5424 message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyslogSink {
+    /// "udp", "tcp" or "unix".
+    pub transport: String,
+    /// A `host:port` for udp and tcp, a socket path such as `/dev/log` for unix.
+    pub address: String,
+    /// Defaults to 13, log audit.
+    pub facility: u8,
+    #[serde(rename = "appName")]
+    pub app_name: String,
+    /// Defaults to the machine's hostname.
+    pub hostname: Option<String>,
+}
+
+/// POSTs every event as JSON to a URL.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookSink {
+    pub url: String,
+    /// Sent as `Authorization: Bearer <token>` if set.
+    #[serde(rename = "bearerToken", skip_serializing)]
+    pub bearer_token: Option<String>,
+}
+
+/// A range of static addresses handed out to instances, see [`crate::ipam`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subnet {
+    pub name: String,
+    /// Hosts whose instances get addresses from this subnet.
+    pub hosts: Vec<String>,
+    /// The libvirt network instances in this subnet are attached to.
+    pub network: String,
+    /// Such as `10.77.0.0/24`, only IPv4 is supported.
+    pub cidr: String,
+    pub gateway: String,
+    /// The first address handed out, defaults to the first one in `cidr`.
+    #[serde(rename = "rangeStart")]
+    pub range_start: Option<String>,
+    /// The last address handed out, defaults to the last one in `cidr`.
+    #[serde(rename = "rangeEnd")]
+    pub range_end: Option<String>,
+    pub nameservers: Vec<String>,
+}
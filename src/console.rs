@@ -0,0 +1,103 @@
+//! Bridging an instance's serial console to a WebSocket.
+//!
+//! Every domain has a `<console type="pty"/>`, which libvirt can hand out as a
+//! stream. Bytes from the guest are sent as binary WebSocket messages, and anything
+//! the client sends is typed into the console.
+
+use crate::Result;
+use axum::extract::ws::{Message, WebSocket};
+use futures::{SinkExt, StreamExt};
+use std::sync::Arc;
+use tokio::{sync::mpsc, task::spawn_blocking};
+use uuid::Uuid;
+use virt::{connect::Connect, domain::Domain, stream::Stream};
+
+/// An open console stream and the connection it came from.
+pub struct Console {
+    stream: Stream,
+    conn: Connect,
+}
+
+// SAFETY: libvirt streams are thread safe, and the bridge only ever has one thread
+// reading and one thread writing.
+unsafe impl Send for Console {}
+unsafe impl Sync for Console {}
+
+impl Drop for Console {
+    fn drop(&mut self) {
+        if let Err(why) = self.conn.close() {
+            debug!("can't close libvirt connection: {}", why);
+        }
+    }
+}
+
+/// Opens the serial console of a domain. This blocks, so call it from
+/// [`spawn_blocking`]. Anyone else attached to the console is kicked off.
+pub fn open(uri: &str, id: Uuid) -> Result<Console> {
+    let conn = Connect::open(uri)?;
+    let dom = Domain::lookup_by_uuid_string(&conn, &id.to_string())?;
+    let stream = Stream::new(&conn, 0)?;
+    dom.open_console(None, &stream, virt::sys::VIR_DOMAIN_CONSOLE_FORCE)?;
+
+    Ok(Console { stream, conn })
+}
+
+/// Shuttles bytes between a WebSocket and a console until either side hangs up.
+#[instrument(skip_all)]
+pub async fn bridge(socket: WebSocket, console: Console) {
+    let console = Arc::new(console);
+    let (mut sink, mut source) = socket.split();
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(16);
+
+    let reader = {
+        let console = console.clone();
+        spawn_blocking(move || {
+            let mut buf = [0u8; 4096];
+            loop {
+                match console.stream.recv(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        if tx.blocking_send(buf[..n].to_vec()).is_err() {
+                            break;
+                        }
+                    }
+                    Err(why) => {
+                        debug!("console stream ended: {}", why);
+                        break;
+                    }
+                }
+            }
+        })
+    };
+
+    let writer = tokio::spawn(async move {
+        while let Some(data) = rx.recv().await {
+            if sink.send(Message::Binary(data)).await.is_err() {
+                break;
+            }
+        }
+        let _ = sink.close().await;
+    });
+
+    while let Some(Ok(msg)) = source.next().await {
+        let data = match msg {
+            Message::Binary(data) => data,
+            Message::Text(text) => text.into_bytes(),
+            Message::Close(_) => break,
+            Message::Ping(_) | Message::Pong(_) => continue,
+        };
+
+        let console = console.clone();
+        let sent = spawn_blocking(move || console.stream.send(&data)).await;
+        if !matches!(sent, Ok(Ok(_))) {
+            break;
+        }
+    }
+
+    // wakes up the reader if it is still waiting on the guest
+    if let Err(why) = console.stream.abort() {
+        debug!("can't abort console stream: {}", why);
+    }
+    let _ = reader.await;
+    let _ = writer.await;
+}
@@ -0,0 +1,214 @@
+//! An in-process bus of everything that happens to instances.
+//!
+//! Every audit log write and instance status change is published here, so clients can
+//! follow along at `/api/v1/events` instead of polling. Events are not stored, anyone
+//! who isn't subscribed when one is published misses it and should read the audit log.
+
+use crate::{
+    models::{AuditEvent, Instance, InstanceStatus},
+    principal::Actor,
+    Result,
+};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+/// How many events a slow subscriber can fall behind before it starts missing them.
+const CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// An instance moved to a new status.
+    Status {
+        uuid: Uuid,
+        name: String,
+        status: InstanceStatus,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// A row was written to the audit log.
+    Audit(AuditEvent),
+    /// The subscriber fell behind and missed some events, it should re-read whatever
+    /// it cares about.
+    Lagged { missed: u64 },
+}
+
+impl Event {
+    pub fn status(ins: &Instance) -> Self {
+        Event::Status {
+            uuid: ins.uuid,
+            name: ins.name.clone(),
+            status: ins.status,
+            reason: ins.status_reason.clone(),
+        }
+    }
+
+    /// The instance the event is about, if any.
+    pub fn instance(&self) -> Option<Uuid> {
+        match self {
+            Event::Status { uuid, .. } => Some(*uuid),
+            Event::Audit(ev) => ev.uuid.as_deref().and_then(|id| id.parse().ok()),
+            Event::Lagged { .. } => None,
+        }
+    }
+
+    /// The SSE event name.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Status { .. } => "status",
+            Event::Audit(_) => "audit",
+            Event::Lagged { .. } => "lagged",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Bus {
+    tx: broadcast::Sender<Event>,
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        let (tx, _) = broadcast::channel(CAPACITY);
+        Bus { tx }
+    }
+}
+
+impl Bus {
+    pub fn publish(&self, ev: Event) {
+        // an error only means nobody is listening
+        let _ = self.tx.send(ev);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+
+    /// Writes a row to the audit log and publishes it. `data` should have a `name` and,
+    /// for instances, a `uuid` so the row can be found again.
+    pub fn audit<T: Serialize + ?Sized>(
+        &self,
+        conn: &Connection,
+        actor: &Actor,
+        kind: &str,
+        op: &str,
+        data: &T,
+    ) -> Result {
+        conn.execute(
+            "INSERT INTO audit_logs(kind, op, data, actor, node, source_ip, request_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                kind,
+                op,
+                serde_json::to_string(data)?,
+                actor.login_name,
+                actor.node,
+                actor.source_ip,
+                actor.request_id,
+            ],
+        )?;
+        let ev = AuditEvent::from_id(conn, conn.last_insert_rowid())?;
+        self.publish(Event::Audit(ev));
+
+        Ok(())
+    }
+}
+
+/// Splits complete server-sent events off the front of `buf`, returning the `data` of
+/// each one. Whatever is left is the start of an event that hasn't fully arrived.
+pub fn take_sse_data(buf: &mut Vec<u8>) -> Vec<String> {
+    let mut result = vec![];
+
+    while let Some(end) = buf.windows(2).position(|w| w == b"\n\n") {
+        let frame: Vec<u8> = buf.drain(..end + 2).collect();
+        let frame = String::from_utf8_lossy(&frame);
+        let data = frame
+            .lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .map(|data| data.strip_prefix(' ').unwrap_or(data))
+            .collect::<Vec<&str>>();
+        if !data.is_empty() {
+            result.push(data.join("\n"));
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sse_frames() {
+        let mut buf =
+            b"event: status\ndata: {\"a\":1}\n\n: keep-alive\n\nevent: audit\ndata: {\"b\""
+                .to_vec();
+        assert_eq!(take_sse_data(&mut buf), vec![r#"{"a":1}"#.to_string()]);
+        assert_eq!(buf, b"event: audit\ndata: {\"b\"");
+
+        buf.extend_from_slice(b":2}\n\n");
+        assert_eq!(take_sse_data(&mut buf), vec![r#"{"b":2}"#.to_string()]);
+        assert!(buf.is_empty());
+    }
+
+    #[tokio::test]
+    async fn subscribers_see_events() {
+        let bus = Bus::default();
+        let mut rx = bus.subscribe();
+        let uuid = Uuid::new_v4();
+
+        bus.publish(Event::Status {
+            uuid,
+            name: "crobat".into(),
+            status: InstanceStatus::Running,
+            reason: None,
+        });
+
+        let ev = rx.recv().await.unwrap();
+        assert_eq!(ev.instance(), Some(uuid));
+        assert_eq!(ev.name(), "status");
+
+        let json = serde_json::to_string(&ev).unwrap();
+        assert_eq!(serde_json::from_str::<Event>(&json).unwrap(), ev);
+    }
+
+    #[test]
+    fn audit_records_actor() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("./migrate/base_schema.sql"))
+            .unwrap();
+        conn.execute_batch(include_str!("./migrate/20261018-audit-query.sql"))
+            .unwrap();
+        conn.execute_batch(include_str!("./migrate/20261018-audit-actor.sql"))
+            .unwrap();
+
+        let bus = Bus::default();
+        let mut rx = bus.subscribe();
+        let actor = Actor {
+            login_name: "cadey@example.com".into(),
+            node: Some("shachi.example.ts.net".into()),
+            source_ip: Some("100.64.0.2".into()),
+            request_id: Some("b3b4e2d8".into()),
+        };
+        bus.audit(
+            &conn,
+            &actor,
+            "distro",
+            "create",
+            &serde_json::json!({ "name": "alpine" }),
+        )
+        .unwrap();
+
+        let ev = match rx.try_recv().unwrap() {
+            Event::Audit(ev) => ev,
+            other => panic!("expected an audit event, got {:?}", other),
+        };
+        assert_eq!(ev.actor.as_deref(), Some("cadey@example.com"));
+        assert_eq!(ev.node, actor.node);
+        assert_eq!(ev.source_ip, actor.source_ip);
+        assert_eq!(ev.request_id, actor.request_id);
+        assert_eq!(ev.name.as_deref(), Some("alpine"));
+    }
+}
@@ -0,0 +1,200 @@
+//! Proxying an instance's SPICE or VNC display to a WebSocket.
+//!
+//! Domains get a graphics device on an automatically allocated port, which is only
+//! known once they are running. The port is read out of the live domain XML and the
+//! raw protocol is relayed as binary WebSocket messages, which is what spice-html5 and
+//! noVNC speak. The hypervisor has to listen somewhere waifud can reach, see
+//! `spice_listen` and `vnc_listen` in `qemu.conf`.
+
+use crate::{Error, Result};
+use axum::extract::ws::{Message, WebSocket};
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use uuid::Uuid;
+use virt::{connect::Connect, domain::Domain};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Spice,
+    Vnc,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Kind::Spice => write!(f, "spice"),
+            Kind::Vnc => write!(f, "vnc"),
+        }
+    }
+}
+
+/// Where a running domain's display is.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Graphics {
+    pub kind: Kind,
+    pub port: u16,
+    /// The address the hypervisor listens on, if the domain says.
+    pub listen: Option<String>,
+}
+
+impl Graphics {
+    /// The address to connect to, given the host the domain runs on. Wildcard and
+    /// loopback listen addresses mean the host itself.
+    pub fn addr(&self, host: &str) -> String {
+        let ip = match self.listen.as_deref() {
+            None | Some("0.0.0.0" | "::" | "127.0.0.1" | "::1" | "localhost") => host,
+            Some(listen) => listen,
+        };
+
+        if ip.contains(':') {
+            format!("[{}]:{}", ip, self.port)
+        } else {
+            format!("{}:{}", ip, self.port)
+        }
+    }
+}
+
+/// Finds the first usable graphics device in a domain's XML. Devices without a port
+/// yet (`-1`, when the domain isn't running) are skipped.
+pub fn parse(xml: &str) -> Option<Graphics> {
+    xml.split("<graphics ").skip(1).find_map(|rest| {
+        let tag = &rest[..rest.find('>')?];
+        let kind = match attr(tag, "type")? {
+            "spice" => Kind::Spice,
+            "vnc" => Kind::Vnc,
+            _ => return None,
+        };
+        let port = attr(tag, "port")?.parse::<u16>().ok()?;
+
+        Some(Graphics {
+            kind,
+            port,
+            listen: attr(tag, "listen").map(str::to_string),
+        })
+    })
+}
+
+/// Gets an attribute out of the inside of a tag, libvirt uses either quote style.
+fn attr<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    ['"', '\''].iter().find_map(|quote| {
+        let start = format!(" {name}={quote}");
+        let idx = format!(" {tag}").find(&start)? + start.len() - 1;
+        let value = &tag[idx..];
+        Some(&value[..value.find(*quote)?])
+    })
+}
+
+/// Looks up where a running domain's display is. This blocks, so call it from
+/// [`tokio::task::spawn_blocking`].
+pub fn find(uri: &str, id: Uuid) -> Result<Graphics> {
+    let mut conn = Connect::open(uri)?;
+    let result = Domain::lookup_by_uuid_string(&conn, &id.to_string())
+        .map_err(Error::from)
+        .and_then(|dom| {
+            if !dom.is_active()? {
+                return Err(Error::NoGraphics(id, "instance is not running".into()));
+            }
+            parse(&dom.get_xml_desc(0)?)
+                .ok_or_else(|| Error::NoGraphics(id, "instance has no graphics device".into()))
+        });
+    conn.close()?;
+
+    result
+}
+
+/// Shuttles bytes between a WebSocket and the display until either side hangs up.
+#[instrument(skip(socket, stream))]
+pub async fn bridge(socket: WebSocket, stream: TcpStream, addr: String) {
+    let (mut sink, mut source) = socket.split();
+    let (mut reader, mut writer) = stream.into_split();
+
+    let upstream = async {
+        let mut buf = [0u8; 16 * 1024];
+        loop {
+            match reader.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    if sink.send(Message::Binary(buf[..n].to_vec())).await.is_err() {
+                        break;
+                    }
+                }
+                Err(why) => {
+                    debug!("graphics connection ended: {}", why);
+                    break;
+                }
+            }
+        }
+        let _ = sink.close().await;
+    };
+
+    let downstream = async {
+        while let Some(Ok(msg)) = source.next().await {
+            let data = match msg {
+                Message::Binary(data) => data,
+                Message::Text(text) => text.into_bytes(),
+                Message::Close(_) => break,
+                Message::Ping(_) | Message::Pong(_) => continue,
+            };
+            if writer.write_all(&data).await.is_err() {
+                break;
+            }
+        }
+        let _ = writer.shutdown().await;
+    };
+
+    // whichever side finishes first ends the session
+    tokio::select! {
+        _ = upstream => {},
+        _ = downstream => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_live_xml() {
+        let xml = r#"<domain type='kvm' id='3'>
+  <devices>
+    <graphics type='spice' port='5901' autoport='yes' listen='127.0.0.1'>
+      <listen type='address' address='127.0.0.1'/>
+    </graphics>
+  </devices>
+</domain>"#;
+
+        let g = parse(xml).unwrap();
+        assert_eq!(g.kind, Kind::Spice);
+        assert_eq!(g.port, 5901);
+        assert_eq!(g.addr("kos-mos"), "kos-mos:5901");
+    }
+
+    #[test]
+    fn parse_skips_unallocated() {
+        let xml = r#"<graphics type="spice" port="-1" tlsPort="-1" autoport="yes"/>
+<graphics type="vnc" port="5900" listen="100.64.0.2"/>"#;
+
+        let g = parse(xml).unwrap();
+        assert_eq!(g.kind, Kind::Vnc);
+        assert_eq!(g.addr("kos-mos"), "100.64.0.2:5900");
+
+        assert_eq!(
+            parse(r#"<graphics type="spice" port="-1" autoport="yes"/>"#),
+            None
+        );
+    }
+
+    #[test]
+    fn attr_does_not_match_suffixes() {
+        let tag = r#"type="spice" tlsPort="5902" port="5901""#;
+        assert_eq!(attr(tag, "port"), Some("5901"));
+        assert_eq!(attr(tag, "tlsPort"), Some("5902"));
+        assert_eq!(attr(tag, "listen"), None);
+    }
+}
@@ -0,0 +1,317 @@
+//! Running commands on hypervisor hosts.
+//!
+//! Everything waifud does to a host outside of libvirt (making zvols, fetching
+//! images, rolling back snapshots) goes through a [`HostExecutor`] so that the
+//! provisioning logic can be exercised without a real hypervisor.
+
+use crate::{config, Error, Result};
+use async_trait::async_trait;
+use std::{convert::TryInto, process::Stdio, sync::Mutex};
+use tokio::process::Command;
+
+/// The result of running a command on a host.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Output {
+    pub success: bool,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl Output {
+    /// A successful run that printed `stdout`.
+    pub fn ok<T: Into<String>>(stdout: T) -> Self {
+        Output {
+            success: true,
+            stdout: stdout.into(),
+            stderr: String::new(),
+        }
+    }
+
+    /// A failed run that printed `stderr`.
+    pub fn fail<T: Into<String>>(stderr: T) -> Self {
+        Output {
+            success: false,
+            stdout: String::new(),
+            stderr: stderr.into(),
+        }
+    }
+
+    /// Turns a failed run into an error built from its stderr, otherwise returns stdout.
+    pub fn check<F>(self, err: F) -> Result<String>
+    where
+        F: FnOnce(String) -> Error,
+    {
+        if self.success {
+            Ok(self.stdout)
+        } else {
+            Err(err(self.stderr))
+        }
+    }
+}
+
+#[async_trait]
+pub trait HostExecutor: Send + Sync {
+    /// Runs a command on `host`. A command that exits non-zero is not an error at this
+    /// level, callers decide what a failure means with [`Output::check`].
+    async fn run(&self, host: &str, args: &[&str]) -> Result<Output>;
+
+    /// Runs a command on `from` and feeds its output into a command on `to`, like
+    /// `zfs send | zfs recv`. The run only succeeds if both sides do.
+    async fn pipe(
+        &self,
+        from: &str,
+        from_args: &[&str],
+        to: &str,
+        to_args: &[&str],
+    ) -> Result<Output>;
+
+    /// The directory on `host` that downloaded distro images are cached in.
+    fn cache_dir(&self, host: &str) -> String;
+
+    /// The libvirt connection URI for `host`.
+    fn libvirt_uri(&self, host: &str) -> String;
+}
+
+/// Runs commands over `ssh` as configured in [`config::Ssh`].
+pub struct Ssh {
+    cfg: config::Ssh,
+}
+
+impl Ssh {
+    pub fn new(cfg: config::Ssh) -> Self {
+        Ssh { cfg }
+    }
+
+    fn command(&self, host: &str, args: &[&str]) -> Command {
+        let mut cmd = Command::new("ssh");
+        cmd.arg("-oBatchMode=yes")
+            .arg(format!("-oStrictHostKeyChecking={}", self.cfg.known_hosts))
+            .arg(format!("-l{}", self.cfg.user));
+        if let Some(fname) = &self.cfg.known_hosts_file {
+            cmd.arg(format!("-oUserKnownHostsFile={}", fname));
+        }
+        if let Some(fname) = &self.cfg.identity_file {
+            cmd.arg("-i").arg(fname);
+        }
+
+        cmd.arg(host).arg(
+            args.iter()
+                .map(|arg| shell_quote(arg))
+                .collect::<Vec<String>>()
+                .join(" "),
+        );
+        cmd
+    }
+}
+
+#[async_trait]
+impl HostExecutor for Ssh {
+    #[instrument(skip(self), err)]
+    async fn run(&self, host: &str, args: &[&str]) -> Result<Output> {
+        let output = self.command(host, args).output().await?;
+
+        Ok(Output {
+            success: output.status.success(),
+            stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        })
+    }
+
+    #[instrument(skip(self), err)]
+    async fn pipe(
+        &self,
+        from: &str,
+        from_args: &[&str],
+        to: &str,
+        to_args: &[&str],
+    ) -> Result<Output> {
+        let mut sender = self
+            .command(from, from_args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdin: Stdio = sender
+            .stdout
+            .take()
+            .ok_or(Error::Catchall("can't take sender stdout".into()))?
+            .try_into()?;
+
+        // both sides are drained at once, so neither can block on a full stderr pipe
+        // while the other is being waited on
+        let mut receiver = self.command(to, to_args);
+        receiver.stdin(stdin);
+        let (received, sent) = tokio::join!(receiver.output(), sender.wait_with_output());
+        let (received, sent) = (received?, sent?);
+
+        Ok(Output {
+            success: sent.status.success() && received.status.success(),
+            stdout: String::from_utf8_lossy(&received.stdout).to_string(),
+            stderr: format!(
+                "{}{}",
+                String::from_utf8_lossy(&sent.stderr),
+                String::from_utf8_lossy(&received.stderr)
+            ),
+        })
+    }
+
+    fn cache_dir(&self, host: &str) -> String {
+        self.cfg
+            .host_cache_dirs
+            .iter()
+            .find(|hcd| hcd.host == host)
+            .map(|hcd| hcd.cache_dir.clone())
+            .unwrap_or_else(|| self.cfg.cache_dir.clone())
+    }
+
+    fn libvirt_uri(&self, host: &str) -> String {
+        let mut params = vec![];
+        if let Some(fname) = &self.cfg.identity_file {
+            params.push(format!("keyfile={}", fname));
+        }
+        if self.cfg.known_hosts == "no" {
+            params.push("no_verify=1".to_string());
+        }
+
+        let mut uri = format!("qemu+ssh://{}@{}/system", self.cfg.user, host);
+        if !params.is_empty() {
+            uri.push('?');
+            uri.push_str(&params.join("&"));
+        }
+        uri
+    }
+}
+
+/// `ssh` hands its arguments to the remote user's shell, so anything that isn't
+/// obviously safe gets single-quoted.
+fn shell_quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:@=+,%".contains(c))
+    {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r#"'\''"#))
+    }
+}
+
+/// An in-memory executor for tests. Every command succeeds with no output unless a
+/// response was registered for it with [`Fake::respond`].
+#[derive(Default)]
+pub struct Fake {
+    responses: Mutex<Vec<(Vec<String>, Output)>>,
+    calls: Mutex<Vec<(String, Vec<String>)>>,
+}
+
+impl Fake {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answers every command that starts with `prefix` with `output`. Later responses
+    /// take priority over earlier ones.
+    pub fn respond(&self, prefix: &[&str], output: Output) {
+        self.responses
+            .lock()
+            .unwrap()
+            .push((prefix.iter().map(|s| s.to_string()).collect(), output));
+    }
+
+    /// Every command run so far, in order, as `(host, args)`.
+    pub fn calls(&self) -> Vec<(String, Vec<String>)> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl HostExecutor for Fake {
+    async fn run(&self, host: &str, args: &[&str]) -> Result<Output> {
+        let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+        self.calls
+            .lock()
+            .unwrap()
+            .push((host.to_string(), args.clone()));
+
+        Ok(self
+            .responses
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|(prefix, _)| args.starts_with(prefix))
+            .map(|(_, output)| output.clone())
+            .unwrap_or(Output::ok("")))
+    }
+
+    async fn pipe(
+        &self,
+        from: &str,
+        from_args: &[&str],
+        to: &str,
+        to_args: &[&str],
+    ) -> Result<Output> {
+        let sent = self.run(from, from_args).await?;
+        let received = self.run(to, to_args).await?;
+
+        Ok(Output {
+            success: sent.success && received.success,
+            stdout: received.stdout,
+            stderr: format!("{}{}", sent.stderr, received.stderr),
+        })
+    }
+
+    fn cache_dir(&self, host: &str) -> String {
+        format!("/var/cache/waifud/{}", host)
+    }
+
+    fn libvirt_uri(&self, _host: &str) -> String {
+        "test:///default".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shell_quote_safe() {
+        assert_eq!(
+            shell_quote("rpool/local/vms/foo@init"),
+            "rpool/local/vms/foo@init"
+        );
+        assert_eq!(shell_quote("-V"), "-V");
+    }
+
+    #[test]
+    fn shell_quote_unsafe() {
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("it's"), r#"'it'\''s'"#);
+        assert_eq!(
+            shell_quote("https://example.com/?a=1&b=2"),
+            "'https://example.com/?a=1&b=2'"
+        );
+    }
+
+    #[tokio::test]
+    async fn fake_responses() {
+        let fake = Fake::new();
+        fake.respond(&["zfs", "list"], Output::fail("dataset does not exist"));
+
+        let out = fake
+            .run("vmhost1", &["zfs", "list", "rpool/foo"])
+            .await
+            .unwrap();
+        assert!(!out.success);
+        assert_eq!(out.stderr, "dataset does not exist");
+
+        let out = fake
+            .run("vmhost1", &["zfs", "create", "rpool/foo"])
+            .await
+            .unwrap();
+        assert!(out.success);
+
+        assert_eq!(fake.calls().len(), 2);
+        assert_eq!(fake.calls()[1].1, vec!["zfs", "create", "rpool/foo"]);
+    }
+}
@@ -0,0 +1,338 @@
+//! Static IPv4 addresses for instances.
+//!
+//! Subnets are listed in the config along with the hosts that can reach them. An
+//! instance created on one of those hosts gets the first free address in the subnet,
+//! which is stored on its row in `instances` and handed to the guest as a cloud-init
+//! network config. Deleting the row gives the address back. Instances on hosts without
+//! a subnet use DHCP on libvirt's `default` network.
+
+use crate::{config::Subnet, models::Instance, Error, Result};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::Ipv4Addr,
+};
+
+/// The subnet instances on `host` get addresses from, if any.
+pub fn subnet_for<'a>(subnets: &'a [Subnet], host: &str) -> Option<&'a Subnet> {
+    subnets
+        .iter()
+        .find(|subnet| subnet.hosts.iter().any(|h| h == host))
+}
+
+fn subnet_of<'a>(subnets: &'a [Subnet], ins: &Instance) -> Option<&'a Subnet> {
+    let name = ins.subnet.as_deref()?;
+    subnets.iter().find(|subnet| subnet.name == name)
+}
+
+/// The libvirt network an instance's interface is attached to.
+pub fn network<'a>(subnets: &'a [Subnet], ins: &Instance) -> &'a str {
+    subnet_of(subnets, ins)
+        .map(|subnet| subnet.network.as_str())
+        .unwrap_or("default")
+}
+
+/// The addresses a [`Subnet`] can hand out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Range {
+    pub prefix: u8,
+    pub gateway: Ipv4Addr,
+    start: u32,
+    end: u32,
+}
+
+impl TryFrom<&Subnet> for Range {
+    type Error = Error;
+
+    fn try_from(subnet: &Subnet) -> Result<Self> {
+        let invalid = |why: String| Error::InvalidSubnet(subnet.name.clone(), why);
+
+        let (addr, prefix) = subnet
+            .cidr
+            .split_once('/')
+            .ok_or_else(|| invalid(format!("{} is not in CIDR notation", subnet.cidr)))?;
+        let addr: Ipv4Addr = addr
+            .parse()
+            .map_err(|_| invalid(format!("{} is not an IPv4 address", addr)))?;
+        let prefix: u8 = prefix
+            .parse()
+            .ok()
+            .filter(|prefix| (8..=30).contains(prefix))
+            .ok_or_else(|| invalid(format!("/{} must be between /8 and /30", prefix)))?;
+
+        let mask = u32::MAX << (32 - prefix);
+        let network = u32::from(addr) & mask;
+        let broadcast = network | !mask;
+        let host_addr = |field: &str, value: &str| -> Result<u32> {
+            value
+                .parse::<Ipv4Addr>()
+                .ok()
+                .map(u32::from)
+                .filter(|ip| *ip > network && *ip < broadcast)
+                .ok_or_else(|| {
+                    invalid(format!(
+                        "{} {} is not a host address in {}",
+                        field, value, subnet.cidr
+                    ))
+                })
+        };
+
+        let gateway = host_addr("gateway", &subnet.gateway)?;
+        let start = match &subnet.range_start {
+            Some(ip) => host_addr("rangeStart", ip)?,
+            None => network + 1,
+        };
+        let end = match &subnet.range_end {
+            Some(ip) => host_addr("rangeEnd", ip)?,
+            None => broadcast - 1,
+        };
+        if start > end {
+            return Err(invalid("rangeStart is after rangeEnd".into()));
+        }
+
+        Ok(Range {
+            prefix,
+            gateway: gateway.into(),
+            start,
+            end,
+        })
+    }
+}
+
+impl Range {
+    /// Every address that can be handed out, in order.
+    pub fn addresses(&self) -> impl Iterator<Item = Ipv4Addr> {
+        let gateway = self.gateway;
+        (self.start..=self.end)
+            .map(Ipv4Addr::from)
+            .filter(move |ip| *ip != gateway)
+    }
+}
+
+/// Picks the first address in `subnet` that no instance is using.
+pub fn allocate(conn: &Connection, subnet: &Subnet) -> Result<Ipv4Addr> {
+    let range = Range::try_from(subnet)?;
+
+    let mut stmt = conn
+        .prepare("SELECT ip_address FROM instances WHERE subnet = ?1 AND ip_address IS NOT NULL")?;
+    let used = stmt
+        .query_map(params![subnet.name], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<HashSet<String>>>()?;
+
+    range
+        .addresses()
+        .find(|ip| !used.contains(&ip.to_string()))
+        .ok_or_else(|| Error::SubnetFull(subnet.name.clone()))
+}
+
+/// Gives a new instance an address if its host has a subnet. The address is only
+/// taken once the instance is inserted.
+pub fn assign(subnets: &[Subnet], conn: &Connection, ins: &mut Instance) -> Result {
+    if let Some(subnet) = subnet_for(subnets, &ins.host) {
+        ins.ip_address = Some(allocate(conn, subnet)?.to_string());
+        ins.subnet = Some(subnet.name.clone());
+    }
+
+    Ok(())
+}
+
+/// Makes sure an instance can keep its address on `host`.
+pub fn check_host(subnets: &[Subnet], ins: &Instance, host: &str) -> Result {
+    match subnet_of(subnets, ins) {
+        Some(subnet) if !subnet.hosts.iter().any(|h| h == host) => Err(Error::SubnetNotOnHost(
+            ins.name.clone(),
+            subnet.name.clone(),
+            host.to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// A cloud-init network config, version 2.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NetworkConfig {
+    pub version: u8,
+    pub ethernets: BTreeMap<String, Ethernet>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Ethernet {
+    #[serde(rename = "match")]
+    pub matches: Match,
+    #[serde(rename = "set-name")]
+    pub set_name: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dhcp4: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub addresses: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<Route>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nameservers: Option<Nameservers>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Match {
+    pub macaddress: String,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Route {
+    pub to: String,
+    pub via: String,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Nameservers {
+    pub addresses: Vec<String>,
+}
+
+/// How an instance should set up its network interface, statically if it has an
+/// address and with DHCP otherwise.
+pub fn network_config(subnets: &[Subnet], ins: &Instance) -> Result<NetworkConfig> {
+    let mut eth = Ethernet {
+        matches: Match {
+            macaddress: ins.mac_address.clone(),
+        },
+        set_name: "eth0".into(),
+        ..Ethernet::default()
+    };
+
+    match (subnet_of(subnets, ins), &ins.ip_address) {
+        (Some(subnet), Some(addr)) => {
+            let range = Range::try_from(subnet)?;
+            eth.addresses = vec![format!("{}/{}", addr, range.prefix)];
+            eth.routes = vec![Route {
+                to: "0.0.0.0/0".into(),
+                via: range.gateway.to_string(),
+            }];
+            if !subnet.nameservers.is_empty() {
+                eth.nameservers = Some(Nameservers {
+                    addresses: subnet.nameservers.clone(),
+                });
+            }
+        }
+        _ => eth.dhcp4 = true,
+    }
+
+    Ok(NetworkConfig {
+        version: 2,
+        ethernets: BTreeMap::from([("eth0".to_string(), eth)]),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subnet() -> Subnet {
+        Subnet {
+            name: "lab".into(),
+            hosts: vec!["vmhost1".into()],
+            network: "br-lab".into(),
+            cidr: "10.77.0.0/24".into(),
+            gateway: "10.77.0.1".into(),
+            range_start: None,
+            range_end: Some("10.77.0.3".into()),
+            nameservers: vec!["10.77.0.1".into()],
+        }
+    }
+
+    #[test]
+    fn ranges() {
+        let range = Range::try_from(&subnet()).unwrap();
+        assert_eq!(range.prefix, 24);
+        assert_eq!(
+            range.addresses().collect::<Vec<_>>(),
+            vec![Ipv4Addr::new(10, 77, 0, 2), Ipv4Addr::new(10, 77, 0, 3)]
+        );
+
+        for (cidr, gateway) in [
+            ("10.77.0.0", "10.77.0.1"),
+            ("10.77.0.0/31", "10.77.0.1"),
+            ("10.77.0.0/24", "10.77.1.1"),
+            ("10.77.0.0/24", "10.77.0.255"),
+        ] {
+            let s = Subnet {
+                cidr: cidr.into(),
+                gateway: gateway.into(),
+                ..subnet()
+            };
+            assert!(
+                matches!(Range::try_from(&s), Err(Error::InvalidSubnet(_, _))),
+                "{} via {}",
+                cidr,
+                gateway
+            );
+        }
+    }
+
+    #[test]
+    fn allocate_skips_used_addresses() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("./migrate/base_schema.sql"))
+            .unwrap();
+        conn.execute_batch(include_str!("./migrate/20261018-ipam.sql"))
+            .unwrap();
+
+        let s = subnet();
+        assert_eq!(allocate(&conn, &s).unwrap(), Ipv4Addr::new(10, 77, 0, 2));
+
+        conn.execute(
+            "INSERT INTO instances(uuid, name, host, mac_address, memory, disk_size, zvol_name, distro, ip_address, subnet) VALUES (?1, 'crobat', 'vmhost1', '', 512, 10, '', 'alpine', '10.77.0.2', 'lab')",
+            params![uuid::Uuid::new_v4()],
+        )
+        .unwrap();
+        assert_eq!(allocate(&conn, &s).unwrap(), Ipv4Addr::new(10, 77, 0, 3));
+
+        conn.execute(
+            "INSERT INTO instances(uuid, name, host, mac_address, memory, disk_size, zvol_name, distro, ip_address, subnet) VALUES (?1, 'golbat', 'vmhost1', '', 512, 10, '', 'alpine', '10.77.0.3', 'lab')",
+            params![uuid::Uuid::new_v4()],
+        )
+        .unwrap();
+        assert!(matches!(allocate(&conn, &s), Err(Error::SubnetFull(_))));
+    }
+
+    #[test]
+    fn static_network_config() {
+        let s = subnet();
+        let ins = Instance {
+            name: "crobat".into(),
+            mac_address: "52:54:00:12:34:56".into(),
+            ip_address: Some("10.77.0.2".into()),
+            subnet: Some(s.name.clone()),
+            ..Instance::default()
+        };
+
+        let yaml = serde_yaml::to_string(&network_config(&[s], &ins).unwrap()).unwrap();
+        let want = "version: 2
+ethernets:
+  eth0:
+    match:
+      macaddress: 52:54:00:12:34:56
+    set-name: eth0
+    addresses:
+    - 10.77.0.2/24
+    routes:
+    - to: 0.0.0.0/0
+      via: 10.77.0.1
+    nameservers:
+      addresses:
+      - 10.77.0.1
+";
+        assert_eq!(
+            serde_yaml::from_str::<serde_yaml::Value>(&yaml).unwrap(),
+            serde_yaml::from_str::<serde_yaml::Value>(want).unwrap()
+        );
+
+        let dhcp = Instance {
+            ip_address: None,
+            subnet: None,
+            ..ins
+        };
+        let eth = &network_config(&[], &dhcp).unwrap().ethernets["eth0"];
+        assert!(eth.dhcp4);
+        assert!(eth.addresses.is_empty());
+    }
+}
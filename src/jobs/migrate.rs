@@ -0,0 +1,548 @@
+//! Moving an instance to another host, one resumable step at a time.
+//!
+//! The zvol is shipped with `zfs send | zfs recv` from a temporary snapshot. Offline
+//! migrations stop the instance, send whatever changed since that snapshot and
+//! redefine the domain on the new host. Live migrations pause the instance instead,
+//! send what changed the same way and then have libvirt move its memory over to the
+//! received zvol, so it picks up where it left off after a short pause.
+
+use super::{
+    provision::{check_clones, define_domain, render_domain},
+    set_status,
+};
+use crate::{
+    host::HostExecutor,
+    models::{Instance, InstanceStatus, Job},
+    principal::Actor,
+    Config, Error, Result, State,
+};
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr, thread, time::Duration};
+use tokio::task::spawn_blocking;
+use virt::{connect::Connect, domain::Domain, sys::VIR_DOMAIN_PAUSED};
+
+pub const KIND: &str = "migrate";
+
+const BASE_SNAPSHOT: &str = "migrate-base";
+const FINAL_SNAPSHOT: &str = "migrate-final";
+
+/// How long a guest gets to shut down cleanly before it is forced off.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Migrate {
+    pub from: String,
+    pub to: String,
+    pub live: bool,
+    /// If the domain uses a SATA disk instead of virtio, read from its current XML.
+    pub sata: bool,
+    /// If the instance was running when the migration started.
+    pub was_active: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step {
+    SendBase,
+    StopDomain,
+    PauseDomain,
+    SendIncremental,
+    DefineDomain,
+    LiveMigrate,
+    SwitchHost,
+    StartDomain,
+    Cleanup,
+}
+
+impl Step {
+    pub const FIRST: Step = Step::SendBase;
+
+    pub fn next(&self, live: bool) -> Option<Step> {
+        match (self, live) {
+            (Step::SendBase, false) => Some(Step::StopDomain),
+            (Step::SendBase, true) => Some(Step::PauseDomain),
+            (Step::StopDomain, _) | (Step::PauseDomain, _) => Some(Step::SendIncremental),
+            (Step::SendIncremental, false) => Some(Step::DefineDomain),
+            (Step::SendIncremental, true) => Some(Step::LiveMigrate),
+            (Step::DefineDomain, _) => Some(Step::SwitchHost),
+            (Step::LiveMigrate, _) => Some(Step::SwitchHost),
+            (Step::SwitchHost, false) => Some(Step::StartDomain),
+            (Step::SwitchHost, true) => Some(Step::Cleanup),
+            (Step::StartDomain, _) => Some(Step::Cleanup),
+            (Step::Cleanup, _) => None,
+        }
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Step::SendBase => "send base",
+            Step::StopDomain => "stop domain",
+            Step::PauseDomain => "pause domain",
+            Step::SendIncremental => "send incremental",
+            Step::DefineDomain => "define domain",
+            Step::LiveMigrate => "live migrate",
+            Step::SwitchHost => "switch host",
+            Step::StartDomain => "start domain",
+            Step::Cleanup => "cleanup",
+        })
+    }
+}
+
+impl FromStr for Step {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "send base" => Step::SendBase,
+            "stop domain" => Step::StopDomain,
+            "pause domain" => Step::PauseDomain,
+            "send incremental" => Step::SendIncremental,
+            "define domain" => Step::DefineDomain,
+            "live migrate" => Step::LiveMigrate,
+            "switch host" => Step::SwitchHost,
+            "start domain" => Step::StartDomain,
+            "cleanup" => Step::Cleanup,
+            _ => return Err(Error::Catchall(format!("unknown migration step {s}"))),
+        })
+    }
+}
+
+/// Runs the step the job is currently on and returns the name of the one after it.
+/// Every step is safe to run again if waifud died halfway through it.
+#[instrument(skip(config, state, job), fields(uuid = %job.uuid), err)]
+pub async fn run_step(config: &Config, state: &State, job: &Job) -> Result<Option<String>> {
+    let step: Step = job.step.parse()?;
+    let m: Migrate = serde_json::from_value(job.data.clone())?;
+    let exec = &*state.exec;
+
+    let conn = state.pool.get().await?;
+    let mut ins = Instance::from_uuid(&conn, job.uuid)?;
+
+    match step {
+        Step::SendBase => {
+            if ins.status != InstanceStatus::Migrating {
+                set_status(
+                    &conn,
+                    &state.events,
+                    &mut ins,
+                    InstanceStatus::Migrating,
+                    None,
+                )?;
+            }
+
+            take_snapshot(exec, &m.from, &ins.zvol_name, BASE_SNAPSHOT).await?;
+
+            if has_snapshot(exec, &m.to, &ins.zvol_name, BASE_SNAPSHOT).await? {
+                debug!("base snapshot already sent");
+            } else if exec
+                .run(&m.to, &["zfs", "list", &ins.zvol_name])
+                .await?
+                .success
+            {
+                return Err(Error::ZvolAlreadyExists(m.to, ins.zvol_name));
+            } else {
+                debug!("sending base snapshot");
+                send_zvol(
+                    exec,
+                    &m.from,
+                    &m.to,
+                    &ins.zvol_name,
+                    &["-R", &format!("{}@{}", ins.zvol_name, BASE_SNAPSHOT)],
+                )
+                .await?;
+            }
+        }
+        Step::StopDomain => {
+            let uri = exec.libvirt_uri(&m.from);
+            let id = ins.uuid;
+            let result: Result = spawn_blocking(move || {
+                let lc = Connect::open(&uri)?;
+                let dom = Domain::lookup_by_uuid_string(&lc, &id.to_string())?;
+
+                if !dom.is_active()? {
+                    return Ok(());
+                }
+
+                debug!("shutting down domain");
+                dom.shutdown()?;
+                let mut waited = Duration::ZERO;
+                while dom.is_active()? && waited < SHUTDOWN_TIMEOUT {
+                    thread::sleep(Duration::from_secs(1));
+                    waited += Duration::from_secs(1);
+                }
+
+                if dom.is_active()? {
+                    warn!("domain didn't shut down in time, forcing it off");
+                    dom.destroy()?;
+                }
+                Ok(())
+            })
+            .await?;
+            result?;
+        }
+        Step::PauseDomain => {
+            let uri = exec.libvirt_uri(&m.from);
+            let id = ins.uuid;
+            let result: Result = spawn_blocking(move || {
+                let lc = Connect::open(&uri)?;
+                let dom = Domain::lookup_by_uuid_string(&lc, &id.to_string())?;
+
+                if dom.is_active()? && dom.get_state()?.0 != VIR_DOMAIN_PAUSED {
+                    debug!("pausing domain");
+                    dom.suspend()?;
+                }
+                Ok(())
+            })
+            .await?;
+            result?;
+        }
+        Step::SendIncremental => {
+            take_snapshot(exec, &m.from, &ins.zvol_name, FINAL_SNAPSHOT).await?;
+
+            if !has_snapshot(exec, &m.to, &ins.zvol_name, FINAL_SNAPSHOT).await? {
+                debug!("sending incremental snapshot");
+                send_zvol(
+                    exec,
+                    &m.from,
+                    &m.to,
+                    &ins.zvol_name,
+                    &[
+                        "-R",
+                        "-I",
+                        &format!("@{}", BASE_SNAPSHOT),
+                        &format!("{}@{}", ins.zvol_name, FINAL_SNAPSHOT),
+                    ],
+                )
+                .await?;
+            }
+        }
+        Step::DefineDomain => {
+            let xml = render_domain(config, &ins, m.sata)?;
+            define_domain(exec, &m.to, xml).await?;
+        }
+        Step::LiveMigrate => {
+            let src = exec.libvirt_uri(&m.from);
+            let dst = exec.libvirt_uri(&m.to);
+            let to = m.to.clone();
+            let id = ins.uuid;
+            let result: Result = spawn_blocking(move || {
+                use virt::sys::{VIR_MIGRATE_PERSIST_DEST, VIR_MIGRATE_UNDEFINE_SOURCE};
+
+                let dst = Connect::open(&dst)?;
+                let moved = match Domain::lookup_by_uuid_string(&dst, &id.to_string()) {
+                    Ok(dom) if dom.is_active()? => {
+                        debug!("domain already on destination");
+                        dom
+                    }
+                    _ => {
+                        let src = Connect::open(&src)?;
+                        let dom = Domain::lookup_by_uuid_string(&src, &id.to_string())?;
+
+                        // the disk was already sent while the domain was paused, so
+                        // only its memory is copied
+                        debug!("migrating domain");
+                        dom.migrate(
+                            &dst,
+                            VIR_MIGRATE_PERSIST_DEST | VIR_MIGRATE_UNDEFINE_SOURCE,
+                            &format!("tcp://{}", to),
+                            0,
+                        )?
+                    }
+                };
+
+                if moved.get_state()?.0 == VIR_DOMAIN_PAUSED {
+                    debug!("resuming domain");
+                    moved.resume()?;
+                }
+                Ok(())
+            })
+            .await?;
+            result?;
+        }
+        Step::SwitchHost => {
+            ins.host = m.to.clone();
+            conn.execute(
+                "UPDATE instances SET host = ?1 WHERE uuid = ?2",
+                params![ins.host, ins.uuid],
+            )?;
+            state
+                .events
+                .audit(&conn, &Actor::system("jobs"), "instance", "migrate", &ins)?;
+        }
+        Step::StartDomain => {
+            if m.was_active {
+                let uri = exec.libvirt_uri(&m.to);
+                let id = ins.uuid;
+                let result: Result = spawn_blocking(move || {
+                    let lc = Connect::open(&uri)?;
+                    let dom = Domain::lookup_by_uuid_string(&lc, &id.to_string())?;
+
+                    if !dom.is_active()? {
+                        debug!("starting domain");
+                        dom.create()?;
+                    }
+                    Ok(())
+                })
+                .await?;
+                result?;
+            }
+        }
+        Step::Cleanup => {
+            let uri = exec.libvirt_uri(&m.from);
+            let id = ins.uuid;
+            let result: Result = spawn_blocking(move || {
+                let lc = Connect::open(&uri)?;
+                // live migrations already undefined the source domain
+                if let Ok(dom) = Domain::lookup_by_uuid_string(&lc, &id.to_string()) {
+                    if dom.is_active()? {
+                        dom.destroy()?;
+                    }
+                    debug!("undefining source domain");
+                    dom.undefine_flags(virt::sys::VIR_DOMAIN_UNDEFINE_NVRAM)?;
+                }
+                Ok(())
+            })
+            .await?;
+            result?;
+
+            cleanup(exec, &m, &ins.zvol_name).await?;
+            let status = if m.was_active {
+                InstanceStatus::Running
+            } else {
+                InstanceStatus::Off
+            };
+            set_status(&conn, &state.events, &mut ins, status, None)?;
+        }
+    }
+
+    Ok(step.next(m.live).map(|s| s.to_string()))
+}
+
+async fn has_snapshot(exec: &dyn HostExecutor, host: &str, zvol: &str, name: &str) -> Result<bool> {
+    Ok(exec
+        .run(
+            host,
+            &[
+                "zfs",
+                "list",
+                "-t",
+                "snapshot",
+                &format!("{}@{}", zvol, name),
+            ],
+        )
+        .await?
+        .success)
+}
+
+async fn take_snapshot(exec: &dyn HostExecutor, host: &str, zvol: &str, name: &str) -> Result {
+    if has_snapshot(exec, host, zvol, name).await? {
+        return Ok(());
+    }
+
+    exec.run(host, &["zfs", "snapshot", &format!("{}@{}", zvol, name)])
+        .await?
+        .check(|stderr| Error::CantMakeSnapshot(host.to_string(), name.to_string(), stderr))?;
+
+    Ok(())
+}
+
+/// Pipes `zfs send <args>` on `from` into `zfs recv` on `to`.
+pub async fn send_zvol(
+    exec: &dyn HostExecutor,
+    from: &str,
+    to: &str,
+    zvol: &str,
+    args: &[&str],
+) -> Result {
+    let mut send = vec!["zfs", "send"];
+    send.extend_from_slice(args);
+
+    exec.pipe(from, &send, to, &["zfs", "recv", "-F", zvol])
+        .await?
+        .check(|stderr| Error::CantSendZvol(from.to_string(), to.to_string(), stderr))?;
+
+    Ok(())
+}
+
+/// Removes the source zvol and the temporary snapshots on the destination. The source
+/// zvol is left alone while other instances are cloned from it.
+async fn cleanup(exec: &dyn HostExecutor, m: &Migrate, zvol: &str) -> Result {
+    if exec.run(&m.from, &["zfs", "list", zvol]).await?.success {
+        check_clones(exec, &m.from, zvol).await?;
+        debug!("destroying source zvol");
+        exec.run(&m.from, &["zfs", "destroy", "-r", zvol])
+            .await?
+            .check(|stderr| Error::CantDeleteZvol(m.from.clone(), stderr))?;
+    }
+
+    for name in [BASE_SNAPSHOT, FINAL_SNAPSHOT] {
+        if has_snapshot(exec, &m.to, zvol, name).await? {
+            exec.run(&m.to, &["zfs", "destroy", &format!("{}@{}", zvol, name)])
+                .await?
+                .check(|stderr| {
+                    Error::CantDeleteSnapshot(m.to.clone(), name.to_string(), stderr)
+                })?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Marks the instance as failed once its migration job has run out of retries. The
+/// source copy is left alone so it can be started again by hand, other than being
+/// unpaused if a live migration paused it.
+pub async fn fail(state: &State, job: &Job, why: &str) -> Result {
+    let conn = state.pool.get().await?;
+    let mut ins = Instance::from_uuid(&conn, job.uuid)?;
+
+    let m: Migrate = serde_json::from_value(job.data.clone())?;
+    if m.live {
+        let uri = state.exec.libvirt_uri(&m.from);
+        let id = ins.uuid;
+        let result: Result = spawn_blocking(move || {
+            let lc = Connect::open(&uri)?;
+            let dom = Domain::lookup_by_uuid_string(&lc, &id.to_string())?;
+            if dom.get_state()?.0 == VIR_DOMAIN_PAUSED {
+                dom.resume()?;
+            }
+            Ok(())
+        })
+        .await?;
+        if let Err(why) = result {
+            warn!(uuid = %ins.uuid, "can't resume source domain: {why}");
+        }
+    }
+
+    error!(uuid = %ins.uuid, name = %ins.name, step = %job.step, "can't migrate instance: {why}");
+    set_status(
+        &conn,
+        &state.events,
+        &mut ins,
+        InstanceStatus::MigrationFailed,
+        Some(format!("{}: {}", job.step, why)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::{Fake, Output};
+
+    fn migrate() -> Migrate {
+        Migrate {
+            from: "vmhost1".into(),
+            to: "vmhost2".into(),
+            live: false,
+            sata: false,
+            was_active: true,
+        }
+    }
+
+    #[test]
+    fn steps_round_trip() {
+        for live in [false, true] {
+            let mut step = Some(Step::FIRST);
+            while let Some(s) = step {
+                assert_eq!(s.to_string().parse::<Step>().unwrap(), s);
+                step = s.next(live);
+            }
+        }
+    }
+
+    #[test]
+    fn live_skips_offline_steps() {
+        let mut steps = vec![];
+        let mut step = Some(Step::FIRST);
+        while let Some(s) = step {
+            steps.push(s);
+            step = s.next(true);
+        }
+
+        assert_eq!(
+            steps,
+            vec![
+                Step::SendBase,
+                Step::PauseDomain,
+                Step::SendIncremental,
+                Step::LiveMigrate,
+                Step::SwitchHost,
+                Step::Cleanup
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn send_pipes_between_hosts() {
+        let exec = Fake::new();
+        send_zvol(
+            &exec,
+            "vmhost1",
+            "vmhost2",
+            "rpool/safe/vms/foo",
+            &["-R", "rpool/safe/vms/foo@migrate-base"],
+        )
+        .await
+        .unwrap();
+
+        let calls = exec.calls();
+        assert_eq!(calls[0].0, "vmhost1");
+        assert_eq!(
+            calls[0].1,
+            vec!["zfs", "send", "-R", "rpool/safe/vms/foo@migrate-base"]
+        );
+        assert_eq!(calls[1].0, "vmhost2");
+        assert_eq!(calls[1].1, vec!["zfs", "recv", "-F", "rpool/safe/vms/foo"]);
+    }
+
+    #[tokio::test]
+    async fn send_failures_are_reported() {
+        let exec = Fake::new();
+        exec.respond(&["zfs", "recv"], Output::fail("out of space"));
+
+        let err = send_zvol(&exec, "vmhost1", "vmhost2", "rpool/safe/vms/foo", &["-R"])
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, Error::CantSendZvol(from, to, _) if from == "vmhost1" && to == "vmhost2")
+        );
+    }
+
+    #[tokio::test]
+    async fn cleanup_leaves_destination_zvol() {
+        let exec = Fake::new();
+        cleanup(&exec, &migrate(), "rpool/safe/vms/foo")
+            .await
+            .unwrap();
+
+        let destroyed: Vec<_> = exec
+            .calls()
+            .into_iter()
+            .filter(|(_, args)| args[1] == "destroy")
+            .collect();
+        assert_eq!(destroyed[0].0, "vmhost1");
+        assert_eq!(
+            destroyed[0].1,
+            vec!["zfs", "destroy", "-r", "rpool/safe/vms/foo"]
+        );
+        assert!(destroyed[1..]
+            .iter()
+            .all(|(host, args)| host == "vmhost2" && args[2].contains('@')));
+    }
+
+    #[tokio::test]
+    async fn cleanup_keeps_cloned_source() {
+        let exec = Fake::new();
+        exec.respond(
+            &["zfs", "list", "-H"],
+            Output::ok("rpool/safe/vms/foo@init\trpool/safe/vms/bar\n"),
+        );
+
+        let err = cleanup(&exec, &migrate(), "rpool/safe/vms/foo")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::HasClones(_, names) if names == "bar"));
+        assert!(exec.calls().iter().all(|(_, args)| args[1] != "destroy"));
+    }
+}
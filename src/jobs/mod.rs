@@ -0,0 +1,270 @@
+use crate::{
+    events::Bus,
+    models::{Instance, InstanceStatus, Job},
+    principal::Actor,
+    Config, Error, Result, State,
+};
+use bb8::PooledConnection;
+use bb8_rusqlite::RusqliteConnectionManager;
+use chrono::Utc;
+use rusqlite::params;
+use serde::Serialize;
+use std::{sync::Arc, time::Duration};
+use tokio::time::sleep;
+use uuid::Uuid;
+
+pub mod migrate;
+pub mod provision;
+
+/// The number of times a single step is attempted before the job is marked as failed.
+pub const MAX_ATTEMPTS: i32 = 8;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Seconds to wait before retrying a step that failed `attempts` times.
+fn backoff(attempts: i32) -> i64 {
+    (5 * 2i64.pow(attempts.clamp(0, 10) as u32)).min(15 * 60)
+}
+
+/// Puts a new job into the queue, returning its ID.
+pub fn enqueue<T: Serialize>(
+    conn: &PooledConnection<'_, RusqliteConnectionManager>,
+    events: &Bus,
+    actor: &Actor,
+    uuid: Uuid,
+    kind: &str,
+    step: &str,
+    data: &T,
+) -> Result<i64> {
+    conn.execute(
+        "INSERT INTO jobs(uuid, kind, step, data) VALUES (?1, ?2, ?3, ?4)",
+        params![uuid, kind, step, serde_json::to_string(data)?],
+    )?;
+    let id = conn.last_insert_rowid();
+
+    events.audit(
+        conn,
+        actor,
+        "job",
+        "create",
+        &serde_json::json!({
+            "id": id,
+            "uuid": uuid.to_string(),
+            "kind": kind,
+        }),
+    )?;
+
+    Ok(id)
+}
+
+/// Moves an instance to a new status and records the change in the audit log.
+pub(crate) fn set_status(
+    conn: &PooledConnection<'_, RusqliteConnectionManager>,
+    events: &Bus,
+    ins: &mut Instance,
+    status: InstanceStatus,
+    reason: Option<String>,
+) -> Result {
+    ins.set_status(conn, events, status, reason)?;
+    events.audit(
+        conn,
+        &Actor::system("jobs"),
+        "instance",
+        &ins.status.to_string(),
+        ins,
+    )?;
+
+    Ok(())
+}
+
+/// Cancels every job for an instance that has not started running yet.
+pub fn cancel_for_instance(
+    conn: &PooledConnection<'_, RusqliteConnectionManager>,
+    uuid: Uuid,
+) -> Result<usize> {
+    Ok(conn.execute(
+        "UPDATE jobs SET state = 'cancelled', updated_at = ?2 WHERE uuid = ?1 AND state = 'pending'",
+        params![uuid, Utc::now().timestamp()],
+    )?)
+}
+
+/// If an instance has a job that is queued or running.
+pub fn has_unfinished(
+    conn: &PooledConnection<'_, RusqliteConnectionManager>,
+    uuid: Uuid,
+) -> Result<bool> {
+    Ok(conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM jobs WHERE uuid = ?1 AND state IN ('pending', 'running'))",
+        params![uuid],
+        |row| row.get(0),
+    )?)
+}
+
+/// Runs the job queue forever. Jobs that were running when waifud last stopped are
+/// picked back up at the step they were on.
+#[instrument(skip_all)]
+pub async fn run(config: Arc<Config>, state: Arc<State>) {
+    match resume(&state).await {
+        Ok(0) => {}
+        Ok(n) => info!("resuming {n} interrupted job(s)"),
+        Err(why) => error!("can't resume interrupted jobs: {why}"),
+    }
+
+    loop {
+        match claim(&state).await {
+            Ok(Some(job)) => {
+                let config = config.clone();
+                let state = state.clone();
+                tokio::spawn(async move {
+                    let id = job.id;
+                    if let Err(why) = work(config, state.clone(), job).await {
+                        error!(job = id, "can't record job progress: {why}");
+                        if let Err(why) = release(&state, id, &why.to_string()).await {
+                            error!(job = id, "can't put job back in the queue: {why}");
+                        }
+                    }
+                });
+            }
+            Ok(None) => sleep(POLL_INTERVAL).await,
+            Err(why) => {
+                error!("can't claim job: {why}");
+                sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+}
+
+async fn resume(state: &State) -> Result<usize> {
+    let conn = state.pool.get().await?;
+
+    Ok(conn.execute(
+        "UPDATE jobs SET state = 'pending', updated_at = ?1 WHERE state = 'running'",
+        params![Utc::now().timestamp()],
+    )?)
+}
+
+/// Puts a job that stopped partway through a step back in the queue so it isn't
+/// stuck as running until waifud restarts. The step runs again after a backoff.
+async fn release(state: &State, id: i64, why: &str) -> Result {
+    let conn = state.pool.get().await?;
+    let now = Utc::now().timestamp();
+
+    conn.execute(
+        "UPDATE jobs SET state = 'pending', last_error = ?2, run_after = ?3, updated_at = ?4 WHERE id = ?1 AND state = 'running'",
+        params![id, why, now + backoff(1), now],
+    )?;
+
+    Ok(())
+}
+
+async fn claim(state: &State) -> Result<Option<Job>> {
+    let conn = state.pool.get().await?;
+    let now = Utc::now().timestamp();
+
+    let mut job = match Job::next_pending(&conn, now)? {
+        Some(job) => job,
+        None => return Ok(None),
+    };
+
+    let claimed = conn.execute(
+        "UPDATE jobs SET state = 'running', updated_at = ?2 WHERE id = ?1 AND state = 'pending'",
+        params![job.id, now],
+    )?;
+    if claimed != 1 {
+        return Ok(None);
+    }
+    job.state = "running".into();
+
+    Ok(Some(job))
+}
+
+#[instrument(skip(config, state, job), fields(job = job.id, kind = %job.kind))]
+async fn work(config: Arc<Config>, state: Arc<State>, job: Job) -> Result {
+    let mut job = job;
+
+    loop {
+        {
+            let conn = state.pool.get().await?;
+            let current: String = conn.query_row(
+                "SELECT state FROM jobs WHERE id = ?1",
+                params![job.id],
+                |row| row.get(0),
+            )?;
+            if current != "running" {
+                info!("job is now {current}, stopping");
+                return Ok(());
+            }
+        }
+
+        debug!(step = %job.step, attempt = job.attempts + 1, "running step");
+        let result = match job.kind.as_str() {
+            provision::KIND => provision::run_step(&config, &state, &job).await,
+            migrate::KIND => migrate::run_step(&config, &state, &job).await,
+            kind => Err(Error::Catchall(format!("unknown job kind {kind}"))),
+        };
+
+        let conn = state.pool.get().await?;
+        let now = Utc::now().timestamp();
+        job.attempts += 1;
+
+        match result {
+            Ok(next) => {
+                conn.execute(
+                    "INSERT INTO job_steps(job_id, step, attempt) VALUES (?1, ?2, ?3)",
+                    params![job.id, job.step, job.attempts],
+                )?;
+
+                match next {
+                    Some(next) => {
+                        conn.execute(
+                            "UPDATE jobs SET step = ?2, attempts = 0, last_error = NULL, updated_at = ?3 WHERE id = ?1",
+                            params![job.id, next, now],
+                        )?;
+                        job.step = next;
+                        job.attempts = 0;
+                    }
+                    None => {
+                        conn.execute(
+                            "UPDATE jobs SET state = 'done', attempts = 0, last_error = NULL, updated_at = ?2 WHERE id = ?1",
+                            params![job.id, now],
+                        )?;
+                        info!("job finished");
+                        return Ok(());
+                    }
+                }
+            }
+            Err(why) => {
+                let why = why.to_string();
+                error!(step = %job.step, attempt = job.attempts, "step failed: {why}");
+
+                conn.execute(
+                    "INSERT INTO job_steps(job_id, step, attempt, error) VALUES (?1, ?2, ?3, ?4)",
+                    params![job.id, job.step, job.attempts, why],
+                )?;
+
+                if job.attempts >= MAX_ATTEMPTS {
+                    conn.execute(
+                        "UPDATE jobs SET state = 'failed', attempts = ?2, last_error = ?3, updated_at = ?4 WHERE id = ?1",
+                        params![job.id, job.attempts, why, now],
+                    )?;
+                    drop(conn);
+
+                    match job.kind.as_str() {
+                        provision::KIND => provision::fail(&state, &job, &why).await?,
+                        migrate::KIND => migrate::fail(&state, &job, &why).await?,
+                        _ => {}
+                    }
+
+                    return Ok(());
+                }
+
+                conn.execute(
+                    "UPDATE jobs SET state = 'pending', attempts = ?2, last_error = ?3, run_after = ?4, updated_at = ?5 WHERE id = ?1",
+                    params![job.id, job.attempts, why, now + backoff(job.attempts), now],
+                )?;
+
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,552 @@
+//! Provisioning a new instance, one resumable step at a time.
+
+use super::set_status;
+use crate::{
+    host::HostExecutor,
+    ipam,
+    libvirt::NewInstance,
+    models::{Distro, Instance, InstanceStatus, Job},
+    Config, Error, Result, State,
+};
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+use tokio::task::spawn_blocking;
+use virt::{connect::Connect, domain::Domain};
+
+pub const KIND: &str = "provision";
+
+/// Everything needed to turn an `instances` row into a running VM.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Provision {
+    pub details: NewInstance,
+    pub distro: Distro,
+    pub mac_addr: String,
+    /// The snapshot to `zfs clone` the zvol from instead of hydrating it from the
+    /// distro image.
+    #[serde(default)]
+    pub clone_from: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step {
+    FetchImage,
+    CreateZvol,
+    HydrateZvol,
+    CloneZvol,
+    InitSnapshot,
+    DefineDomain,
+    StartDomain,
+}
+
+impl Step {
+    pub const FIRST: Step = Step::FetchImage;
+
+    pub fn next(&self) -> Option<Step> {
+        match self {
+            Step::FetchImage => Some(Step::CreateZvol),
+            Step::CreateZvol => Some(Step::HydrateZvol),
+            Step::HydrateZvol => Some(Step::InitSnapshot),
+            Step::CloneZvol => Some(Step::InitSnapshot),
+            Step::InitSnapshot => Some(Step::DefineDomain),
+            Step::DefineDomain => Some(Step::StartDomain),
+            Step::StartDomain => None,
+        }
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Step::FetchImage => "fetch image",
+            Step::CreateZvol => "create zvol",
+            Step::HydrateZvol => "hydrate zvol",
+            Step::CloneZvol => "clone zvol",
+            Step::InitSnapshot => "init snapshot",
+            Step::DefineDomain => "define domain",
+            Step::StartDomain => "start domain",
+        })
+    }
+}
+
+impl FromStr for Step {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "fetch image" => Step::FetchImage,
+            "create zvol" => Step::CreateZvol,
+            "hydrate zvol" => Step::HydrateZvol,
+            "clone zvol" => Step::CloneZvol,
+            "init snapshot" => Step::InitSnapshot,
+            "define domain" => Step::DefineDomain,
+            "start domain" => Step::StartDomain,
+            _ => return Err(Error::Catchall(format!("unknown provisioning step {s}"))),
+        })
+    }
+}
+
+/// Runs the step the job is currently on and returns the name of the one after it.
+/// Every step is safe to run again if waifud died halfway through it.
+#[instrument(skip(config, state, job), fields(uuid = %job.uuid), err)]
+pub async fn run_step(config: &Config, state: &State, job: &Job) -> Result<Option<String>> {
+    let step: Step = job.step.parse()?;
+    let p: Provision = serde_json::from_value(job.data.clone())?;
+    let details = &p.details;
+    let distro = &p.distro;
+
+    let conn = state.pool.get().await?;
+    let mut ins = Instance::from_uuid(&conn, job.uuid)?;
+
+    match step {
+        Step::FetchImage => {
+            if !image_exists(&*state.exec, &ins.host, distro).await? {
+                debug!("downloading image");
+                set_status(
+                    &conn,
+                    &state.events,
+                    &mut ins,
+                    InstanceStatus::DownloadingImage,
+                    None,
+                )?;
+                download_image(&*state.exec, &ins.host, distro).await?;
+            }
+        }
+        Step::CreateZvol => {
+            create_zvol(
+                &*state.exec,
+                &ins.host,
+                &ins.zvol_name,
+                details.disk_size_gb.unwrap(),
+            )
+            .await?;
+        }
+        Step::HydrateZvol => {
+            debug!("hydrating zvol");
+            set_status(
+                &conn,
+                &state.events,
+                &mut ins,
+                InstanceStatus::HydratingZvol,
+                None,
+            )?;
+            hydrate_zvol(&*state.exec, &ins.host, distro, &ins.zvol_name).await?;
+        }
+        Step::CloneZvol => {
+            let snapshot = p
+                .clone_from
+                .as_deref()
+                .ok_or_else(|| Error::Catchall("clone job has no source snapshot".into()))?;
+            debug!("cloning zvol from {}", snapshot);
+            clone_zvol(&*state.exec, &ins.host, snapshot, &ins.zvol_name).await?;
+        }
+        Step::InitSnapshot => {
+            debug!("making init snapshot");
+            snapshot_zvol(&*state.exec, &ins.host, &ins.zvol_name, "init").await?;
+        }
+        Step::DefineDomain => {
+            let xml = render_domain(config, &ins, details.sata.unwrap())?;
+            define_domain(&*state.exec, &ins.host, xml).await?;
+        }
+        Step::StartDomain => {
+            // cloud-init can report back before this step finishes, so the status has
+            // to be set before the domain is started
+            if ins.status.can_become(InstanceStatus::WaitingForCloudInit) {
+                ins.start_boot(&conn)?;
+                set_status(
+                    &conn,
+                    &state.events,
+                    &mut ins,
+                    InstanceStatus::WaitingForCloudInit,
+                    None,
+                )?;
+            }
+
+            let uri = state.exec.libvirt_uri(&ins.host);
+            let id = ins.uuid;
+            let result: Result = spawn_blocking(move || {
+                let lc = Connect::open(&uri)?;
+                let dom = Domain::lookup_by_uuid_string(&lc, &id.to_string())?;
+
+                if !dom.is_active()? {
+                    debug!("starting domain");
+                    dom.create()?;
+                }
+                Ok(())
+            })
+            .await?;
+            result?;
+        }
+    }
+
+    Ok(step.next().map(|s| s.to_string()))
+}
+
+/// Renders the libvirt domain XML for an instance from `templates/base.rs.xml`.
+pub fn render_domain(config: &Config, ins: &Instance, sata: bool) -> Result<String> {
+    let zvol_prefix = ins
+        .zvol_name
+        .rsplit_once('/')
+        .map(|(prefix, _)| prefix.to_string())
+        .ok_or_else(|| Error::Catchall(format!("zvol name {} has no prefix", ins.zvol_name)))?;
+    let mut buf: Vec<u8> = vec![];
+
+    debug!("rendering xml");
+    crate::templates::base_xml(
+        &mut buf,
+        ins.name.clone(),
+        ins.uuid.to_string(),
+        ins.mac_address.clone(),
+        ipam::network(&config.subnets, ins).to_string(),
+        zvol_prefix,
+        sata,
+        ins.memory * 1024,
+        ins.cpus,
+        format!("{}/api/cloudinit/{}/", config.base_url, ins.uuid),
+        config.qemu_path.clone(),
+    )?;
+
+    let buf = String::from_utf8(buf).unwrap();
+    trace!("libvirt xml:\n{}", buf);
+    Ok(buf)
+}
+
+/// Defines (or redefines) a domain on `host`.
+pub async fn define_domain(exec: &dyn HostExecutor, host: &str, xml: String) -> Result {
+    let uri = exec.libvirt_uri(host);
+    spawn_blocking(move || {
+        debug!("connecting to host");
+        let lc = Connect::open(&uri)?;
+
+        debug!("defining domain");
+        Domain::define_xml(&lc, &xml)?;
+        Ok(())
+    })
+    .await?
+}
+
+fn image_path(exec: &dyn HostExecutor, host: &str, distro: &Distro) -> String {
+    format!("{}/{}", exec.cache_dir(host), distro.sha256sum)
+}
+
+pub async fn image_exists(exec: &dyn HostExecutor, host: &str, distro: &Distro) -> Result<bool> {
+    debug!("checking if image exists");
+    Ok(exec
+        .run(host, &["test", "-f", &image_path(exec, host, distro)])
+        .await?
+        .success)
+}
+
+/// Downloads a distro image into the host's cache, leaving nothing behind if it fails.
+pub async fn download_image(exec: &dyn HostExecutor, host: &str, distro: &Distro) -> Result {
+    let fname = image_path(exec, host, distro);
+
+    exec.run(host, &["mkdir", "-p", &exec.cache_dir(host)])
+        .await?
+        .check(|stderr| Error::CantDownloadImage(distro.download_url.clone(), stderr))?;
+
+    let output = exec
+        .run(host, &["wget", "-O", &fname, &distro.download_url])
+        .await?;
+    if !output.success {
+        exec.run(host, &["rm", "-f", &fname]).await?;
+        return Err(Error::CantDownloadImage(
+            distro.download_url.clone(),
+            output.stderr,
+        ));
+    }
+
+    Ok(())
+}
+
+/// Creates a zvol unless one with that name already exists.
+pub async fn create_zvol(exec: &dyn HostExecutor, host: &str, zvol: &str, size_gb: i32) -> Result {
+    if exec.run(host, &["zfs", "list", zvol]).await?.success {
+        debug!("zvol already exists");
+        return Ok(());
+    }
+
+    debug!("making zvol");
+    exec.run(
+        host,
+        &["zfs", "create", "-V", &format!("{}G", size_gb), zvol],
+    )
+    .await?
+    .check(|stderr| Error::CantMakeZvol(host.to_string(), stderr))?;
+
+    Ok(())
+}
+
+/// Writes a cached distro image onto a zvol.
+pub async fn hydrate_zvol(
+    exec: &dyn HostExecutor,
+    host: &str,
+    distro: &Distro,
+    zvol: &str,
+) -> Result {
+    exec.run(
+        host,
+        &[
+            "qemu-img",
+            "convert",
+            "-O",
+            "raw",
+            &image_path(exec, host, distro),
+            &format!("/dev/zvol/{}", zvol),
+        ],
+    )
+    .await?
+    .check(|stderr| Error::CantHydrateZvol(host.to_string(), stderr))?;
+
+    Ok(())
+}
+
+/// Makes a zvol that shares its blocks with `snapshot` unless one with that name
+/// already exists. The source snapshot can't be destroyed while the clone exists.
+pub async fn clone_zvol(exec: &dyn HostExecutor, host: &str, snapshot: &str, zvol: &str) -> Result {
+    if exec.run(host, &["zfs", "list", zvol]).await?.success {
+        debug!("zvol already exists");
+        return Ok(());
+    }
+
+    exec.run(host, &["zfs", "clone", snapshot, zvol])
+        .await?
+        .check(|stderr| Error::CantMakeZvol(host.to_string(), stderr))?;
+
+    Ok(())
+}
+
+/// Fails if any zvol was cloned from `target`, which is either a snapshot or a whole
+/// zvol, and so would stop it from being destroyed. Clones are named after the
+/// instances they belong to, so those names are what the error lists.
+pub async fn check_clones(exec: &dyn HostExecutor, host: &str, target: &str) -> Result {
+    let (zvol, snapshot) = match target.split_once('@') {
+        Some((zvol, snapshot)) => (zvol, Some(snapshot)),
+        None => (target, None),
+    };
+
+    let out = exec
+        .run(
+            host,
+            &[
+                "zfs",
+                "list",
+                "-H",
+                "-t",
+                "snapshot",
+                "-o",
+                "name,clones",
+                "-r",
+                zvol,
+            ],
+        )
+        .await?
+        .check(|stderr| Error::CantListSnapshots(host.to_string(), stderr))?;
+
+    let clones = parse_clones(zvol, snapshot, &out);
+    if clones.is_empty() {
+        return Ok(());
+    }
+
+    Err(Error::HasClones(target.to_string(), clones.join(", ")))
+}
+
+/// Parses the output of `zfs list -H -t snapshot -o name,clones` into the names of
+/// the clones, keeping only those of `snapshot` if it is set.
+fn parse_clones(zvol: &str, snapshot: Option<&str>, output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (name, clones) = line.split_once('\t')?;
+            let name = name.strip_prefix(zvol)?.strip_prefix('@')?;
+            if snapshot.map_or(false, |snapshot| snapshot != name) || clones == "-" {
+                return None;
+            }
+            Some(clones)
+        })
+        .flat_map(|clones| clones.split(','))
+        .map(|clone| clone.rsplit('/').next().unwrap_or(clone).to_string())
+        .collect()
+}
+
+/// Takes a snapshot of a zvol unless it already has one with that name.
+pub async fn snapshot_zvol(exec: &dyn HostExecutor, host: &str, zvol: &str, name: &str) -> Result {
+    let snapshot = format!("{}@{}", zvol, name);
+    if exec
+        .run(host, &["zfs", "list", "-t", "snapshot", &snapshot])
+        .await?
+        .success
+    {
+        return Ok(());
+    }
+
+    exec.run(host, &["zfs", "snapshot", &snapshot])
+        .await?
+        .check(|stderr| Error::CantMakeInitSnapshot(host.to_string(), stderr))?;
+
+    Ok(())
+}
+
+/// Marks the instance as failed once its provisioning job has run out of retries.
+pub async fn fail(state: &State, job: &Job, why: &str) -> Result {
+    let conn = state.pool.get().await?;
+    let mut ins = Instance::from_uuid(&conn, job.uuid)?;
+
+    error!(uuid = %ins.uuid, name = %ins.name, step = %job.step, "can't make instance: {why}");
+    set_status(
+        &conn,
+        &state.events,
+        &mut ins,
+        InstanceStatus::ProvisioningFailed,
+        Some(format!("{}: {}", job.step, why)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        host::{Fake, Output},
+        vendordata::Family,
+    };
+
+    fn distro() -> Distro {
+        Distro {
+            name: "alpine-edge".into(),
+            download_url: "https://example.com/alpine.qcow2".into(),
+            sha256sum: "deadbeef".into(),
+            min_size: 2,
+            format: "waifud://qcow2".into(),
+            family: Family::Generic,
+        }
+    }
+
+    #[tokio::test]
+    async fn download_cleans_up_after_failure() {
+        let exec = Fake::new();
+        exec.respond(&["wget"], Output::fail("404 not found"));
+
+        let err = download_image(&exec, "vmhost1", &distro())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::CantDownloadImage(_, _)));
+
+        let calls = exec.calls();
+        assert_eq!(
+            calls.last().unwrap().1,
+            vec!["rm", "-f", "/var/cache/waifud/vmhost1/deadbeef"]
+        );
+    }
+
+    #[tokio::test]
+    async fn create_zvol_is_idempotent() {
+        let exec = Fake::new();
+
+        create_zvol(&exec, "vmhost1", "rpool/local/vms/foo", 10)
+            .await
+            .unwrap();
+        assert_eq!(exec.calls().len(), 1);
+
+        exec.respond(&["zfs", "list"], Output::fail("dataset does not exist"));
+        create_zvol(&exec, "vmhost1", "rpool/local/vms/foo", 10)
+            .await
+            .unwrap();
+        assert_eq!(
+            exec.calls().last().unwrap().1,
+            vec!["zfs", "create", "-V", "10G", "rpool/local/vms/foo"]
+        );
+    }
+
+    #[tokio::test]
+    async fn snapshot_errors_are_reported() {
+        let exec = Fake::new();
+        exec.respond(&["zfs", "list"], Output::fail("dataset does not exist"));
+        exec.respond(&["zfs", "snapshot"], Output::fail("out of space"));
+
+        let err = snapshot_zvol(&exec, "vmhost1", "rpool/local/vms/foo", "init")
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::CantMakeInitSnapshot(host, why) if host == "vmhost1" && why == "out of space"
+        ));
+    }
+
+    #[test]
+    fn steps_round_trip() {
+        for first in [Step::FIRST, Step::CloneZvol] {
+            let mut step = Some(first);
+            while let Some(s) = step {
+                assert_eq!(s.to_string().parse::<Step>().unwrap(), s);
+                step = s.next();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn clone_zvol_from_snapshot() {
+        let exec = Fake::new();
+        exec.respond(&["zfs", "list"], Output::fail("dataset does not exist"));
+
+        clone_zvol(
+            &exec,
+            "vmhost1",
+            "rpool/safe/vms/foo@init",
+            "rpool/safe/vms/bar",
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            exec.calls().last().unwrap().1,
+            vec![
+                "zfs",
+                "clone",
+                "rpool/safe/vms/foo@init",
+                "rpool/safe/vms/bar"
+            ]
+        );
+    }
+
+    const CLONES: &str = "rpool/safe/vms/foo@init\t-\nrpool/safe/vms/foo@golden\trpool/safe/vms/bar,rpool/safe/vms/baz\n";
+
+    #[tokio::test]
+    async fn zvols_with_clones_are_kept() {
+        let exec = Fake::new();
+        exec.respond(&["zfs", "list"], Output::ok(CLONES));
+
+        let err = check_clones(&exec, "vmhost1", "rpool/safe/vms/foo")
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::HasClones(target, names) if target == "rpool/safe/vms/foo" && names == "bar, baz"
+        ));
+        assert_eq!(
+            exec.calls()[0].1,
+            vec![
+                "zfs",
+                "list",
+                "-H",
+                "-t",
+                "snapshot",
+                "-o",
+                "name,clones",
+                "-r",
+                "rpool/safe/vms/foo"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn snapshots_without_clones_can_go() {
+        let exec = Fake::new();
+        exec.respond(&["zfs", "list"], Output::ok(CLONES));
+
+        check_clones(&exec, "vmhost1", "rpool/safe/vms/foo@init")
+            .await
+            .unwrap();
+        let err = check_clones(&exec, "vmhost1", "rpool/safe/vms/foo@golden")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::HasClones(_, names) if names == "bar, baz"));
+    }
+}
@@ -9,7 +9,8 @@ use bb8::Pool;
 use bb8_rusqlite::RusqliteConnectionManager;
 use hyper::header::InvalidHeaderValue;
 use rusqlite::Connection;
-use std::{env, fmt, net::AddrParseError};
+use std::{env, fmt, net::AddrParseError, sync::Arc};
+use tokio::sync::RwLock;
 
 pub const APPLICATION_NAME: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
 
@@ -21,19 +22,41 @@ pub fn establish_connection() -> Result<Connection> {
 pub type Result<T = (), E = Error> = std::result::Result<T, E>;
 
 pub mod admin;
+pub mod agent;
 pub mod api;
 pub mod client;
 pub mod config;
+pub mod console;
+pub mod events;
+pub mod graphics;
+pub mod host;
+pub mod ipam;
+pub mod jobs;
 pub mod libvirt;
 pub mod migrate;
 pub mod models;
+pub mod principal;
+pub mod quota;
+pub mod rbac;
+pub mod reconcile;
+pub mod scheduler;
 pub mod scrape;
+pub mod sinks;
+pub mod sshkeys;
 pub mod tailauth;
+pub mod tokens;
+pub mod userdata;
+pub mod vendordata;
 
 pub use config::Config;
 
 pub struct State {
     pub pool: Pool<RusqliteConnectionManager>,
+    pub exec: Arc<dyn host::HostExecutor>,
+    /// The last thing the reconciler found, if it has run yet.
+    pub drift: RwLock<Option<reconcile::Report>>,
+    /// Every audit log write and status change, see [`events`].
+    pub events: events::Bus,
 }
 
 impl fmt::Debug for State {
@@ -43,12 +66,17 @@ impl fmt::Debug for State {
 }
 
 impl State {
-    pub async fn new() -> Result<Self> {
+    pub async fn new(cfg: &Config) -> Result<Self> {
         let mgr = RusqliteConnectionManager::new(
             env::var("DATABASE_URL").unwrap_or("./var/waifud.db".to_string()),
         );
         let pool = bb8::Pool::builder().build(mgr).await?;
-        Ok(State { pool })
+        Ok(State {
+            pool,
+            exec: Arc::new(host::Ssh::new(cfg.ssh.clone())),
+            drift: RwLock::new(None),
+            events: events::Bus::default(),
+        })
     }
 }
 
@@ -111,6 +139,12 @@ pub enum Error {
     #[error("invalid header value: {0}")]
     InvalidHTTPHeader(#[from] InvalidHeaderValue),
 
+    #[error("base64 decoding error: {0}")]
+    Base64(#[from] base64::DecodeError),
+
+    #[error("websocket error: {0}")]
+    WebSocket(#[from] tokio_tungstenite::tungstenite::Error),
+
     // Application errors
     #[error("host {0} doesn't exist")]
     HostDoesntExist(String),
@@ -118,6 +152,83 @@ pub enum Error {
     #[error("instance {0} doesn't exist")]
     InstanceDoesntExist(String),
 
+    #[error("guest agent on {0} failed: {1}")]
+    GuestAgent(String, String),
+
+    #[error("snapshot {1} was made, but the filesystems on {0} may still be frozen: {2}")]
+    CantThaw(String, String, String),
+
+    #[error("can't show the display of instance {0}: {1}")]
+    NoGraphics(uuid::Uuid, String),
+
+    #[error("no host has room for this instance:\n\n{0}")]
+    NoHostFits(String),
+
+    #[error("you need to be {0} to do this, you are {1}")]
+    MissingRole(rbac::Role, rbac::Role),
+
+    #[error("unknown role {0}, use viewer, operator or admin")]
+    UnknownRole(String),
+
+    #[error("instance {0} belongs to {1}")]
+    NotOwner(String, String),
+
+    #[error("{0} is over quota: {1}")]
+    QuotaExceeded(String, String),
+
+    #[error("unknown placement strategy {0}, use spread or pack")]
+    UnknownPlacementStrategy(String),
+
+    #[error("pass a disk size or a distro to take the disk size from")]
+    UnknownDiskSize,
+
+    #[error("unknown syslog transport {0}, use udp, tcp or unix")]
+    UnknownSyslogTransport(String),
+
+    #[error("subnet {0} is misconfigured: {1}")]
+    InvalidSubnet(String, String),
+
+    #[error("invalid ssh key: {0}")]
+    InvalidSshKey(String),
+
+    #[error("you already have ssh key {0}, its ID is {1}")]
+    SshKeyExists(String, uuid::Uuid),
+
+    #[error("can't render user-data: {0}")]
+    Template(String),
+
+    #[error("subnet {0} has no free addresses")]
+    SubnetFull(String),
+
+    #[error("instance {0} has an address in subnet {1}, which host {2} isn't in")]
+    SubnetNotOnHost(String, String, String),
+
+    #[error("can't check free space on {0}:\n\n{1}")]
+    CantCheckFreeSpace(String, String),
+
+    #[error("instance can't go from {0} to {1}")]
+    IllegalTransition(models::InstanceStatus, models::InstanceStatus),
+
+    #[error("instance is {0}, try again when it's done")]
+    InstanceBusy(models::InstanceStatus),
+
+    #[error("job {0} is {1}, only failed jobs can be retried")]
+    JobNotFailed(i64, String),
+
+    #[error("unknown instance status {0}")]
+    UnknownInstanceStatus(String),
+
+    #[error("unknown boot stage {0}")]
+    UnknownBootStage(String),
+
+    #[error(
+        "unknown distro family {0}, use ubuntu, nixos, arch, amazon-linux, rocky-linux or generic"
+    )]
+    UnknownDistroFamily(String),
+
+    #[error("instance {0} is {1}: {2}")]
+    InstanceFailed(String, models::InstanceStatus, String),
+
     #[error("can't download {0}:\n\n{1}")]
     CantDownloadImage(String, String),
 
@@ -130,12 +241,54 @@ pub enum Error {
     #[error("can't rollback zfs zvol to snapshot {1} on {0}:\n\n{2}")]
     CantRollbackZvol(String, String, String),
 
+    #[error("zfs zvol {1} already exists on {0}")]
+    ZvolAlreadyExists(String, String),
+
+    #[error("can't send zfs zvol from {0} to {1}:\n\n{2}")]
+    CantSendZvol(String, String, String),
+
+    #[error("instance is already on host {0}")]
+    AlreadyOnHost(String),
+
     #[error("can't hydrate zfs zvol on {0}:\n\n{1}")]
     CantHydrateZvol(String, String),
 
     #[error("can't create zfs init snapshot on {0}:\n\n{1}")]
     CantMakeInitSnapshot(String, String),
 
+    #[error("can't resize zfs zvol on {0}:\n\n{1}")]
+    CantResizeZvol(String, String),
+
+    #[error("can't shrink disk from {0} GB to {1} GB, disks can only grow")]
+    CantShrinkDisk(i32, i32),
+
+    #[error("{0} must be greater than zero")]
+    InvalidResize(String),
+
+    #[error("can't list zfs snapshots on {0}:\n\n{1}")]
+    CantListSnapshots(String, String),
+
+    #[error("can't create zfs snapshot {1} on {0}:\n\n{2}")]
+    CantMakeSnapshot(String, String, String),
+
+    #[error("can't delete zfs snapshot {1} on {0}:\n\n{2}")]
+    CantDeleteSnapshot(String, String, String),
+
+    #[error("snapshot {0} doesn't exist")]
+    SnapshotDoesntExist(String),
+
+    #[error("{0} is not a valid snapshot name, use letters, numbers, '-', '_', '.' and ':'")]
+    InvalidSnapshotName(String),
+
+    #[error("snapshot {0} is used by reinit and can't be deleted")]
+    SnapshotProtected(String),
+
+    #[error("snapshots newer than {0} would be destroyed ({1}), pass destroy_newer=true to roll back anyway")]
+    NewerSnapshots(String, String),
+
+    #[error("{0} can't be destroyed while these instances are cloned from it: {1}")]
+    HasClones(String, String),
+
     #[error("internal middleware logic error")]
     BadMiddlewareStack,
 
@@ -144,6 +297,9 @@ pub enum Error {
 
     #[error("can't make token: {0}")]
     CantMakeToken(String),
+
+    #[error("invalid token: {0}")]
+    InvalidToken(String),
 }
 
 impl<E> From<bb8::RunError<E>> for Error
@@ -169,8 +325,41 @@ impl Into<(StatusCode, String)> for Error {
                 StatusCode::UNAUTHORIZED,
                 "you lack authorization".to_string(),
             ),
+            Error::InvalidToken(_) => (StatusCode::UNAUTHORIZED, format!("{}", self)),
+            Error::CantMakeToken(_) => (StatusCode::BAD_REQUEST, format!("{}", self)),
             Error::Libvirt(why) => (StatusCode::INTERNAL_SERVER_ERROR, why.message().to_string()),
             Error::Dhall(why) => (StatusCode::BAD_REQUEST, format!("{}", why)),
+            Error::InstanceDoesntExist(_) | Error::SnapshotDoesntExist(_) => {
+                (StatusCode::NOT_FOUND, format!("{}", self))
+            }
+            Error::InvalidSnapshotName(_)
+            | Error::SnapshotProtected(_)
+            | Error::CantShrinkDisk(_, _)
+            | Error::InvalidResize(_)
+            | Error::AlreadyOnHost(_)
+            | Error::UnknownPlacementStrategy(_)
+            | Error::UnknownDiskSize
+            | Error::UnknownSyslogTransport(_)
+            | Error::SubnetNotOnHost(_, _, _)
+            | Error::Template(_)
+            | Error::UnknownDistroFamily(_)
+            | Error::InvalidSshKey(_) => (StatusCode::BAD_REQUEST, format!("{}", self)),
+            Error::NoHostFits(_) | Error::SubnetFull(_) => {
+                (StatusCode::SERVICE_UNAVAILABLE, format!("{}", self))
+            }
+            Error::GuestAgent(_, _) | Error::CantThaw(_, _, _) => {
+                (StatusCode::BAD_GATEWAY, format!("{}", self))
+            }
+            Error::QuotaExceeded(_, _) | Error::NotOwner(_, _) | Error::MissingRole(_, _) => {
+                (StatusCode::FORBIDDEN, format!("{}", self))
+            }
+            Error::IllegalTransition(_, _)
+            | Error::InstanceBusy(_)
+            | Error::JobNotFailed(_, _)
+            | Error::NoGraphics(_, _)
+            | Error::NewerSnapshots(_, _)
+            | Error::HasClones(_, _)
+            | Error::SshKeyExists(_, _) => (StatusCode::CONFLICT, format!("{}", self)),
             Error::SQLite(err) => match err {
                 rusqlite::Error::QueryReturnedNoRows => {
                     (StatusCode::NOT_FOUND, "404 not found".into())
@@ -1,19 +1,24 @@
 use mac_address::MacAddress;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct NewInstance {
     pub name: Option<String>,
     pub memory_mb: Option<i32>,
     pub cpus: Option<i32>,
-    pub host: String,
+    /// Leave this out to let the scheduler pick a host.
+    pub host: Option<String>,
     pub disk_size_gb: Option<i32>,
     pub zvol_prefix: Option<String>,
     pub distro: String,
     pub sata: Option<bool>,
     pub user_data: Option<String>,
     pub join_tailnet: bool,
+    /// Available to the user-data template as `vars.<name>`.
+    #[serde(default)]
+    pub vars: BTreeMap<String, String>,
 }
 
 pub fn random_mac() -> String {
@@ -21,3 +26,39 @@ pub fn random_mac() -> String {
     addr[0] = (addr[0] | 2) & 0xfe;
     MacAddress::new(addr).to_string()
 }
+
+/// New sizes for an instance, anything left out stays the same.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct ResizeInstance {
+    pub memory_mb: Option<i32>,
+    pub cpus: Option<i32>,
+    pub disk_size_gb: Option<i32>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct MigrateInstance {
+    /// The host to move the instance to, must be in the config's `hosts`.
+    pub host: String,
+    /// Pause the instance while it moves instead of shutting it down, so it keeps
+    /// its memory.
+    pub live: Option<bool>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct CloneInstance {
+    /// The snapshot of the source instance to clone, defaults to `init`.
+    pub snapshot: Option<String>,
+    /// Name for the new instance, a random one is picked if this is not set.
+    pub name: Option<String>,
+    /// Cloud-init user data for the new instance, defaults to the source's.
+    pub user_data: Option<String>,
+    /// User-data template variables for the new instance, defaults to the source's.
+    pub vars: Option<BTreeMap<String, String>>,
+}
+
+/// Hands an instance over to another user.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct TransferInstance {
+    /// Tailscale login name of the new owner.
+    pub owner: String,
+}
@@ -2,17 +2,24 @@
 extern crate tracing;
 
 use axum::{
-    routing::{delete, get, post},
+    middleware::from_fn,
+    routing::{delete, get, patch, post, put},
     Extension, Router,
 };
 use axum_extra::routing::SpaRouter;
 use std::{net::SocketAddr, sync::Arc};
 use tower::limit::ConcurrencyLimitLayer;
-use tower_http::trace::TraceLayer;
+use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
 use waifud::{
     admin,
-    api::{self, audit, cloudinit, distros, instances},
-    Config, Result, State,
+    api::{
+        self, agent, audit, cloudinit, distros, events, instances, jobs, placement, quotas,
+        reconcile, snapshots, sshkeys, tokens, users,
+    },
+    rbac, Config, Result, State,
 };
 
 #[tokio::main]
@@ -22,10 +29,14 @@ async fn main() -> Result {
     waifud::migrate::run()?;
 
     let cfg: Config = serde_dhall::from_file("./config.dhall").parse()?;
+    let cfg = Arc::new(cfg);
+    let state = Arc::new(State::new(&cfg).await?);
 
     let files = SpaRouter::new("/static", "static");
 
     let middleware = tower::ServiceBuilder::new()
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        .layer(PropagateRequestIdLayer::x_request_id())
         .layer(TraceLayer::new_for_http())
         .layer(ConcurrencyLimitLayer::new(64))
         .layer(Extension(Arc::new(tailscale_client::Client::new(
@@ -33,45 +44,223 @@ async fn main() -> Result {
             cfg.tailscale.api_key.clone(),
             cfg.tailscale.tailnet.clone(),
         )?)))
-        .layer(Extension(Arc::new(State::new().await?)))
-        .layer(Extension(Arc::new(cfg)));
+        .layer(Extension(state.clone()))
+        .layer(Extension(cfg.clone()));
+
+    let viewer = from_fn(rbac::viewer);
+    let operator = from_fn(rbac::operator);
+    let admin = from_fn(rbac::admin);
 
     let admin_panel = Router::new()
-        .route("/", get(admin::home))
-        .route("/api/config", get(admin::config))
-        .route("/test", get(admin::test_handler))
-        .route("/instances", get(admin::instances))
-        .route("/instances/create", get(admin::instance_create))
-        .route("/instances/:id", get(admin::instance))
-        .route("/distros", get(admin::distro_list))
+        .route("/", get(admin::home).route_layer(viewer.clone()))
+        .route("/api/config", get(admin::config).route_layer(admin.clone()))
+        .route(
+            "/test",
+            get(admin::test_handler).route_layer(viewer.clone()),
+        )
+        .route(
+            "/instances",
+            get(admin::instances).route_layer(viewer.clone()),
+        )
+        .route(
+            "/instances/create",
+            get(admin::instance_create).route_layer(viewer.clone()),
+        )
+        .route(
+            "/instances/:id",
+            get(admin::instance).route_layer(viewer.clone()),
+        )
+        .route(
+            "/instances/:id/graphics",
+            get(admin::instance_graphics).route_layer(operator.clone()),
+        )
+        .route(
+            "/distros",
+            get(admin::distro_list).route_layer(viewer.clone()),
+        )
         .layer(middleware.clone());
 
     let cloudinit = Router::new()
         .route("/:id/meta-data", get(cloudinit::meta_data))
+        .route("/:id/network-config", get(cloudinit::network_config))
+        .route("/:id/phone-home", post(cloudinit::phone_home))
         .route("/:id/user-data", get(cloudinit::user_data))
         .route("/:id/vendor-data", get(cloudinit::vendor_data))
         .layer(middleware.clone());
 
     let api = Router::new()
-        .route("/auditlogs", get(audit::list))
-        .route("/auditlogs/instance/:id", get(audit::list_for_instance))
-        .route("/distros", get(distros::list))
-        .route("/distros", post(distros::create))
-        .route("/distros/:name", post(distros::update))
-        .route("/distros/:name", get(distros::get))
-        .route("/distros/:name", delete(distros::delete))
-        .route("/instances", post(instances::create))
-        .route("/instances", get(instances::list))
-        .route("/instances/:id", get(instances::get))
-        .route("/instances/:id/reinit", post(instances::reinit))
-        .route("/instances/:id/hardreboot", post(instances::hard_reboot))
-        .route("/instances/:id/reboot", post(instances::reboot))
-        .route("/instances/:id/start", post(instances::start))
-        .route("/instances/:id/shutdown", post(instances::shutdown))
-        .route("/instances/name/:name", get(instances::get_by_name))
-        .route("/instances/:id", delete(instances::delete))
-        .route("/instances/:id/machine", get(instances::get_machine))
-        .route("/libvirt/machines", get(api::libvirt::get_machines))
+        .route("/auditlogs", get(audit::list).route_layer(viewer.clone()))
+        .route(
+            "/auditlogs/instance/:id",
+            get(audit::list_for_instance).route_layer(viewer.clone()),
+        )
+        .route("/distros", get(distros::list).route_layer(viewer.clone()))
+        .route("/distros", post(distros::create).route_layer(admin.clone()))
+        .route(
+            "/distros/:name",
+            post(distros::update).route_layer(admin.clone()),
+        )
+        .route(
+            "/distros/:name",
+            get(distros::get).route_layer(viewer.clone()),
+        )
+        .route(
+            "/distros/:name",
+            delete(distros::delete).route_layer(admin.clone()),
+        )
+        .route("/events", get(events::stream).route_layer(viewer.clone()))
+        .route(
+            "/instances",
+            post(instances::create).route_layer(operator.clone()),
+        )
+        .route(
+            "/instances",
+            get(instances::list).route_layer(viewer.clone()),
+        )
+        .route(
+            "/instances/:id",
+            get(instances::get).route_layer(viewer.clone()),
+        )
+        .route(
+            "/instances/:id",
+            patch(instances::resize).route_layer(operator.clone()),
+        )
+        .route(
+            "/instances/:id/reinit",
+            post(instances::reinit).route_layer(operator.clone()),
+        )
+        .route(
+            "/instances/:id/hardreboot",
+            post(instances::hard_reboot).route_layer(operator.clone()),
+        )
+        .route(
+            "/instances/:id/reboot",
+            post(instances::reboot).route_layer(operator.clone()),
+        )
+        .route(
+            "/instances/:id/start",
+            post(instances::start).route_layer(operator.clone()),
+        )
+        .route(
+            "/instances/:id/shutdown",
+            post(instances::shutdown).route_layer(operator.clone()),
+        )
+        .route(
+            "/instances/name/:name",
+            get(instances::get_by_name).route_layer(viewer.clone()),
+        )
+        .route(
+            "/instances/:id",
+            delete(instances::delete).route_layer(operator.clone()),
+        )
+        .route(
+            "/instances/:id/agent/exec",
+            post(agent::exec).route_layer(operator.clone()),
+        )
+        .route(
+            "/instances/:id/agent/info",
+            get(agent::os_info).route_layer(viewer.clone()),
+        )
+        .route(
+            "/instances/:id/agent/interfaces",
+            get(agent::interfaces).route_layer(viewer.clone()),
+        )
+        .route(
+            "/instances/:id/agent/password",
+            post(agent::set_password).route_layer(operator.clone()),
+        )
+        .route(
+            "/instances/:id/console",
+            get(instances::console).route_layer(operator.clone()),
+        )
+        .route(
+            "/instances/:id/graphics",
+            get(instances::graphics).route_layer(operator.clone()),
+        )
+        .route(
+            "/instances/:id/machine",
+            get(instances::get_machine).route_layer(viewer.clone()),
+        )
+        .route(
+            "/instances/:id/clone",
+            post(instances::clone).route_layer(operator.clone()),
+        )
+        .route(
+            "/instances/:id/migrate",
+            post(instances::migrate).route_layer(operator.clone()),
+        )
+        .route(
+            "/instances/:id/owner",
+            post(instances::transfer).route_layer(operator.clone()),
+        )
+        .route(
+            "/instances/:id/snapshots",
+            get(snapshots::list).route_layer(viewer.clone()),
+        )
+        .route(
+            "/instances/:id/snapshots",
+            post(snapshots::create).route_layer(operator.clone()),
+        )
+        .route(
+            "/instances/:id/snapshots/:name",
+            delete(snapshots::delete).route_layer(operator.clone()),
+        )
+        .route(
+            "/instances/:id/snapshots/:name/rollback",
+            post(snapshots::rollback).route_layer(operator.clone()),
+        )
+        .route("/jobs", get(jobs::list).route_layer(viewer.clone()))
+        .route("/jobs/:id", get(jobs::get).route_layer(viewer.clone()))
+        .route(
+            "/jobs/:id/retry",
+            post(jobs::retry).route_layer(operator.clone()),
+        )
+        .route(
+            "/libvirt/machines",
+            get(api::libvirt::get_machines).route_layer(viewer.clone()),
+        )
+        .route(
+            "/placement",
+            get(placement::dry_run).route_layer(viewer.clone()),
+        )
+        .route("/quota", get(quotas::me).route_layer(viewer.clone()))
+        .route("/quotas", get(quotas::list).route_layer(admin.clone()))
+        .route(
+            "/quotas/:login_name",
+            get(quotas::get).route_layer(admin.clone()),
+        )
+        .route(
+            "/quotas/:login_name",
+            put(quotas::set).route_layer(admin.clone()),
+        )
+        .route(
+            "/quotas/:login_name",
+            delete(quotas::clear).route_layer(admin.clone()),
+        )
+        .route(
+            "/reconcile",
+            get(reconcile::get).route_layer(viewer.clone()),
+        )
+        .route(
+            "/reconcile",
+            post(reconcile::run).route_layer(operator.clone()),
+        )
+        .route("/sshkeys", get(sshkeys::list).route_layer(viewer.clone()))
+        .route(
+            "/sshkeys",
+            post(sshkeys::create).route_layer(viewer.clone()),
+        )
+        .route(
+            "/sshkeys/:id",
+            delete(sshkeys::delete).route_layer(viewer.clone()),
+        )
+        .route("/tokens", get(tokens::list).route_layer(viewer.clone()))
+        .route("/tokens", post(tokens::create).route_layer(viewer.clone()))
+        .route(
+            "/tokens/:id",
+            delete(tokens::delete).route_layer(viewer.clone()),
+        )
+        .route("/whoami", get(users::whoami).route_layer(viewer.clone()))
         .layer(middleware.clone());
 
     let app = Router::new()
@@ -81,6 +270,12 @@ async fn main() -> Result {
         .merge(files);
 
     // tokio::spawn(waifud::scrape::cron());
+    tokio::spawn(waifud::sinks::run(
+        cfg.audit.clone(),
+        state.events.subscribe(),
+    ));
+    tokio::spawn(waifud::jobs::run(cfg.clone(), state.clone()));
+    tokio::spawn(waifud::reconcile::run(cfg.clone(), state.clone()));
 
     let addr = &"[::]:23818".parse()?;
     info!("listening on {}", addr);
@@ -0,0 +1,3 @@
+ALTER TABLE audit_logs ADD COLUMN node TEXT;
+ALTER TABLE audit_logs ADD COLUMN source_ip TEXT;
+ALTER TABLE audit_logs ADD COLUMN request_id TEXT;
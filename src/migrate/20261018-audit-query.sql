@@ -0,0 +1,10 @@
+ALTER TABLE audit_logs ADD COLUMN actor TEXT;
+
+CREATE INDEX IF NOT EXISTS audit_logs_actor
+  ON audit_logs(actor);
+
+CREATE INDEX IF NOT EXISTS audit_logs_kind_op
+  ON audit_logs(kind, op);
+
+CREATE INDEX IF NOT EXISTS audit_logs_ts
+  ON audit_logs(ts);
@@ -0,0 +1,3 @@
+ALTER TABLE instances ADD COLUMN boot_stage TEXT;
+ALTER TABLE instances ADD COLUMN boot_stage_at INTEGER;
+ALTER TABLE instances ADD COLUMN boot_started_at INTEGER;
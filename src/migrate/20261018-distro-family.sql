@@ -0,0 +1,7 @@
+ALTER TABLE distros ADD COLUMN family TEXT NOT NULL DEFAULT 'generic';
+
+UPDATE distros SET family = 'ubuntu' WHERE name LIKE 'ubuntu-%';
+UPDATE distros SET family = 'nixos' WHERE name LIKE 'nixos-%';
+UPDATE distros SET family = 'arch' WHERE name = 'arch';
+UPDATE distros SET family = 'amazon-linux' WHERE name LIKE 'amazon-linux-%';
+UPDATE distros SET family = 'rocky-linux' WHERE name LIKE 'rocky-linux-%';
@@ -0,0 +1 @@
+ALTER TABLE instances ADD COLUMN cpus INTEGER NOT NULL DEFAULT 2;
@@ -0,0 +1,4 @@
+ALTER TABLE instances ADD COLUMN owner TEXT;
+
+CREATE INDEX IF NOT EXISTS instances_owner
+  ON instances(owner);
@@ -0,0 +1 @@
+ALTER TABLE instances ADD COLUMN status_reason TEXT;
@@ -0,0 +1,6 @@
+ALTER TABLE instances ADD COLUMN ip_address TEXT;
+ALTER TABLE instances ADD COLUMN subnet TEXT;
+
+CREATE UNIQUE INDEX IF NOT EXISTS instances_ip_address
+  ON instances(ip_address)
+  WHERE ip_address IS NOT NULL;
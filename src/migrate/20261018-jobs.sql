@@ -0,0 +1,31 @@
+CREATE TABLE IF NOT EXISTS jobs
+  ( id INTEGER PRIMARY KEY AUTOINCREMENT
+  , uuid TEXT NOT NULL
+  , kind TEXT NOT NULL
+  , step TEXT NOT NULL
+  , state TEXT NOT NULL DEFAULT 'pending'
+  , attempts INTEGER NOT NULL DEFAULT 0
+  , last_error TEXT
+  , data TEXT NOT NULL
+  , run_after INTEGER NOT NULL DEFAULT (STRFTIME('%s', 'now'))
+  , created_at INTEGER NOT NULL DEFAULT (STRFTIME('%s', 'now'))
+  , updated_at INTEGER NOT NULL DEFAULT (STRFTIME('%s', 'now'))
+  );
+
+CREATE INDEX IF NOT EXISTS jobs_state
+  ON jobs(state, run_after);
+
+CREATE INDEX IF NOT EXISTS jobs_uuid
+  ON jobs(uuid);
+
+CREATE TABLE IF NOT EXISTS job_steps
+  ( id INTEGER PRIMARY KEY AUTOINCREMENT
+  , job_id INTEGER NOT NULL REFERENCES jobs(id) ON DELETE CASCADE
+  , ts INTEGER NOT NULL DEFAULT (STRFTIME('%s', 'now'))
+  , step TEXT NOT NULL
+  , attempt INTEGER NOT NULL
+  , error TEXT
+  );
+
+CREATE INDEX IF NOT EXISTS job_steps_job_id
+  ON job_steps(job_id);
@@ -0,0 +1,19 @@
+-- statuses used to be free-form text and defaulted to 'unknown', anything that
+-- isn't a status waifud knows is marked off until reconciliation finds out more
+UPDATE instances
+   SET status_reason = 'status was ' || status || ' before statuses were checked'
+     , status = 'off'
+ WHERE status NOT IN
+   ( 'init'
+   , 'downloading image'
+   , 'hydrating zvol'
+   , 'waiting for cloud-init'
+   , 'starting'
+   , 'running'
+   , 'rebooting'
+   , 'off'
+   , 'reinit'
+   , 'migrating'
+   , 'provisioning failed'
+   , 'migration failed'
+   );
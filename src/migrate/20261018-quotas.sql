@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS quota_overrides
+  ( login_name TEXT PRIMARY KEY NOT NULL
+  , max_instances INTEGER
+  , max_memory_mb INTEGER
+  , max_disk_gb INTEGER
+  , max_cpus INTEGER
+  , updated_at INTEGER NOT NULL DEFAULT (STRFTIME('%s', 'now'))
+  );
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS ssh_keys
+  ( id TEXT PRIMARY KEY NOT NULL
+  , owner TEXT NOT NULL
+  , name TEXT NOT NULL
+  , public_key TEXT NOT NULL
+  , fingerprint TEXT NOT NULL
+  , created_at INTEGER NOT NULL DEFAULT (STRFTIME('%s', 'now'))
+  , UNIQUE (owner, fingerprint)
+  );
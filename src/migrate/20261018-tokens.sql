@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS tokens
+  ( id TEXT PRIMARY KEY NOT NULL
+  , owner TEXT NOT NULL
+  , description TEXT NOT NULL DEFAULT ''
+  , token_hash TEXT UNIQUE NOT NULL
+  , scopes TEXT NOT NULL
+  , created_at INTEGER NOT NULL DEFAULT (STRFTIME('%s', 'now'))
+  , expires_at INTEGER
+  , last_used_at INTEGER
+  );
+
+CREATE INDEX IF NOT EXISTS tokens_owner
+  ON tokens(owner);
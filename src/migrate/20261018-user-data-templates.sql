@@ -0,0 +1 @@
+ALTER TABLE cloudconfig_seeds ADD COLUMN vars TEXT NOT NULL DEFAULT '{}';
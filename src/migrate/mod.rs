@@ -11,6 +11,20 @@ pub fn run() -> Result<()> {
         M::up(include_str!("./base_schema.sql")),
         M::up(include_str!("./20220225-session.sql")),
         M::up(include_str!("./20220814-no-session.sql")),
+        M::up(include_str!("./20261018-jobs.sql")),
+        M::up(include_str!("./20261018-instance-cpus.sql")),
+        M::up(include_str!("./20261018-instance-status-reason.sql")),
+        M::up(include_str!("./20261018-quotas.sql")),
+        M::up(include_str!("./20261018-instance-owners.sql")),
+        M::up(include_str!("./20261018-tokens.sql")),
+        M::up(include_str!("./20261018-audit-query.sql")),
+        M::up(include_str!("./20261018-audit-actor.sql")),
+        M::up(include_str!("./20261018-ipam.sql")),
+        M::up(include_str!("./20261018-user-data-templates.sql")),
+        M::up(include_str!("./20261018-ssh-keys.sql")),
+        M::up(include_str!("./20261018-distro-family.sql")),
+        M::up(include_str!("./20261018-boot-stages.sql")),
+        M::up(include_str!("./20261018-legacy-statuses.sql")),
     ]);
     conn.pragma_update(None, "journal_mode", &"WAL").unwrap();
 
@@ -1,10 +1,20 @@
 use bb8::PooledConnection;
 use bb8_rusqlite::RusqliteConnectionManager;
-use rusqlite::params;
+use chrono::Utc;
+use rusqlite::{
+    params,
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef},
+    Connection, OptionalExtension, Row, ToSql,
+};
 use serde::{Deserialize, Serialize};
+use std::{convert::TryFrom, fmt, str::FromStr};
 use uuid::Uuid;
 
-use crate::Result;
+use crate::{
+    events::{Bus, Event},
+    vendordata::Family,
+    Error, Result,
+};
 
 #[derive(Debug, Default, Deserialize, Serialize, Clone)]
 pub struct Instance {
@@ -15,33 +25,71 @@ pub struct Instance {
     pub memory: i32,
     pub disk_size: i32,
     pub zvol_name: String,
-    pub status: String,
+    pub status: InstanceStatus,
+    /// Why the instance is in its current status, usually set when something failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_reason: Option<String>,
     pub distro: String,
     pub join_tailnet: bool,
+    pub cpus: i32,
+    /// Tailscale login name of whoever made the instance, counted against their quota.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    /// The static address assigned by [`crate::ipam`], unset for instances that use
+    /// DHCP.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip_address: Option<String>,
+    /// The subnet `ip_address` came from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subnet: Option<String>,
+    /// How far cloud-init got the last time the instance was set up.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boot_stage: Option<BootStage>,
+    /// When `boot_stage` was last changed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boot_stage_at: Option<i64>,
+    /// When the domain was last started for cloud-init to set it up.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boot_started_at: Option<i64>,
 }
 
 impl Instance {
+    /// The columns [`Instance::from_row`] expects, in order.
+    pub const COLUMNS: &'static str =
+        "uuid, name, host, mac_address, memory, disk_size, zvol_name, status, distro, join_tailnet, cpus, status_reason, owner, ip_address, subnet, boot_stage, boot_stage_at, boot_started_at";
+
+    pub fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Instance {
+            uuid: row.get(0)?,
+            name: row.get(1)?,
+            host: row.get(2)?,
+            mac_address: row.get(3)?,
+            memory: row.get(4)?,
+            disk_size: row.get(5)?,
+            zvol_name: row.get(6)?,
+            status: row.get(7)?,
+            distro: row.get(8)?,
+            join_tailnet: row.get(9)?,
+            cpus: row.get(10)?,
+            status_reason: row.get(11)?,
+            owner: row.get(12)?,
+            ip_address: row.get(13)?,
+            subnet: row.get(14)?,
+            boot_stage: row.get(15)?,
+            boot_stage_at: row.get(16)?,
+            boot_started_at: row.get(17)?,
+        })
+    }
+
     pub fn from_name(
         conn: &PooledConnection<'_, RusqliteConnectionManager>,
         name: String,
     ) -> Result<Self> {
-        let mut stmt = conn.prepare(
-            "SELECT uuid, name, host, mac_address, memory, disk_size, zvol_name, status, distro, join_tailnet FROM instances WHERE name = ?1",
-        )?;
-        let instance = stmt.query_row(params![name], |row| {
-            Ok(Instance {
-                uuid: row.get(0)?,
-                name: row.get(1)?,
-                host: row.get(2)?,
-                mac_address: row.get(3)?,
-                memory: row.get(4)?,
-                disk_size: row.get(5)?,
-                zvol_name: row.get(6)?,
-                status: row.get(7)?,
-                distro: row.get(8)?,
-                join_tailnet: row.get(9)?,
-            })
-        })?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM instances WHERE name = ?1",
+            Instance::COLUMNS
+        ))?;
+        let instance = stmt.query_row(params![name], Instance::from_row)?;
 
         Ok(instance)
     }
@@ -50,26 +98,339 @@ impl Instance {
         conn: &PooledConnection<'_, RusqliteConnectionManager>,
         id: Uuid,
     ) -> Result<Self> {
-        let mut stmt = conn.prepare(
-            "SELECT uuid, name, host, mac_address, memory, disk_size, zvol_name, status, distro, join_tailnet FROM instances WHERE uuid = ?1",
-        )?;
-        let instance = stmt.query_row(params![id], |row| {
-            Ok(Instance {
-                uuid: row.get(0)?,
-                name: row.get(1)?,
-                host: row.get(2)?,
-                mac_address: row.get(3)?,
-                memory: row.get(4)?,
-                disk_size: row.get(5)?,
-                zvol_name: row.get(6)?,
-                status: row.get(7)?,
-                distro: row.get(8)?,
-                join_tailnet: row.get(9)?,
-            })
-        })?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM instances WHERE uuid = ?1",
+            Instance::COLUMNS
+        ))?;
+        let instance = stmt.query_row(params![id], Instance::from_row)?;
 
         Ok(instance)
     }
+
+    pub fn get_owned_by(
+        conn: &PooledConnection<'_, RusqliteConnectionManager>,
+        owner: &str,
+    ) -> Result<Vec<Self>> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM instances WHERE owner = ?1",
+            Instance::COLUMNS
+        ))?;
+
+        let mut result = vec![];
+        for instance in stmt.query_map(params![owner], Instance::from_row)? {
+            result.push(instance?);
+        }
+
+        Ok(result)
+    }
+
+    pub fn get_all(conn: &PooledConnection<'_, RusqliteConnectionManager>) -> Result<Vec<Self>> {
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM instances", Instance::COLUMNS))?;
+
+        let mut result = vec![];
+        for instance in stmt.query_map(params![], Instance::from_row)? {
+            result.push(instance?);
+        }
+
+        Ok(result)
+    }
+
+    /// Moves the instance to a new status and tells everyone on `events`. Fails without
+    /// touching anything if the move isn't a legal transition.
+    pub fn set_status(
+        &mut self,
+        conn: &PooledConnection<'_, RusqliteConnectionManager>,
+        events: &Bus,
+        status: InstanceStatus,
+        reason: Option<String>,
+    ) -> Result {
+        self.status.check_transition(status)?;
+
+        conn.execute(
+            "UPDATE instances SET status = ?1, status_reason = ?2 WHERE uuid = ?3",
+            params![status, reason, self.uuid],
+        )?;
+        self.status = status;
+        self.status_reason = reason;
+        events.publish(Event::status(self));
+
+        Ok(())
+    }
+
+    /// Starts waiting for cloud-init to set the instance up again, forgetting how far
+    /// it got last time.
+    pub fn start_boot(&mut self, conn: &Connection) -> Result {
+        let now = Utc::now().timestamp();
+        conn.execute(
+            "UPDATE instances SET boot_stage = NULL, boot_stage_at = NULL, boot_started_at = ?1 WHERE uuid = ?2",
+            params![now, self.uuid],
+        )?;
+        self.boot_stage = None;
+        self.boot_stage_at = None;
+        self.boot_started_at = Some(now);
+
+        Ok(())
+    }
+
+    /// Records how far cloud-init has got. Stages only move forward, this returns false
+    /// without touching anything if the instance is already at or past `stage`.
+    pub fn set_boot_stage(&mut self, conn: &Connection, stage: BootStage) -> Result<bool> {
+        if self.boot_stage.map(|at| at >= stage).unwrap_or(false) {
+            return Ok(false);
+        }
+
+        let now = Utc::now().timestamp();
+        conn.execute(
+            "UPDATE instances SET boot_stage = ?1, boot_stage_at = ?2 WHERE uuid = ?3",
+            params![stage, now, self.uuid],
+        )?;
+        self.boot_stage = Some(stage);
+        self.boot_stage_at = Some(now);
+
+        Ok(true)
+    }
+
+    /// If cloud-init is expected to phone home.
+    pub fn is_booting(&self) -> bool {
+        matches!(
+            self.status,
+            InstanceStatus::WaitingForCloudInit | InstanceStatus::Reinit
+        ) && self
+            .boot_stage
+            .map(|stage| stage < BootStage::ModulesFinished)
+            .unwrap_or(true)
+    }
+
+    /// Fails unless `login_name` owns the instance. Instances made before owners were
+    /// recorded belong to nobody, so anyone may manage them.
+    pub fn check_owner(&self, login_name: &str) -> Result {
+        match &self.owner {
+            Some(owner) if owner != login_name => {
+                Err(Error::NotOwner(self.name.clone(), owner.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Where an instance is in its lifecycle.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum InstanceStatus {
+    #[default]
+    Init,
+    DownloadingImage,
+    HydratingZvol,
+    WaitingForCloudInit,
+    Starting,
+    Running,
+    Rebooting,
+    Off,
+    Reinit,
+    Migrating,
+    ProvisioningFailed,
+    MigrationFailed,
+}
+
+impl InstanceStatus {
+    /// If a background job owns the instance's zvol and domain right now.
+    pub fn is_busy(&self) -> bool {
+        use InstanceStatus::*;
+        matches!(self, Init | DownloadingImage | HydratingZvol | Migrating)
+    }
+
+    /// If the instance got stuck and needs someone to look at it.
+    pub fn is_failed(&self) -> bool {
+        matches!(
+            self,
+            InstanceStatus::ProvisioningFailed | InstanceStatus::MigrationFailed
+        )
+    }
+
+    /// If an instance in this status can move to `next`.
+    pub fn can_become(&self, next: InstanceStatus) -> bool {
+        use InstanceStatus::*;
+
+        match (self, next) {
+            // jobs set the same status again when they retry a step
+            (from, to) if *from == to => true,
+
+            (
+                Init | DownloadingImage | HydratingZvol | WaitingForCloudInit | Reinit,
+                ProvisioningFailed,
+            ) => true,
+            (Init | ProvisioningFailed, DownloadingImage | HydratingZvol | WaitingForCloudInit) => {
+                true
+            }
+            (DownloadingImage, HydratingZvol) => true,
+            (HydratingZvol, WaitingForCloudInit) => true,
+
+            (WaitingForCloudInit | Starting | Rebooting | Reinit, Running | Off | Rebooting) => {
+                true
+            }
+            (WaitingForCloudInit | Running, Reinit) => true,
+            (Running, Rebooting | Off | Migrating) => true,
+            (Off, Starting | Running | Reinit | Migrating) => true,
+
+            (Migrating | MigrationFailed, Running | Off) => true,
+            (Migrating, MigrationFailed) => true,
+            (MigrationFailed, Migrating) => true,
+
+            _ => false,
+        }
+    }
+
+    /// Like [`InstanceStatus::can_become`], but as an error for the API.
+    pub fn check_transition(&self, next: InstanceStatus) -> Result {
+        if self.can_become(next) {
+            Ok(())
+        } else {
+            Err(Error::IllegalTransition(*self, next))
+        }
+    }
+
+    /// Refuses to touch an instance while a background job owns it.
+    pub fn check_idle(&self) -> Result {
+        if self.is_busy() {
+            Err(Error::InstanceBusy(*self))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl fmt::Display for InstanceStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            InstanceStatus::Init => "init",
+            InstanceStatus::DownloadingImage => "downloading image",
+            InstanceStatus::HydratingZvol => "hydrating zvol",
+            InstanceStatus::WaitingForCloudInit => "waiting for cloud-init",
+            InstanceStatus::Starting => "starting",
+            InstanceStatus::Running => "running",
+            InstanceStatus::Rebooting => "rebooting",
+            InstanceStatus::Off => "off",
+            InstanceStatus::Reinit => "reinit",
+            InstanceStatus::Migrating => "migrating",
+            InstanceStatus::ProvisioningFailed => "provisioning failed",
+            InstanceStatus::MigrationFailed => "migration failed",
+        })
+    }
+}
+
+impl FromStr for InstanceStatus {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "init" => InstanceStatus::Init,
+            "downloading image" => InstanceStatus::DownloadingImage,
+            "hydrating zvol" => InstanceStatus::HydratingZvol,
+            "waiting for cloud-init" => InstanceStatus::WaitingForCloudInit,
+            "starting" => InstanceStatus::Starting,
+            "running" => InstanceStatus::Running,
+            "rebooting" => InstanceStatus::Rebooting,
+            "off" => InstanceStatus::Off,
+            "reinit" => InstanceStatus::Reinit,
+            "migrating" => InstanceStatus::Migrating,
+            "provisioning failed" => InstanceStatus::ProvisioningFailed,
+            "migration failed" => InstanceStatus::MigrationFailed,
+            _ => return Err(Error::UnknownInstanceStatus(s.to_string())),
+        })
+    }
+}
+
+impl From<InstanceStatus> for String {
+    fn from(status: InstanceStatus) -> Self {
+        status.to_string()
+    }
+}
+
+impl TryFrom<String> for InstanceStatus {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl ToSql for InstanceStatus {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.to_string()))
+    }
+}
+
+impl FromSql for InstanceStatus {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|why: Error| FromSqlError::Other(why.to_string().into()))
+    }
+}
+
+/// How far cloud-init has got setting an instance up, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum BootStage {
+    /// The guest fetched its meta-data, so cloud-init has started.
+    SeedFetched,
+    /// cloud-init phoned home after running its final modules.
+    ModulesFinished,
+    /// cloud-init never phoned home.
+    Failed,
+}
+
+impl fmt::Display for BootStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BootStage::SeedFetched => "seed fetched",
+            BootStage::ModulesFinished => "modules finished",
+            BootStage::Failed => "failed",
+        })
+    }
+}
+
+impl FromStr for BootStage {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "seed fetched" => BootStage::SeedFetched,
+            "modules finished" => BootStage::ModulesFinished,
+            "failed" => BootStage::Failed,
+            _ => return Err(Error::UnknownBootStage(s.to_string())),
+        })
+    }
+}
+
+impl From<BootStage> for String {
+    fn from(stage: BootStage) -> Self {
+        stage.to_string()
+    }
+}
+
+impl TryFrom<String> for BootStage {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl ToSql for BootStage {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.to_string()))
+    }
+}
+
+impl FromSql for BootStage {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|why: Error| FromSqlError::Other(why.to_string().into()))
+    }
 }
 
 pub struct CloudconfigSeed {
@@ -87,6 +448,9 @@ pub struct Distro {
     #[serde(rename = "minSize")]
     pub min_size: i32,
     pub format: String,
+    /// Decides what vendor-data instances get.
+    #[serde(default)]
+    pub family: Family,
 }
 
 impl Distro {
@@ -101,6 +465,7 @@ impl Distro {
          , sha256sum
          , min_size
          , format
+         , family
          FROM distros
          WHERE name = ?1",
             params![name],
@@ -111,13 +476,14 @@ impl Distro {
                     sha256sum: row.get(2)?,
                     min_size: row.get(3)?,
                     format: row.get(4)?,
+                    family: row.get(5)?,
                 })
             },
         )?)
     }
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct AuditEvent {
     pub id: i32,
     pub ts: i64,
@@ -126,54 +492,157 @@ pub struct AuditEvent {
     pub data: Option<serde_json::Value>,
     pub uuid: Option<String>,
     pub name: Option<String>,
+    /// Login name of whoever caused the event, `system:<component>` for things waifud
+    /// did on its own.
+    #[serde(default)]
+    pub actor: Option<String>,
+    /// The tailnet node the request came from.
+    #[serde(default)]
+    pub node: Option<String>,
+    #[serde(default)]
+    pub source_ip: Option<String>,
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+/// Which way to walk the audit log.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// Filters for the audit log. Everything left out matches anything.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditQuery {
+    pub kind: Option<String>,
+    pub op: Option<String>,
+    pub name: Option<String>,
+    pub instance: Option<Uuid>,
+    pub actor: Option<String>,
+    /// Only events at or after this unix timestamp.
+    pub since: Option<i64>,
+    /// Only events before this unix timestamp.
+    pub until: Option<i64>,
+    /// Newest first by default.
+    #[serde(default)]
+    pub order: Order,
+    /// The `next` of the previous page.
+    pub cursor: Option<i64>,
+    /// Defaults to [`AuditQuery::DEFAULT_LIMIT`], at most [`AuditQuery::MAX_LIMIT`].
+    pub limit: Option<u32>,
+}
+
+impl AuditQuery {
+    pub const DEFAULT_LIMIT: u32 = 100;
+    pub const MAX_LIMIT: u32 = 1000;
+}
+
+/// One page of the audit log.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditPage {
+    pub events: Vec<AuditEvent>,
+    /// Pass this as `cursor` to get the next page, unset on the last page.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next: Option<i64>,
 }
 
 impl AuditEvent {
-    pub fn get_for_instance(
-        uuid: Uuid,
-        conn: &PooledConnection<'_, RusqliteConnectionManager>,
-    ) -> Result<Vec<AuditEvent>> {
-        let mut stmt =
-            conn.prepare("SELECT id, ts, kind, op, data, uuid, name from audit_logs where uuid=? and kind='instance'")?;
-
-        let vals: Vec<AuditEvent> = stmt
-            .query_map(params![uuid.to_string()], |row| {
-                Ok(AuditEvent {
-                    id: row.get(0)?,
-                    ts: row.get(1)?,
-                    kind: row.get(2)?,
-                    op: row.get(3)?,
-                    data: row.get(4)?,
-                    uuid: row.get(5)?,
-                    name: row.get(6)?,
-                })
-            })?
-            .into_iter()
-            .map(Result::unwrap)
-            .collect();
-        Ok(vals)
+    const COLUMNS: &'static str =
+        "id, ts, kind, op, data, uuid, name, actor, node, source_ip, request_id";
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(AuditEvent {
+            id: row.get(0)?,
+            ts: row.get(1)?,
+            kind: row.get(2)?,
+            op: row.get(3)?,
+            data: row.get(4)?,
+            uuid: row.get(5)?,
+            name: row.get(6)?,
+            actor: row.get(7)?,
+            node: row.get(8)?,
+            source_ip: row.get(9)?,
+            request_id: row.get(10)?,
+        })
     }
 
-    pub fn get_all(
-        conn: &PooledConnection<'_, RusqliteConnectionManager>,
-    ) -> Result<Vec<AuditEvent>> {
-        let mut stmt = conn.prepare("SELECT id, ts, kind, op, data, uuid, name from audit_logs")?;
-        let vals: Vec<AuditEvent> = stmt
-            .query_map(params![], |row| {
-                Ok(AuditEvent {
-                    id: row.get(0)?,
-                    ts: row.get(1)?,
-                    kind: row.get(2)?,
-                    op: row.get(3)?,
-                    data: row.get(4)?,
-                    uuid: row.get(5)?,
-                    name: row.get(6)?,
-                })
-            })?
-            .into_iter()
-            .map(Result::unwrap)
-            .collect();
-        Ok(vals)
+    pub fn from_id(conn: &Connection, id: i64) -> Result<Self> {
+        Ok(conn.query_row(
+            &format!("SELECT {} FROM audit_logs WHERE id = ?1", Self::COLUMNS),
+            params![id],
+            Self::from_row,
+        )?)
+    }
+
+    pub fn query(conn: &Connection, q: &AuditQuery) -> Result<AuditPage> {
+        let (dir, cmp) = match q.order {
+            Order::Asc => ("ASC", ">"),
+            Order::Desc => ("DESC", "<"),
+        };
+        let limit = q
+            .limit
+            .unwrap_or(AuditQuery::DEFAULT_LIMIT)
+            .clamp(1, AuditQuery::MAX_LIMIT);
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {}
+             FROM audit_logs
+             WHERE (?1 IS NULL OR kind = ?1)
+               AND (?2 IS NULL OR op = ?2)
+               AND (?3 IS NULL OR name = ?3)
+               AND (?4 IS NULL OR uuid = ?4)
+               AND (?5 IS NULL OR actor = ?5)
+               AND (?6 IS NULL OR ts >= ?6)
+               AND (?7 IS NULL OR ts < ?7)
+               AND (?8 IS NULL OR id {cmp} ?8)
+             ORDER BY id {dir}
+             LIMIT ?9",
+            Self::COLUMNS
+        ))?;
+
+        let mut events = vec![];
+        for ev in stmt.query_map(
+            params![
+                q.kind,
+                q.op,
+                q.name,
+                q.instance.map(|id| id.to_string()),
+                q.actor,
+                q.since,
+                q.until,
+                q.cursor,
+                limit,
+            ],
+            Self::from_row,
+        )? {
+            events.push(ev?);
+        }
+
+        let next = if events.len() == limit as usize {
+            events.last().map(|ev| ev.id as i64)
+        } else {
+            None
+        };
+
+        Ok(AuditPage { events, next })
+    }
+
+    /// Every instance event for an instance, oldest first.
+    pub fn get_for_instance(uuid: Uuid, conn: &Connection) -> Result<Vec<AuditEvent>> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM audit_logs WHERE uuid = ?1 AND kind = 'instance' ORDER BY id",
+            Self::COLUMNS
+        ))?;
+
+        let mut result = vec![];
+        for ev in stmt.query_map(params![uuid.to_string()], Self::from_row)? {
+            result.push(ev?);
+        }
+
+        Ok(result)
     }
 }
 
@@ -203,3 +672,339 @@ impl Session {
         )?)
     }
 }
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Job {
+    pub id: i64,
+    pub uuid: Uuid,
+    pub kind: String,
+    pub step: String,
+    pub state: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub data: serde_json::Value,
+    pub run_after: i64,
+    pub created_at: i64,
+    pub updated_at: i64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub steps: Vec<JobStep>,
+}
+
+impl Job {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Job {
+            id: row.get(0)?,
+            uuid: row.get(1)?,
+            kind: row.get(2)?,
+            step: row.get(3)?,
+            state: row.get(4)?,
+            attempts: row.get(5)?,
+            last_error: row.get(6)?,
+            data: row.get(7)?,
+            run_after: row.get(8)?,
+            created_at: row.get(9)?,
+            updated_at: row.get(10)?,
+            steps: vec![],
+        })
+    }
+
+    pub fn get(conn: &PooledConnection<'_, RusqliteConnectionManager>, id: i64) -> Result<Self> {
+        let mut job = conn.query_row(
+            "SELECT id, uuid, kind, step, state, attempts, last_error, data, run_after, created_at, updated_at FROM jobs WHERE id = ?1",
+            params![id],
+            Job::from_row,
+        )?;
+
+        let mut stmt = conn.prepare(
+            "SELECT id, ts, step, attempt, error FROM job_steps WHERE job_id = ?1 ORDER BY id ASC",
+        )?;
+        for step in stmt.query_map(params![id], |row| {
+            Ok(JobStep {
+                id: row.get(0)?,
+                ts: row.get(1)?,
+                step: row.get(2)?,
+                attempt: row.get(3)?,
+                error: row.get(4)?,
+            })
+        })? {
+            job.steps.push(step?);
+        }
+
+        Ok(job)
+    }
+
+    pub fn list(
+        conn: &PooledConnection<'_, RusqliteConnectionManager>,
+        state: Option<String>,
+        uuid: Option<Uuid>,
+    ) -> Result<Vec<Self>> {
+        let mut stmt = conn.prepare(
+            "SELECT id, uuid, kind, step, state, attempts, last_error, data, run_after, created_at, updated_at
+             FROM jobs
+             WHERE (?1 IS NULL OR state = ?1)
+               AND (?2 IS NULL OR uuid = ?2)
+             ORDER BY id DESC",
+        )?;
+
+        let mut result = vec![];
+        for job in stmt.query_map(params![state, uuid], Job::from_row)? {
+            result.push(job?);
+        }
+
+        Ok(result)
+    }
+
+    /// Fetches the oldest pending job that is due to run at `now`.
+    pub fn next_pending(
+        conn: &PooledConnection<'_, RusqliteConnectionManager>,
+        now: i64,
+    ) -> Result<Option<Self>> {
+        Ok(conn
+            .query_row(
+                "SELECT id, uuid, kind, step, state, attempts, last_error, data, run_after, created_at, updated_at
+                 FROM jobs
+                 WHERE state = 'pending' AND run_after <= ?1
+                 ORDER BY run_after ASC, id ASC
+                 LIMIT 1",
+                params![now],
+                Job::from_row,
+            )
+            .optional()?)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct JobStep {
+    pub id: i64,
+    pub ts: i64,
+    pub step: String,
+    pub attempt: i32,
+    pub error: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_round_trip() {
+        use InstanceStatus::*;
+
+        for status in [
+            Init,
+            DownloadingImage,
+            HydratingZvol,
+            WaitingForCloudInit,
+            Starting,
+            Running,
+            Rebooting,
+            Off,
+            Reinit,
+            Migrating,
+            ProvisioningFailed,
+            MigrationFailed,
+        ] {
+            assert_eq!(
+                status.to_string().parse::<InstanceStatus>().unwrap(),
+                status
+            );
+            assert_eq!(
+                serde_json::to_string(&status).unwrap(),
+                format!("\"{}\"", status)
+            );
+        }
+        assert!("on fire".parse::<InstanceStatus>().is_err());
+    }
+
+    #[test]
+    fn status_transitions() {
+        use InstanceStatus::*;
+
+        assert!(Init.can_become(DownloadingImage));
+        assert!(HydratingZvol.can_become(WaitingForCloudInit));
+        assert!(WaitingForCloudInit.can_become(Running));
+        assert!(Running.can_become(Migrating));
+        assert!(Migrating.can_become(MigrationFailed));
+        assert!(ProvisioningFailed.can_become(HydratingZvol));
+
+        assert!(!HydratingZvol.can_become(Starting));
+        assert!(!Migrating.can_become(Reinit));
+        assert!(!ProvisioningFailed.can_become(Running));
+        assert!(Reinit.can_become(ProvisioningFailed));
+        assert!(matches!(
+            HydratingZvol.check_transition(Starting),
+            Err(Error::IllegalTransition(HydratingZvol, Starting))
+        ));
+        assert!(HydratingZvol.check_idle().is_err());
+        assert!(Off.check_idle().is_ok());
+    }
+
+    #[test]
+    fn legacy_statuses() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("./migrate/base_schema.sql"))
+            .unwrap();
+        conn.execute_batch(include_str!(
+            "./migrate/20261018-instance-status-reason.sql"
+        ))
+        .unwrap();
+        for (name, status) in [("crobat", None), ("zubat", Some("running"))] {
+            conn.execute(
+                "INSERT INTO instances(uuid, name, host, mac_address, memory, disk_size, zvol_name, distro, status) VALUES (?1, ?2, 'vmhost1', '', 512, 10, '', 'alpine', COALESCE(?3, 'unknown'))",
+                params![Uuid::new_v4(), name, status],
+            )
+            .unwrap();
+        }
+
+        conn.execute_batch(include_str!("./migrate/20261018-legacy-statuses.sql"))
+            .unwrap();
+
+        let status = |name: &str| -> (InstanceStatus, Option<String>) {
+            conn.query_row(
+                "SELECT status, status_reason FROM instances WHERE name = ?1",
+                params![name],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap()
+        };
+        assert_eq!(
+            status("crobat"),
+            (
+                InstanceStatus::Off,
+                Some("status was unknown before statuses were checked".into())
+            )
+        );
+        assert_eq!(status("zubat"), (InstanceStatus::Running, None));
+    }
+
+    #[test]
+    fn boot_stages() {
+        use BootStage::*;
+
+        for stage in [SeedFetched, ModulesFinished, Failed] {
+            assert_eq!(stage.to_string().parse::<BootStage>().unwrap(), stage);
+        }
+        assert!("halfway".parse::<BootStage>().is_err());
+
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("./migrate/base_schema.sql"))
+            .unwrap();
+        conn.execute_batch(include_str!("./migrate/20261018-boot-stages.sql"))
+            .unwrap();
+
+        let mut i = Instance {
+            uuid: Uuid::new_v4(),
+            status: InstanceStatus::WaitingForCloudInit,
+            ..Instance::default()
+        };
+        conn.execute(
+            "INSERT INTO instances(uuid, name, host, mac_address, memory, disk_size, zvol_name, distro) VALUES (?1, 'crobat', 'vmhost1', '', 512, 10, '', 'alpine')",
+            params![i.uuid],
+        )
+        .unwrap();
+
+        i.start_boot(&conn).unwrap();
+        assert!(i.is_booting());
+        assert!(i.set_boot_stage(&conn, SeedFetched).unwrap());
+        assert!(!i.set_boot_stage(&conn, SeedFetched).unwrap());
+        assert!(i.set_boot_stage(&conn, ModulesFinished).unwrap());
+        assert!(!i.is_booting());
+        assert!(!i.set_boot_stage(&conn, SeedFetched).unwrap());
+
+        let stored: Option<BootStage> = conn
+            .query_row(
+                "SELECT boot_stage FROM instances WHERE uuid = ?1",
+                params![i.uuid],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(stored, Some(ModulesFinished));
+
+        i.start_boot(&conn).unwrap();
+        assert_eq!(i.boot_stage, None);
+        assert!(i.is_booting());
+    }
+
+    #[test]
+    fn ownership() {
+        let mut i = Instance {
+            name: "mine".into(),
+            owner: Some("cadey@example.com".into()),
+            ..Instance::default()
+        };
+        assert!(i.check_owner("cadey@example.com").is_ok());
+        assert!(matches!(
+            i.check_owner("mara@example.com"),
+            Err(Error::NotOwner(_, _))
+        ));
+
+        i.owner = None;
+        assert!(i.check_owner("mara@example.com").is_ok());
+    }
+
+    #[test]
+    fn audit_query_pages() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("./migrate/base_schema.sql"))
+            .unwrap();
+        conn.execute_batch(include_str!("./migrate/20261018-audit-query.sql"))
+            .unwrap();
+        conn.execute_batch(include_str!("./migrate/20261018-audit-actor.sql"))
+            .unwrap();
+
+        let id = Uuid::new_v4();
+        for (kind, op, actor) in [
+            ("instance", "create", "cadey@example.com"),
+            ("instance", "start", "cadey@example.com"),
+            ("distro", "update", "mara@example.com"),
+            ("instance", "reboot", "mara@example.com"),
+        ] {
+            conn.execute(
+                "INSERT INTO audit_logs(kind, op, data, actor) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    kind,
+                    op,
+                    serde_json::json!({ "uuid": id.to_string(), "name": "crobat" }).to_string(),
+                    actor
+                ],
+            )
+            .unwrap();
+        }
+
+        let q = AuditQuery {
+            kind: Some("instance".into()),
+            limit: Some(2),
+            ..AuditQuery::default()
+        };
+        let page = AuditEvent::query(&conn, &q).unwrap();
+        let ops: Vec<&str> = page.events.iter().map(|ev| ev.op.as_str()).collect();
+        assert_eq!(ops, vec!["reboot", "start"]);
+
+        let page = AuditEvent::query(
+            &conn,
+            &AuditQuery {
+                cursor: page.next,
+                ..q
+            },
+        )
+        .unwrap();
+        let ops: Vec<&str> = page.events.iter().map(|ev| ev.op.as_str()).collect();
+        assert_eq!(ops, vec!["create"]);
+        assert_eq!(page.next, None);
+
+        let page = AuditEvent::query(
+            &conn,
+            &AuditQuery {
+                actor: Some("mara@example.com".into()),
+                instance: Some(id),
+                order: Order::Asc,
+                ..AuditQuery::default()
+            },
+        )
+        .unwrap();
+        let ops: Vec<&str> = page.events.iter().map(|ev| ev.op.as_str()).collect();
+        assert_eq!(ops, vec!["update", "reboot"]);
+
+        assert_eq!(AuditEvent::get_for_instance(id, &conn).unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,160 @@
+use crate::{
+    models::Instance, rbac::Role, tailauth::Tailauth, tokens::Token, Config, Error, State,
+};
+use async_trait::async_trait;
+use axum::{
+    extract::FromRequestParts,
+    http::{header::AUTHORIZATION, request::Parts},
+    RequestPartsExt,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Whoever is making a request, either a tailnet user or an API token acting as one.
+/// Requests with an `Authorization: Bearer` header must have a valid token, everything
+/// else is asked about with [`Tailauth`].
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub login_name: String,
+    pub role: Role,
+    /// The token the request used, if it didn't come from the tailnet.
+    pub token: Option<Uuid>,
+}
+
+impl Principal {
+    /// Admins can manage every instance, everyone else only their own.
+    pub fn check_owns(&self, i: &Instance) -> Result<(), Error> {
+        if self.role == Role::Admin {
+            return Ok(());
+        }
+
+        i.check_owner(&self.login_name)
+    }
+}
+
+/// Who did something and where they did it from, recorded with every audit log row.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Actor {
+    /// A Tailscale login name, or `system:<component>` for things waifud does on its
+    /// own.
+    pub login_name: String,
+    /// The tailnet node the request came from.
+    pub node: Option<String>,
+    pub source_ip: Option<String>,
+    /// The `X-Request-Id` of the request, to find it in the logs.
+    pub request_id: Option<String>,
+}
+
+impl Actor {
+    /// Attributes events to part of waifud itself, such as the job runner or the
+    /// reconciler.
+    pub fn system(component: &str) -> Self {
+        Actor {
+            login_name: format!("system:{}", component),
+            ..Actor::default()
+        }
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Actor
+where
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(req: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let who = Principal::from_request_parts(req, state).await?;
+
+        // only set when the principal came from the tailnet
+        let node = req
+            .extensions
+            .get::<Tailauth>()
+            .map(|Tailauth(_, peer)| peer.name.trim_end_matches('.').to_string());
+        let source_ip = req
+            .extract::<axum_client_ip::ClientIp>()
+            .await
+            .ok()
+            .map(|ip| ip.0.to_string());
+        let request_id = req
+            .headers
+            .get("x-request-id")
+            .and_then(|id| id.to_str().ok())
+            .map(str::to_string);
+
+        Ok(Actor {
+            login_name: who.login_name,
+            node,
+            source_ip,
+            request_id,
+        })
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Principal
+where
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(req: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        // the role middleware has already worked out who this is
+        if let Some(principal) = req.extensions.get::<Principal>() {
+            return Ok(principal.clone());
+        }
+
+        let principal = match req.headers.get(AUTHORIZATION) {
+            Some(header) => {
+                let secret = header
+                    .to_str()?
+                    .strip_prefix("Bearer ")
+                    .ok_or_else(|| {
+                        Error::InvalidToken("use an Authorization: Bearer header".into())
+                    })?
+                    .trim()
+                    .to_string();
+                let state = req
+                    .extensions
+                    .get::<Arc<State>>()
+                    .cloned()
+                    .ok_or(Error::BadMiddlewareStack)?;
+                let cfg = req
+                    .extensions
+                    .get::<Arc<Config>>()
+                    .cloned()
+                    .ok_or(Error::BadMiddlewareStack)?;
+                let conn = state.pool.get().await?;
+                let token = Token::authenticate(&conn, &secret)?;
+
+                info!(user = token.owner, token = %token.id, "token used");
+
+                // a token can't do more than its owner is allowed to now, even if they
+                // could when it was made
+                Principal {
+                    login_name: token.owner.clone(),
+                    role: token.role().min(cfg.roles.role_of_login(&token.owner)),
+                    token: Some(token.id),
+                }
+            }
+            None => {
+                let cfg = req
+                    .extensions
+                    .get::<Arc<Config>>()
+                    .cloned()
+                    .ok_or(Error::BadMiddlewareStack)?;
+                let Tailauth(user, peer) = Tailauth::from_request_parts(req, state).await?;
+
+                Principal {
+                    role: cfg.roles.role_of(&user, &peer),
+                    login_name: user.login_name,
+                    token: None,
+                }
+            }
+        };
+
+        req.extensions.insert(principal.clone());
+        Ok(principal)
+    }
+}
@@ -0,0 +1,307 @@
+//! Per-user limits on how much instances may add up to.
+//!
+//! A user's quota comes from, in order: an override set with the quota API, their
+//! entry in `quotas.users`, then `quotas.default`. Usage is every instance in the
+//! `instances` table they own, whatever state it is in.
+
+use crate::{config::Quota, Config, Error, Result};
+use bb8::PooledConnection;
+use bb8_rusqlite::RusqliteConnectionManager;
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeSet, fmt};
+
+/// How much of each quota'd resource is in use, or is being asked for.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    pub instances: i64,
+    pub memory_mb: i64,
+    pub disk_gb: i64,
+    pub cpus: i64,
+}
+
+impl Usage {
+    /// What one new instance of this size adds.
+    pub fn instance(memory_mb: i32, disk_gb: i32, cpus: i32) -> Self {
+        Usage {
+            instances: 1,
+            memory_mb: memory_mb as i64,
+            disk_gb: disk_gb as i64,
+            cpus: cpus as i64,
+        }
+    }
+
+    /// Adds up every instance a user owns.
+    pub fn of(
+        conn: &PooledConnection<'_, RusqliteConnectionManager>,
+        login_name: &str,
+    ) -> Result<Self> {
+        Ok(conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(memory), 0), COALESCE(SUM(disk_size), 0), COALESCE(SUM(cpus), 0) FROM instances WHERE owner = ?1",
+            params![login_name],
+            |row| {
+                Ok(Usage {
+                    instances: row.get(0)?,
+                    memory_mb: row.get(1)?,
+                    disk_gb: row.get(2)?,
+                    cpus: row.get(3)?,
+                })
+            },
+        )?)
+    }
+}
+
+/// Where a user's quota came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    /// Set by an admin with the quota API.
+    Override,
+    /// Listed in `quotas.users`.
+    Config,
+    /// Nothing specific, `quotas.default` applies.
+    Default,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Source::Override => "override",
+            Source::Config => "config",
+            Source::Default => "default",
+        })
+    }
+}
+
+/// A user's quota and how much of it they are using.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    pub login_name: String,
+    pub source: Source,
+    pub quota: Quota,
+    pub usage: Usage,
+}
+
+/// Looks up the quota that applies to a user.
+pub fn quota_for(
+    config: &Config,
+    conn: &PooledConnection<'_, RusqliteConnectionManager>,
+    login_name: &str,
+) -> Result<(Quota, Source)> {
+    let over = conn
+        .query_row(
+            "SELECT max_instances, max_memory_mb, max_disk_gb, max_cpus FROM quota_overrides WHERE login_name = ?1",
+            params![login_name],
+            |row| {
+                Ok(Quota {
+                    max_instances: row.get(0)?,
+                    max_memory_mb: row.get(1)?,
+                    max_disk_gb: row.get(2)?,
+                    max_cpus: row.get(3)?,
+                })
+            },
+        )
+        .optional()?;
+    if let Some(quota) = over {
+        return Ok((quota, Source::Override));
+    }
+
+    Ok(
+        match config
+            .quotas
+            .users
+            .iter()
+            .find(|u| u.login_name == login_name)
+        {
+            Some(u) => (u.quota.clone(), Source::Config),
+            None => (config.quotas.default.clone(), Source::Default),
+        },
+    )
+}
+
+pub fn report(
+    config: &Config,
+    conn: &PooledConnection<'_, RusqliteConnectionManager>,
+    login_name: &str,
+) -> Result<Report> {
+    let (quota, source) = quota_for(config, conn, login_name)?;
+
+    Ok(Report {
+        login_name: login_name.to_string(),
+        source,
+        quota,
+        usage: Usage::of(conn, login_name)?,
+    })
+}
+
+/// Reports on everyone who owns an instance, has an override or is listed in the
+/// config.
+pub fn report_all(
+    config: &Config,
+    conn: &PooledConnection<'_, RusqliteConnectionManager>,
+) -> Result<Vec<Report>> {
+    let mut users: BTreeSet<String> = config
+        .quotas
+        .users
+        .iter()
+        .map(|u| u.login_name.clone())
+        .collect();
+
+    let mut stmt = conn.prepare(
+        "SELECT owner FROM instances WHERE owner IS NOT NULL UNION SELECT login_name FROM quota_overrides",
+    )?;
+    for user in stmt.query_map(params![], |row| row.get(0))? {
+        users.insert(user?);
+    }
+
+    users
+        .iter()
+        .map(|user| report(config, conn, user))
+        .collect()
+}
+
+/// Fails if a user asking for `extra` on top of what they already have would go over
+/// their quota.
+pub fn enforce(
+    config: &Config,
+    conn: &PooledConnection<'_, RusqliteConnectionManager>,
+    login_name: &str,
+    extra: Usage,
+) -> Result {
+    let report = report(config, conn, login_name)?;
+    check(&report.quota, &report.usage, &extra)
+        .map_err(|why| Error::QuotaExceeded(login_name.to_string(), why))
+}
+
+/// Checks one request against a quota. Only growing something is checked, so users
+/// over their quota can still shrink or delete instances.
+fn check(quota: &Quota, usage: &Usage, extra: &Usage) -> Result<(), String> {
+    for (what, max, have, want) in [
+        (
+            "instances",
+            quota.max_instances,
+            usage.instances,
+            extra.instances,
+        ),
+        (
+            "MB of memory",
+            quota.max_memory_mb,
+            usage.memory_mb,
+            extra.memory_mb,
+        ),
+        (
+            "GB of disk",
+            quota.max_disk_gb,
+            usage.disk_gb,
+            extra.disk_gb,
+        ),
+        ("vCPUs", quota.max_cpus, usage.cpus, extra.cpus),
+    ] {
+        if let Some(max) = max {
+            if want > 0 && have + want > max {
+                return Err(format!(
+                    "using {have} of {max} {what}, can't add {want} more"
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Replaces the quota for a user until the override is cleared.
+pub fn set_override(
+    conn: &PooledConnection<'_, RusqliteConnectionManager>,
+    login_name: &str,
+    quota: &Quota,
+) -> Result {
+    conn.execute(
+        "INSERT INTO quota_overrides(login_name, max_instances, max_memory_mb, max_disk_gb, max_cpus) VALUES (?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT(login_name) DO UPDATE SET max_instances = ?2, max_memory_mb = ?3, max_disk_gb = ?4, max_cpus = ?5, updated_at = STRFTIME('%s', 'now')",
+        params![
+            login_name,
+            quota.max_instances,
+            quota.max_memory_mb,
+            quota.max_disk_gb,
+            quota.max_cpus,
+        ],
+    )?;
+
+    Ok(())
+}
+
+/// Goes back to the configured quota for a user. Returns if there was an override.
+pub fn clear_override(
+    conn: &PooledConnection<'_, RusqliteConnectionManager>,
+    login_name: &str,
+) -> Result<bool> {
+    Ok(conn.execute(
+        "DELETE FROM quota_overrides WHERE login_name = ?1",
+        params![login_name],
+    )? != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quota() -> Quota {
+        Quota {
+            max_instances: Some(3),
+            max_memory_mb: Some(4096),
+            max_disk_gb: None,
+            max_cpus: Some(8),
+        }
+    }
+
+    #[test]
+    fn fits_under_quota() {
+        let usage = Usage {
+            instances: 2,
+            memory_mb: 2048,
+            disk_gb: 500,
+            cpus: 4,
+        };
+        assert!(check(&quota(), &usage, &Usage::instance(2048, 1000, 4)).is_ok());
+    }
+
+    #[test]
+    fn rejects_going_over() {
+        let usage = Usage {
+            instances: 3,
+            memory_mb: 1024,
+            disk_gb: 30,
+            cpus: 3,
+        };
+        assert_eq!(
+            check(&quota(), &usage, &Usage::instance(512, 10, 1)),
+            Err("using 3 of 3 instances, can't add 1 more".to_string())
+        );
+    }
+
+    #[test]
+    fn shrinking_is_always_allowed() {
+        let usage = Usage {
+            instances: 5,
+            memory_mb: 8192,
+            disk_gb: 30,
+            cpus: 16,
+        };
+        let shrink = Usage {
+            memory_mb: -1024,
+            cpus: -2,
+            ..Usage::default()
+        };
+        assert!(check(&quota(), &usage, &shrink).is_ok());
+    }
+
+    #[test]
+    fn unset_limits_are_unlimited() {
+        assert!(check(
+            &Quota::default(),
+            &Usage::default(),
+            &Usage::instance(i32::MAX, i32::MAX, i32::MAX)
+        )
+        .is_ok());
+    }
+}
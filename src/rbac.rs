@@ -0,0 +1,268 @@
+//! Roles for tailnet users, worked out from who they are and which node they are on.
+//!
+//! A user gets the most powerful role they match in `roles`, matching by login name,
+//! by a tag on the node they connect from, or by a capability of that node. Users
+//! who match nothing get `roles.default`.
+
+use crate::{principal::Principal, Error, Result};
+use axum::{
+    http::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::{convert::TryFrom, fmt, str::FromStr};
+use ts_localapi::{User, WhoisPeer};
+
+/// What a user may do. Each role can do everything the ones before it can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum Role {
+    /// Can look at instances, distros, jobs and audit logs.
+    Viewer,
+    /// Can also make, change and delete instances and their snapshots.
+    Operator,
+    /// Can also manage distros and quotas and see the server config.
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Role::Viewer => "viewer",
+            Role::Operator => "operator",
+            Role::Admin => "admin",
+        })
+    }
+}
+
+impl FromStr for Role {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "viewer" => Ok(Role::Viewer),
+            "operator" => Ok(Role::Operator),
+            "admin" => Ok(Role::Admin),
+            _ => Err(Error::UnknownRole(s.to_string())),
+        }
+    }
+}
+
+impl From<Role> for String {
+    fn from(role: Role) -> Self {
+        role.to_string()
+    }
+}
+
+impl TryFrom<String> for Role {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+/// Who gets a role. Matching any one of these is enough.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct RoleMatch {
+    #[serde(rename = "loginNames", default)]
+    pub login_names: Vec<String>,
+    /// ACL tags on the user's node, such as `tag:ci`.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+impl RoleMatch {
+    fn matches(&self, user: &User, peer: &WhoisPeer) -> bool {
+        self.login_names.contains(&user.login_name)
+            || peer
+                .tags
+                .iter()
+                .flatten()
+                .any(|tag| self.tags.contains(tag))
+            || peer
+                .capabilities
+                .iter()
+                .flatten()
+                .any(|cap| self.capabilities.contains(cap))
+    }
+}
+
+/// The `roles` section of the config.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Roles {
+    /// The role for users that match nothing below.
+    pub default: Role,
+    #[serde(default)]
+    pub admins: RoleMatch,
+    #[serde(default)]
+    pub operators: RoleMatch,
+    #[serde(default)]
+    pub viewers: RoleMatch,
+}
+
+impl Default for Roles {
+    /// Matches the config example: everyone on the tailnet can look, and admins have
+    /// to be listed.
+    fn default() -> Self {
+        Roles {
+            default: Role::Viewer,
+            admins: RoleMatch::default(),
+            operators: RoleMatch::default(),
+            viewers: RoleMatch::default(),
+        }
+    }
+}
+
+impl Roles {
+    /// Works out the role of a user connecting from a node.
+    pub fn role_of(&self, user: &User, peer: &WhoisPeer) -> Role {
+        if self.admins.matches(user, peer) {
+            Role::Admin
+        } else if self.operators.matches(user, peer) {
+            Role::Operator
+        } else if self.viewers.matches(user, peer) {
+            Role::Viewer
+        } else {
+            self.default
+        }
+    }
+
+    /// The role a user has no matter which node they connect from, which is what
+    /// API tokens are limited to. Roles from node tags and capabilities don't count.
+    pub fn role_of_login(&self, login_name: &str) -> Role {
+        let named = |m: &RoleMatch| m.login_names.iter().any(|name| name == login_name);
+
+        if named(&self.admins) {
+            Role::Admin
+        } else if named(&self.operators) {
+            Role::Operator
+        } else if named(&self.viewers) {
+            Role::Viewer
+        } else {
+            self.default
+        }
+    }
+}
+
+// `who` comes from the Principal extractor, which already caps token roles at what
+// their owner has now, so a demoted user's old tokens fail here too.
+async fn require<B>(
+    want: Role,
+    who: Principal,
+    req: Request<B>,
+    next: Next<B>,
+) -> Result<Response> {
+    if who.role < want {
+        debug!(user = who.login_name, role = %who.role, %want, "missing role");
+        return Err(Error::MissingRole(want, who.role));
+    }
+
+    Ok(next.run(req).await.into_response())
+}
+
+/// Middleware for routes anyone with a role can use.
+pub async fn viewer<B>(who: Principal, req: Request<B>, next: Next<B>) -> Result<Response> {
+    require(Role::Viewer, who, req, next).await
+}
+
+/// Middleware for routes that change instances.
+pub async fn operator<B>(who: Principal, req: Request<B>, next: Next<B>) -> Result<Response> {
+    require(Role::Operator, who, req, next).await
+}
+
+/// Middleware for routes that manage waifud itself.
+pub async fn admin<B>(who: Principal, req: Request<B>, next: Next<B>) -> Result<Response> {
+    require(Role::Admin, who, req, next).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(login_name: &str) -> User {
+        User {
+            id: 1,
+            login_name: login_name.into(),
+            display_name: login_name.into(),
+            profile_pic_url: "".into(),
+            roles: vec![],
+        }
+    }
+
+    fn roles() -> Roles {
+        Roles {
+            default: Role::Viewer,
+            admins: RoleMatch {
+                login_names: vec!["cadey@example.com".into()],
+                ..RoleMatch::default()
+            },
+            operators: RoleMatch {
+                tags: vec!["tag:ci".into()],
+                capabilities: vec!["https://tailscale.com/cap/waifud-operator".into()],
+                ..RoleMatch::default()
+            },
+            viewers: RoleMatch::default(),
+        }
+    }
+
+    #[test]
+    fn roles_by_login_tag_and_capability() {
+        let roles = roles();
+        let peer = WhoisPeer::default();
+
+        assert_eq!(
+            roles.role_of(&user("cadey@example.com"), &peer),
+            Role::Admin
+        );
+        assert_eq!(
+            roles.role_of(&user("mara@example.com"), &peer),
+            Role::Viewer
+        );
+
+        let ci = WhoisPeer {
+            tags: Some(vec!["tag:ci".into()]),
+            ..WhoisPeer::default()
+        };
+        assert_eq!(
+            roles.role_of(&user("mara@example.com"), &ci),
+            Role::Operator
+        );
+
+        let capable = WhoisPeer {
+            capabilities: Some(vec!["https://tailscale.com/cap/waifud-operator".into()]),
+            ..WhoisPeer::default()
+        };
+        assert_eq!(
+            roles.role_of(&user("mara@example.com"), &capable),
+            Role::Operator
+        );
+    }
+
+    #[test]
+    fn strongest_role_wins() {
+        let roles = roles();
+        let peer = WhoisPeer {
+            tags: Some(vec!["tag:ci".into()]),
+            ..WhoisPeer::default()
+        };
+        assert_eq!(
+            roles.role_of(&user("cadey@example.com"), &peer),
+            Role::Admin
+        );
+        assert!(Role::Admin > Role::Operator && Role::Operator > Role::Viewer);
+        assert_eq!("operator".parse::<Role>().unwrap(), Role::Operator);
+        assert!("root".parse::<Role>().is_err());
+    }
+
+    #[test]
+    fn login_roles_ignore_nodes() {
+        let roles = roles();
+        assert_eq!(roles.role_of_login("cadey@example.com"), Role::Admin);
+        // mara is only an operator from a tag:ci node
+        assert_eq!(roles.role_of_login("mara@example.com"), Role::Viewer);
+    }
+}
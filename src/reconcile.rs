@@ -0,0 +1,534 @@
+//! Keeping the `instances` table honest about what libvirt and ZFS are doing.
+//!
+//! Every so often each host is asked for its domains and zvols. Instance statuses
+//! that disagree with the real domain state are corrected (a guest that powered
+//! itself off is "off", not "running"), everything else that doesn't line up is
+//! only reported so a human can decide what to do about it. Instances whose cloud-init
+//! never phoned home within `cloudInitTimeout` are marked as failed.
+
+use crate::{
+    models::{BootStage, Instance, InstanceStatus, Job},
+    principal::Actor,
+    Config, Result, State,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{task::spawn_blocking, time::sleep};
+use uuid::Uuid;
+use virt::connect::Connect;
+
+/// What the reconciler found on every host the last time it ran.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    pub generated_at: i64,
+    pub hosts: Vec<HostReport>,
+}
+
+impl Report {
+    /// If anything at all didn't line up.
+    pub fn has_drift(&self) -> bool {
+        self.hosts.iter().any(HostReport::has_drift)
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HostReport {
+    pub host: String,
+    /// Set when the host couldn't be checked at all.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Instances whose status was changed to match their domain.
+    pub corrected: Vec<Correction>,
+    /// Domains on the host that no instance knows about.
+    pub orphaned_domains: Vec<OrphanedDomain>,
+    /// Zvols on the host that no instance knows about.
+    pub orphaned_zvols: Vec<String>,
+    /// Instances on the host that have no domain.
+    pub missing_domains: Vec<MissingDomain>,
+}
+
+impl HostReport {
+    pub fn has_drift(&self) -> bool {
+        self.error.is_some()
+            || !self.corrected.is_empty()
+            || !self.orphaned_domains.is_empty()
+            || !self.orphaned_zvols.is_empty()
+            || !self.missing_domains.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Correction {
+    pub uuid: Uuid,
+    pub name: String,
+    pub from: InstanceStatus,
+    pub to: InstanceStatus,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OrphanedDomain {
+    pub uuid: String,
+    pub name: String,
+    pub active: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MissingDomain {
+    pub uuid: Uuid,
+    pub name: String,
+}
+
+/// A domain as libvirt sees it, only the parts the reconciler cares about.
+#[derive(Debug, Clone, PartialEq)]
+struct DomainState {
+    uuid: String,
+    name: String,
+    active: bool,
+}
+
+/// Runs the reconciler forever, every `reconcileInterval` seconds.
+#[instrument(skip_all)]
+pub async fn run(config: Arc<Config>, state: Arc<State>) {
+    loop {
+        match reconcile(&config, &state).await {
+            Ok(report) if report.has_drift() => info!("instances have drifted from their hosts"),
+            Ok(_) => debug!("no drift"),
+            Err(why) => error!("can't reconcile instances: {why}"),
+        }
+
+        sleep(Duration::from_secs(config.reconcile_interval)).await;
+    }
+}
+
+/// Checks every host once and stores the result in [`State::drift`].
+pub async fn reconcile(config: &Config, state: &State) -> Result<Report> {
+    let mut report = Report {
+        generated_at: Utc::now().timestamp(),
+        hosts: vec![],
+    };
+
+    for host in &config.hosts {
+        let host_report = match reconcile_host(config, state, host).await {
+            Ok(host_report) => host_report,
+            Err(why) => HostReport {
+                host: host.clone(),
+                error: Some(why.to_string()),
+                ..HostReport::default()
+            },
+        };
+        report.hosts.push(host_report);
+    }
+
+    *state.drift.write().await = Some(report.clone());
+    Ok(report)
+}
+
+/// How long after its domain is started an instance waiting for cloud-init is left
+/// alone, libvirt may not report the domain as running straight away.
+const BOOT_GRACE_SECS: i64 = 120;
+
+/// Instances whose domain may be in the middle of changing: ones with a job queued or
+/// running, and ones that only just started booting.
+fn settling(instances: &[Instance], jobs: &HashSet<Uuid>, now: i64) -> HashSet<Uuid> {
+    instances
+        .iter()
+        .filter(|i| {
+            jobs.contains(&i.uuid)
+                || (i.is_booting()
+                    && i.boot_started_at
+                        .map(|started| now - started < BOOT_GRACE_SECS)
+                        .unwrap_or(false))
+        })
+        .map(|i| i.uuid)
+        .collect()
+}
+
+#[instrument(skip(config, state), err)]
+async fn reconcile_host(config: &Config, state: &State, host: &str) -> Result<HostReport> {
+    // jobs are looked at before the domains so one that finishes in between (such as
+    // provisioning starting a domain) still counts
+    let jobs: HashSet<Uuid> = {
+        let conn = state.pool.get().await?;
+        let mut jobs = Job::list(&conn, Some("pending".into()), None)?;
+        jobs.extend(Job::list(&conn, Some("running".into()), None)?);
+        jobs.into_iter().map(|job| job.uuid).collect()
+    };
+
+    let domains = {
+        let uri = state.exec.libvirt_uri(host);
+        let domains: Result<Vec<DomainState>> = spawn_blocking(move || {
+            let mut conn = Connect::open(&uri)?;
+            let mut result = vec![];
+
+            for dom in conn.list_all_domains(0)? {
+                result.push(DomainState {
+                    uuid: dom.get_uuid_string()?,
+                    name: dom.get_name()?,
+                    active: dom.is_active()?,
+                });
+            }
+            conn.close()?;
+
+            Ok(result)
+        })
+        .await?;
+        domains?
+    };
+
+    let conn = state.pool.get().await?;
+    let instances = Instance::get_all(&conn)?;
+
+    let mut prefixes: BTreeSet<String> = instances
+        .iter()
+        .filter(|i| i.host == host)
+        .filter_map(|i| i.zvol_name.rsplit_once('/').map(|(p, _)| p.to_string()))
+        .collect();
+    prefixes.insert(config.rpool_base.clone());
+
+    let mut zvols = vec![];
+    for prefix in prefixes {
+        let output = state
+            .exec
+            .run(
+                host,
+                &[
+                    "zfs", "list", "-H", "-o", "name", "-t", "volume", "-d", "1", &prefix,
+                ],
+            )
+            .await?;
+        if !output.success {
+            debug!(%prefix, "can't list zvols: {}", output.stderr.trim());
+            continue;
+        }
+        zvols.extend(output.stdout.lines().map(|l| l.to_string()));
+    }
+
+    let settling = settling(&instances, &jobs, Utc::now().timestamp());
+    let (mut report, corrections) = compare(host, &instances, &settling, &domains, &zvols);
+
+    for (uuid, to) in corrections {
+        let mut ins = Instance::from_uuid(&conn, uuid)?;
+        let from = ins.status;
+        // the instance may have moved on since the domains were listed
+        if ins.status.check_transition(to).is_err() {
+            continue;
+        }
+
+        ins.set_status(
+            &conn,
+            &state.events,
+            to,
+            Some("corrected by the reconciler".into()),
+        )?;
+        state.events.audit(
+            &conn,
+            &Actor::system("reconciler"),
+            "instance",
+            "reconcile",
+            &ins,
+        )?;
+        report.corrected.push(Correction {
+            uuid,
+            name: ins.name,
+            from,
+            to,
+        });
+    }
+
+    let now = Utc::now().timestamp();
+    for uuid in timed_out(host, &instances, now, config.cloud_init_timeout) {
+        let mut ins = Instance::from_uuid(&conn, uuid)?;
+        let from = ins.status;
+        // it may have phoned home or been turned off since
+        if !ins.is_booting() {
+            continue;
+        }
+
+        let reason = format!(
+            "cloud-init didn't phone home within {} seconds, last stage: {}",
+            config.cloud_init_timeout,
+            ins.boot_stage
+                .map(|stage| stage.to_string())
+                .unwrap_or_else(|| "not started".into())
+        );
+        ins.set_boot_stage(&conn, BootStage::Failed)?;
+        ins.set_status(
+            &conn,
+            &state.events,
+            InstanceStatus::ProvisioningFailed,
+            Some(reason),
+        )?;
+        state.events.audit(
+            &conn,
+            &Actor::system("reconciler"),
+            "instance",
+            "boot timeout",
+            &ins,
+        )?;
+        report.corrected.push(Correction {
+            uuid,
+            name: ins.name,
+            from,
+            to: InstanceStatus::ProvisioningFailed,
+        });
+    }
+
+    Ok(report)
+}
+
+/// Instances on `host` that have waited longer than `timeout` seconds for cloud-init
+/// to phone home. Instances set up before boots were tracked never time out.
+fn timed_out(host: &str, instances: &[Instance], now: i64, timeout: u64) -> Vec<Uuid> {
+    instances
+        .iter()
+        .filter(|i| i.host == host && i.is_booting())
+        .filter(|i| {
+            i.boot_started_at
+                .map(|started| now - started > timeout as i64)
+                .unwrap_or(false)
+        })
+        .map(|i| i.uuid)
+        .collect()
+}
+
+/// Works out what's wrong on one host. Instances a job is busy with (or that are in
+/// `settling`) are left alone, their domains and zvols come and go as the job runs.
+fn compare(
+    host: &str,
+    instances: &[Instance],
+    settling: &HashSet<Uuid>,
+    domains: &[DomainState],
+    zvols: &[String],
+) -> (HostReport, Vec<(Uuid, InstanceStatus)>) {
+    let mut report = HostReport {
+        host: host.to_string(),
+        ..HostReport::default()
+    };
+    let mut corrections = vec![];
+
+    let busy: HashSet<String> = instances
+        .iter()
+        .filter(|i| i.status.is_busy() || settling.contains(&i.uuid))
+        .map(|i| i.uuid.to_string())
+        .collect();
+    let here: Vec<&Instance> = instances.iter().filter(|i| i.host == host).collect();
+
+    for ins in &here {
+        if busy.contains(&ins.uuid.to_string()) || ins.status.is_failed() {
+            continue;
+        }
+
+        match domains.iter().find(|d| d.uuid == ins.uuid.to_string()) {
+            None => report.missing_domains.push(MissingDomain {
+                uuid: ins.uuid,
+                name: ins.name.clone(),
+            }),
+            Some(dom) => {
+                use InstanceStatus::*;
+
+                let to = match (dom.active, ins.status) {
+                    (false, Running | Starting | Rebooting | Reinit | WaitingForCloudInit) => {
+                        Some(Off)
+                    }
+                    (true, Off) => Some(Running),
+                    _ => None,
+                };
+                if let Some(to) = to {
+                    corrections.push((ins.uuid, to));
+                }
+            }
+        }
+    }
+
+    for dom in domains {
+        if busy.contains(&dom.uuid) || here.iter().any(|i| i.uuid.to_string() == dom.uuid) {
+            continue;
+        }
+
+        report.orphaned_domains.push(OrphanedDomain {
+            uuid: dom.uuid.clone(),
+            name: dom.name.clone(),
+            active: dom.active,
+        });
+    }
+
+    for zvol in zvols {
+        let known = here.iter().any(|i| &i.zvol_name == zvol)
+            || instances
+                .iter()
+                .any(|i| busy.contains(&i.uuid.to_string()) && &i.zvol_name == zvol);
+        if !known {
+            report.orphaned_zvols.push(zvol.clone());
+        }
+    }
+
+    (report, corrections)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instance(name: &str, host: &str, status: InstanceStatus) -> Instance {
+        Instance {
+            uuid: Uuid::new_v4(),
+            name: name.into(),
+            host: host.into(),
+            zvol_name: format!("rpool/local/vms/{}", name),
+            status,
+            ..Instance::default()
+        }
+    }
+
+    fn domain(ins: &Instance, active: bool) -> DomainState {
+        DomainState {
+            uuid: ins.uuid.to_string(),
+            name: ins.name.clone(),
+            active,
+        }
+    }
+
+    #[test]
+    fn corrects_status_from_domain_state() {
+        let stopped = instance("stopped", "vmhost1", InstanceStatus::Running);
+        let started = instance("started", "vmhost1", InstanceStatus::Off);
+        let fine = instance("fine", "vmhost1", InstanceStatus::Running);
+        let instances = vec![stopped.clone(), started.clone(), fine.clone()];
+        let domains = vec![
+            domain(&stopped, false),
+            domain(&started, true),
+            domain(&fine, true),
+        ];
+
+        let (report, corrections) = compare("vmhost1", &instances, &HashSet::new(), &domains, &[]);
+        assert!(!report.has_drift());
+        assert_eq!(
+            corrections,
+            vec![
+                (stopped.uuid, InstanceStatus::Off),
+                (started.uuid, InstanceStatus::Running)
+            ]
+        );
+    }
+
+    #[test]
+    fn flags_orphans_and_missing_domains() {
+        let gone = instance("gone", "vmhost1", InstanceStatus::Running);
+        let elsewhere = instance("elsewhere", "vmhost2", InstanceStatus::Running);
+        let stray = DomainState {
+            uuid: Uuid::new_v4().to_string(),
+            name: "handmade".into(),
+            active: true,
+        };
+        let zvols = vec![
+            "rpool/local/vms/gone".to_string(),
+            "rpool/local/vms/leftover".to_string(),
+        ];
+
+        let (report, corrections) = compare(
+            "vmhost1",
+            &[gone.clone(), elsewhere.clone()],
+            &HashSet::new(),
+            &[stray.clone(), domain(&elsewhere, false)],
+            &zvols,
+        );
+        assert!(corrections.is_empty());
+        assert_eq!(report.missing_domains[0].uuid, gone.uuid);
+        assert_eq!(
+            report
+                .orphaned_domains
+                .iter()
+                .map(|d| d.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["handmade", "elsewhere"]
+        );
+        assert_eq!(report.orphaned_zvols, vec!["rpool/local/vms/leftover"]);
+    }
+
+    #[test]
+    fn leaves_busy_instances_alone() {
+        let provisioning = instance("new", "vmhost1", InstanceStatus::HydratingZvol);
+        let moving = instance("moving", "vmhost2", InstanceStatus::Migrating);
+        let zvols = vec![provisioning.zvol_name.clone(), moving.zvol_name.clone()];
+
+        let (report, corrections) = compare(
+            "vmhost1",
+            &[provisioning, moving.clone()],
+            &HashSet::new(),
+            &[domain(&moving, true)],
+            &zvols,
+        );
+        assert!(corrections.is_empty());
+        assert!(!report.has_drift());
+    }
+
+    #[test]
+    fn leaves_starting_instances_alone() {
+        let now = 10_000;
+        // the provisioning job has set the status but not started the domain yet
+        let queued = instance("queued", "vmhost1", InstanceStatus::WaitingForCloudInit);
+        let just_booted = Instance {
+            boot_started_at: Some(now - 10),
+            ..instance(
+                "just-booted",
+                "vmhost1",
+                InstanceStatus::WaitingForCloudInit,
+            )
+        };
+        let stale = Instance {
+            boot_started_at: Some(now - 3600),
+            ..instance("stale", "vmhost1", InstanceStatus::WaitingForCloudInit)
+        };
+        let instances = vec![queued.clone(), just_booted.clone(), stale.clone()];
+        let domains: Vec<_> = instances.iter().map(|i| domain(i, false)).collect();
+
+        let jobs = HashSet::from([queued.uuid]);
+        let settling = settling(&instances, &jobs, now);
+        assert_eq!(settling, HashSet::from([queued.uuid, just_booted.uuid]));
+
+        let (report, corrections) = compare("vmhost1", &instances, &settling, &domains, &[]);
+        assert_eq!(corrections, vec![(stale.uuid, InstanceStatus::Off)]);
+        assert!(report.orphaned_domains.is_empty());
+    }
+
+    #[test]
+    fn times_out_silent_boots() {
+        let now = 10_000;
+        let booting = |name: &str, started: Option<i64>, stage: Option<BootStage>| Instance {
+            boot_started_at: started,
+            boot_stage: stage,
+            ..instance(name, "vmhost1", InstanceStatus::WaitingForCloudInit)
+        };
+
+        let stuck = booting("stuck", Some(now - 3600), Some(BootStage::SeedFetched));
+        let reinit = Instance {
+            status: InstanceStatus::Reinit,
+            ..booting("reinit", Some(now - 3600), None)
+        };
+        let instances = vec![
+            stuck.clone(),
+            reinit.clone(),
+            booting("fresh", Some(now - 60), None),
+            booting("legacy", None, None),
+            booting("done", Some(now - 3600), Some(BootStage::ModulesFinished)),
+            Instance {
+                host: "vmhost2".into(),
+                ..booting("elsewhere", Some(now - 3600), None)
+            },
+            Instance {
+                boot_started_at: Some(now - 3600),
+                ..instance("running", "vmhost1", InstanceStatus::Running)
+            },
+        ];
+
+        assert_eq!(
+            timed_out("vmhost1", &instances, now, 1800),
+            vec![stuck.uuid, reinit.uuid]
+        );
+    }
+}
@@ -0,0 +1,300 @@
+//! Picking a host for new instances when the user doesn't care which one.
+//!
+//! Every host in `cfg.hosts` is asked how much memory libvirt has free and how much
+//! space is left in `rpoolBase`. Hosts that can't fit the instance are dropped and a
+//! [`Strategy`] ranks the rest.
+
+use crate::{models::Instance, Config, Error, Result, State};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use tokio::task::spawn_blocking;
+use virt::connect::Connect;
+
+/// How much room a host has left.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HostStats {
+    pub host: String,
+    pub free_memory_mb: u64,
+    pub free_disk_gb: u64,
+    pub instances: usize,
+}
+
+/// A host that was considered for an instance, and why it was or wasn't picked.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Candidate {
+    pub host: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<HostStats>,
+    /// Set when the host can't take the instance.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rejected: Option<String>,
+}
+
+/// The outcome of a placement decision. Candidates are listed best first, rejected
+/// hosts last.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Placement {
+    pub strategy: String,
+    pub memory_mb: i32,
+    pub disk_size_gb: i32,
+    pub host: Option<String>,
+    pub candidates: Vec<Candidate>,
+}
+
+impl Placement {
+    /// Lists every host and why it was or wasn't picked, one per line.
+    pub fn explain(&self) -> String {
+        self.candidates
+            .iter()
+            .map(|c| match (&c.rejected, &self.host) {
+                (Some(why), _) => format!("{}: {}", c.host, why),
+                (None, Some(host)) if host == &c.host => format!("{}: picked", c.host),
+                (None, _) => format!("{}: fits, ranked lower by {}", c.host, self.strategy),
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Ranks hosts that have room for an instance. Hosts that sort first are preferred.
+pub trait Strategy: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn rank(&self, a: &HostStats, b: &HostStats) -> Ordering;
+}
+
+/// Puts instances on the emptiest host, so load is spread out evenly.
+pub struct Spread;
+
+impl Strategy for Spread {
+    fn name(&self) -> &'static str {
+        "spread"
+    }
+
+    fn rank(&self, a: &HostStats, b: &HostStats) -> Ordering {
+        b.free_memory_mb
+            .cmp(&a.free_memory_mb)
+            .then(a.instances.cmp(&b.instances))
+            .then(b.free_disk_gb.cmp(&a.free_disk_gb))
+    }
+}
+
+/// Puts instances on the fullest host that still fits them, keeping other hosts free
+/// for big instances.
+pub struct Pack;
+
+impl Strategy for Pack {
+    fn name(&self) -> &'static str {
+        "pack"
+    }
+
+    fn rank(&self, a: &HostStats, b: &HostStats) -> Ordering {
+        a.free_memory_mb
+            .cmp(&b.free_memory_mb)
+            .then(b.instances.cmp(&a.instances))
+            .then(a.free_disk_gb.cmp(&b.free_disk_gb))
+    }
+}
+
+/// Looks up a strategy by name.
+pub fn strategy(name: &str) -> Result<Box<dyn Strategy>> {
+    match name {
+        "spread" => Ok(Box::new(Spread)),
+        "pack" => Ok(Box::new(Pack)),
+        _ => Err(Error::UnknownPlacementStrategy(name.to_string())),
+    }
+}
+
+/// Asks a host how much room it has.
+#[instrument(skip(config, state), err)]
+pub async fn host_stats(config: &Config, state: &State, host: &str) -> Result<HostStats> {
+    let uri = state.exec.libvirt_uri(host);
+    let free_memory: Result<u64> = spawn_blocking(move || {
+        let mut conn = Connect::open(&uri)?;
+        let free = conn.get_free_memory()?;
+        conn.close()?;
+        Ok(free)
+    })
+    .await?;
+
+    let avail = state
+        .exec
+        .run(
+            host,
+            &["zfs", "list", "-H", "-p", "-o", "avail", &config.rpool_base],
+        )
+        .await?
+        .check(|stderr| Error::CantCheckFreeSpace(host.to_string(), stderr))?;
+    let free_disk: u64 = avail.trim().parse().map_err(|_| {
+        Error::CantCheckFreeSpace(host.to_string(), format!("unexpected zfs output {avail:?}"))
+    })?;
+
+    let conn = state.pool.get().await?;
+    let instances = Instance::get_all(&conn)?
+        .into_iter()
+        .filter(|i| i.host == host)
+        .count();
+
+    Ok(HostStats {
+        host: host.to_string(),
+        free_memory_mb: free_memory? / 1024 / 1024,
+        free_disk_gb: free_disk / 1024 / 1024 / 1024,
+        instances,
+    })
+}
+
+/// Checks every configured host and picks one for an instance.
+pub async fn place(
+    config: &Config,
+    state: &State,
+    strategy: &dyn Strategy,
+    memory_mb: i32,
+    disk_size_gb: i32,
+) -> Result<Placement> {
+    let mut stats = vec![];
+    for host in &config.hosts {
+        stats.push((host.clone(), host_stats(config, state, host).await));
+    }
+
+    Ok(decide(stats, strategy, memory_mb, disk_size_gb))
+}
+
+fn decide(
+    stats: Vec<(String, Result<HostStats>)>,
+    strategy: &dyn Strategy,
+    memory_mb: i32,
+    disk_size_gb: i32,
+) -> Placement {
+    let mut fits = vec![];
+    let mut rejected = vec![];
+
+    for (host, stats) in stats {
+        let stats = match stats {
+            Ok(stats) => stats,
+            Err(why) => {
+                rejected.push(Candidate {
+                    host,
+                    stats: None,
+                    rejected: Some(format!("can't check host: {why}")),
+                });
+                continue;
+            }
+        };
+
+        let why = if stats.free_memory_mb < memory_mb as u64 {
+            Some(format!(
+                "needs {} MB of memory, has {} MB free",
+                memory_mb, stats.free_memory_mb
+            ))
+        } else if stats.free_disk_gb < disk_size_gb as u64 {
+            Some(format!(
+                "needs {} GB of disk, has {} GB free",
+                disk_size_gb, stats.free_disk_gb
+            ))
+        } else {
+            None
+        };
+
+        match why {
+            Some(why) => rejected.push(Candidate {
+                host,
+                stats: Some(stats),
+                rejected: Some(why),
+            }),
+            None => fits.push(stats),
+        }
+    }
+
+    // sort_by is stable, so ties go to the host listed first in the config
+    fits.sort_by(|a, b| strategy.rank(a, b));
+
+    let host = fits.first().map(|s| s.host.clone());
+    let mut candidates: Vec<Candidate> = fits
+        .into_iter()
+        .map(|stats| Candidate {
+            host: stats.host.clone(),
+            stats: Some(stats),
+            rejected: None,
+        })
+        .collect();
+    candidates.extend(rejected);
+
+    Placement {
+        strategy: strategy.name().to_string(),
+        memory_mb,
+        disk_size_gb,
+        host,
+        candidates,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(
+        host: &str,
+        free_memory_mb: u64,
+        free_disk_gb: u64,
+        instances: usize,
+    ) -> (String, Result<HostStats>) {
+        (
+            host.to_string(),
+            Ok(HostStats {
+                host: host.to_string(),
+                free_memory_mb,
+                free_disk_gb,
+                instances,
+            }),
+        )
+    }
+
+    fn hosts() -> Vec<(String, Result<HostStats>)> {
+        vec![
+            stats("vmhost1", 4096, 500, 6),
+            stats("vmhost2", 16384, 500, 2),
+            stats("vmhost3", 1024, 500, 1),
+            (
+                "vmhost4".to_string(),
+                Err(Error::HostDoesntExist("vmhost4".into())),
+            ),
+        ]
+    }
+
+    #[test]
+    fn spread_picks_emptiest_host() {
+        let p = decide(hosts(), &Spread, 2048, 10);
+        assert_eq!(p.host.as_deref(), Some("vmhost2"));
+        assert_eq!(
+            p.candidates
+                .iter()
+                .map(|c| c.host.as_str())
+                .collect::<Vec<_>>(),
+            vec!["vmhost2", "vmhost1", "vmhost3", "vmhost4"]
+        );
+        assert!(p.candidates[2].rejected.is_some());
+        assert!(p.candidates[3].rejected.is_some());
+    }
+
+    #[test]
+    fn pack_picks_fullest_host_that_fits() {
+        let p = decide(hosts(), &Pack, 2048, 10);
+        assert_eq!(p.host.as_deref(), Some("vmhost1"));
+    }
+
+    #[test]
+    fn nothing_fits() {
+        let p = decide(hosts(), &Spread, 2048, 1000);
+        assert_eq!(p.host, None);
+        assert!(p.candidates.iter().all(|c| c.rejected.is_some()));
+        assert!(p
+            .explain()
+            .starts_with("vmhost1: needs 1000 GB of disk, has 500 GB free"));
+    }
+
+    #[test]
+    fn strategies_by_name() {
+        assert_eq!(strategy("spread").unwrap().name(), "spread");
+        assert_eq!(strategy("pack").unwrap().name(), "pack");
+        assert!(strategy("random").is_err());
+    }
+}
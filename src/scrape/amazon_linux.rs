@@ -1,4 +1,4 @@
-use crate::{models::Distro, Error};
+use crate::{models::Distro, vendordata::Family, Error};
 use scraper::Html;
 use url::Url;
 
@@ -70,5 +70,6 @@ pub async fn scrape() -> crate::Result<crate::models::Distro> {
         sha256sum: shasum.to_string(),
         min_size: 25,
         format: "waifud://qcow2".to_string(),
+        family: Family::AmazonLinux,
     })
 }
@@ -1,6 +1,6 @@
 use scraper::Html;
 
-use crate::models::Distro;
+use crate::{models::Distro, vendordata::Family};
 
 /// # Scraper for https://geo.mirror.pkgbuild.com/images/
 ///
@@ -76,5 +76,6 @@ pub async fn scrape() -> crate::Result<crate::models::Distro> {
         sha256sum: shasum.to_string(),
         min_size: 2,
         format: "waifud://qcow2".to_string(),
+        family: Family::Arch,
     })
 }
@@ -57,15 +57,23 @@ pub async fn cron() {
                    , sha256sum    = ?2
                    , min_size     = ?3
                    , format       = ?4
+                   , family       = ?6
                  WHERE name = ?5",
-                params![d.download_url, d.sha256sum, d.min_size, d.format, d.name],
+                params![
+                    d.download_url,
+                    d.sha256sum,
+                    d.min_size,
+                    d.format,
+                    d.name,
+                    d.family
+                ],
             ) {
                 error!("can't update distros: {why}");
                 continue 'outer;
             }
 
             if let Err(why) = tx.execute(
-                "INSERT INTO audit_logs(kind, op, data) VALUES (?1, ?2, ?3)",
+                "INSERT INTO audit_logs(kind, op, data, actor) VALUES (?1, ?2, ?3, 'system:scrape')",
                 params!["distro", "update", serde_json::to_string(&d).unwrap()],
             ) {
                 error!("can't update audit logs: {why}");
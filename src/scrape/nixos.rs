@@ -1,4 +1,4 @@
-use crate::models::Distro;
+use crate::{models::Distro, vendordata::Family};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
@@ -25,6 +25,7 @@ pub async fn scrape() -> crate::Result<Vec<Distro>> {
             sha256sum: val.sha256.clone(),
             min_size: 8,
             format: "waifud://qcow2".to_string(),
+            family: Family::Nixos,
         })
     }
 
@@ -1,6 +1,6 @@
 use scraper::{ElementRef, Html};
 
-use crate::models::Distro;
+use crate::{models::Distro, vendordata::Family};
 
 /// # Scraper for Rocky Linux cloud images
 ///
@@ -98,5 +98,6 @@ pub async fn scrape(version: i32) -> crate::Result<crate::models::Distro> {
         sha256sum: shasum.to_string(),
         min_size: 10,
         format: "waifud://qcow2".to_string(),
+        family: Family::RockyLinux,
     })
 }
@@ -1,7 +1,7 @@
 use scraper::{ElementRef, Html};
 use std::collections::HashMap;
 
-use crate::{models::Distro, Error};
+use crate::{models::Distro, vendordata::Family, Error};
 
 /// # Scraper for Ubuntu cloud images
 ///
@@ -98,5 +98,6 @@ pub async fn scrape((version, name): (&str, &str)) -> crate::Result<crate::model
         sha256sum: shasum.to_string(),
         min_size: 5,
         format: "waifud://qcow2".to_string(),
+        family: Family::Ubuntu,
     })
 }
@@ -0,0 +1,302 @@
+//! Forwarding the audit log to places outside waifud, as configured in `audit.sinks`.
+//!
+//! Every sink gets its own task and a bounded queue of events. A sink that can't keep
+//! up has new events dropped (and logged) once its queue is full, nothing ever waits
+//! on a sink. The `audit_logs` table stays the complete record either way.
+
+use crate::{
+    config::{Audit, AuditSink, SyslogSink},
+    events::Event,
+    models::AuditEvent,
+    Error, Result, APPLICATION_NAME,
+};
+use chrono::{SecondsFormat, TimeZone, Utc};
+use std::{fs, net::SocketAddr, process, time::Duration};
+use tokio::{
+    fs::OpenOptions,
+    io::AsyncWriteExt,
+    net::{lookup_host, TcpStream, UdpSocket, UnixDatagram},
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc,
+    },
+    time::sleep,
+};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// RFC 5424 severity for every event.
+const SEVERITY_NOTICE: u32 = 5;
+
+/// Sends the audit events published on `rx` to every configured sink.
+pub async fn run(config: Audit, mut rx: broadcast::Receiver<Event>) {
+    let mut queues = vec![];
+    for sink in config.sinks {
+        let name = sink.to_string();
+        let sender = match Sender::new(sink) {
+            Ok(sender) => sender,
+            Err(why) => {
+                error!(sink = %name, "can't use audit sink: {why}");
+                continue;
+            }
+        };
+        let (tx, queue) = mpsc::channel(config.buffer_size.max(1));
+        tokio::spawn(deliver(sender, queue, config.max_retries));
+        queues.push((name, tx));
+    }
+
+    if queues.is_empty() {
+        return;
+    }
+
+    loop {
+        let ev = match rx.recv().await {
+            Ok(Event::Audit(ev)) => ev,
+            Ok(_) => continue,
+            Err(RecvError::Lagged(missed)) => {
+                error!("audit sinks missed {missed} events");
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        for (name, tx) in &queues {
+            if let Err(why) = tx.try_send(ev.clone()) {
+                warn!(sink = %name, event = ev.id, "dropping audit event: {why}");
+            }
+        }
+    }
+}
+
+/// Sends events from `queue` one at a time, retrying each one before moving on.
+async fn deliver(mut sender: Sender, mut queue: mpsc::Receiver<AuditEvent>, max_retries: u32) {
+    let name = sender.sink.to_string();
+
+    while let Some(ev) = queue.recv().await {
+        let mut attempts = 0;
+        while let Err(why) = sender.send(&ev).await {
+            if attempts >= max_retries {
+                error!(
+                    sink = %name,
+                    event = ev.id,
+                    "giving up on audit event after {attempts} retries: {why}"
+                );
+                break;
+            }
+
+            attempts += 1;
+            warn!(sink = %name, event = ev.id, attempts, "can't send audit event: {why}");
+            sleep(backoff(attempts)).await;
+        }
+    }
+}
+
+/// How long to wait before retrying an event that failed `attempts` times.
+fn backoff(attempts: u32) -> Duration {
+    Duration::from_millis(500 * 2u64.pow(attempts.min(7))).min(Duration::from_secs(60))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Transport {
+    Udp,
+    Tcp,
+    Unix,
+}
+
+fn transport(name: &str) -> Result<Transport> {
+    match name {
+        "udp" => Ok(Transport::Udp),
+        "tcp" => Ok(Transport::Tcp),
+        "unix" => Ok(Transport::Unix),
+        _ => Err(Error::UnknownSyslogTransport(name.to_string())),
+    }
+}
+
+struct Sender {
+    sink: AuditSink,
+    http: reqwest::Client,
+    /// Only used for syslog.
+    transport: Transport,
+    hostname: String,
+    /// Kept open between events, dropped when a write fails so the next event
+    /// reconnects.
+    tcp: Option<TcpStream>,
+}
+
+impl Sender {
+    fn new(sink: AuditSink) -> Result<Self> {
+        let (transport, hostname) = match &sink {
+            AuditSink::Syslog(syslog) => (
+                transport(&syslog.transport)?,
+                syslog.hostname.clone().unwrap_or_else(local_hostname),
+            ),
+            _ => (Transport::Udp, local_hostname()),
+        };
+
+        Ok(Sender {
+            sink,
+            http: reqwest::Client::builder()
+                .user_agent(APPLICATION_NAME)
+                .timeout(WEBHOOK_TIMEOUT)
+                .build()?,
+            transport,
+            hostname,
+            tcp: None,
+        })
+    }
+
+    async fn send(&mut self, ev: &AuditEvent) -> Result {
+        match &self.sink {
+            AuditSink::File(sink) => {
+                let mut line = serde_json::to_vec(ev)?;
+                line.push(b'\n');
+
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&sink.path)
+                    .await?;
+                file.write_all(&line).await?;
+                file.flush().await?;
+            }
+            AuditSink::Syslog(sink) => {
+                let msg = format_syslog(sink, &self.hostname, ev)?;
+                match self.transport {
+                    Transport::Udp => {
+                        let addr = resolve(&sink.address).await?;
+                        let bind = if addr.is_ipv4() {
+                            "0.0.0.0:0"
+                        } else {
+                            "[::]:0"
+                        };
+                        let sock = UdpSocket::bind(bind).await?;
+                        sock.send_to(msg.as_bytes(), addr).await?;
+                    }
+                    Transport::Tcp => {
+                        let mut stream = match self.tcp.take() {
+                            Some(stream) => stream,
+                            None => TcpStream::connect(&sink.address).await?,
+                        };
+                        // octet counting framing from RFC 6587
+                        let frame = format!("{} {}", msg.len(), msg);
+                        stream.write_all(frame.as_bytes()).await?;
+                        self.tcp = Some(stream);
+                    }
+                    Transport::Unix => {
+                        let sock = UnixDatagram::unbound()?;
+                        sock.send_to(msg.as_bytes(), &sink.address).await?;
+                    }
+                }
+            }
+            AuditSink::Webhook(sink) => {
+                let mut req = self.http.post(&sink.url).json(ev);
+                if let Some(token) = &sink.bearer_token {
+                    req = req.bearer_auth(token);
+                }
+                req.send().await?.error_for_status()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+async fn resolve(address: &str) -> Result<SocketAddr> {
+    lookup_host(address)
+        .await?
+        .next()
+        .ok_or_else(|| Error::Catchall(format!("{address} doesn't resolve to anything")))
+}
+
+fn local_hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .ok()
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "-".into())
+}
+
+/// Formats an event as an RFC 5424 message whose body is the event as JSON.
+fn format_syslog(sink: &SyslogSink, hostname: &str, ev: &AuditEvent) -> Result<String> {
+    let pri = sink.facility as u32 * 8 + SEVERITY_NOTICE;
+    let ts = Utc
+        .timestamp_opt(ev.ts, 0)
+        .single()
+        .unwrap_or_else(Utc::now)
+        .to_rfc3339_opts(SecondsFormat::Secs, true);
+
+    Ok(format!(
+        "<{pri}>1 {ts} {hostname} {app} {procid} {msgid} - {msg}",
+        hostname = header_field(hostname, 255),
+        app = header_field(&sink.app_name, 48),
+        procid = process::id(),
+        msgid = header_field(&ev.kind, 32),
+        msg = serde_json::to_string(ev)?,
+    ))
+}
+
+/// Header fields are printable ASCII without spaces, `-` when empty.
+fn header_field(value: &str, max: usize) -> String {
+    let value: String = value
+        .chars()
+        .map(|c| if c.is_ascii_graphic() { c } else { '_' })
+        .take(max)
+        .collect();
+
+    if value.is_empty() {
+        "-".into()
+    } else {
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn syslog_message() {
+        let sink = SyslogSink {
+            transport: "udp".into(),
+            address: "127.0.0.1:514".into(),
+            facility: 13,
+            app_name: "waifud".into(),
+            hostname: None,
+        };
+        let ev = AuditEvent {
+            id: 42,
+            ts: 1_700_000_000,
+            kind: "tailnet authkey".into(),
+            op: "create".into(),
+            data: None,
+            uuid: None,
+            name: None,
+            actor: Some("system:cloudinit".into()),
+            node: None,
+            source_ip: None,
+            request_id: None,
+        };
+
+        let msg = format_syslog(&sink, "vmhost1", &ev).unwrap();
+        let prefix = format!(
+            "<109>1 2023-11-14T22:13:20Z vmhost1 waifud {} tailnet_authkey - {{\"id\":42,",
+            process::id()
+        );
+        assert!(msg.starts_with(&prefix), "{msg}");
+    }
+
+    #[test]
+    fn unknown_transport() {
+        assert_eq!(transport("tcp").unwrap(), Transport::Tcp);
+        assert!(matches!(
+            transport("carrier-pigeon"),
+            Err(Error::UnknownSyslogTransport(_))
+        ));
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        assert_eq!(backoff(1), Duration::from_secs(1));
+        assert_eq!(backoff(30), Duration::from_secs(60));
+    }
+}
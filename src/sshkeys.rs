@@ -0,0 +1,290 @@
+//! SSH public keys stored for each Tailscale user, so instances they make can be
+//! reached with them.
+//!
+//! Every instance's vendor-data lists its owner's keys in `ssh_authorized_keys`, and
+//! user-data templates can use them as `{{ ssh_keys }}`.
+
+use crate::{Error, Result};
+use base64::{
+    engine::general_purpose::{STANDARD, STANDARD_NO_PAD},
+    Engine,
+};
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// Key types OpenSSH accepts in `authorized_keys`.
+const ALGORITHMS: &[&str] = &[
+    "ssh-ed25519",
+    "ssh-rsa",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "sk-ssh-ed25519@openssh.com",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SshKey {
+    pub id: Uuid,
+    /// Tailscale login name of the user the key belongs to.
+    pub owner: String,
+    pub name: String,
+    /// In `authorized_keys` format, such as `ssh-ed25519 AAAA... cadey@shachi`.
+    pub public_key: String,
+    /// Such as `SHA256:...`, the same as `ssh-keygen -l` shows.
+    pub fingerprint: String,
+    pub created_at: i64,
+}
+
+/// What to add a key with.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct NewSshKey {
+    /// Defaults to the key's comment, or its fingerprint if it has none.
+    pub name: Option<String>,
+    pub public_key: String,
+}
+
+/// The parts of a public key in `authorized_keys` format.
+#[derive(Debug, Clone, PartialEq)]
+struct PublicKey {
+    algorithm: String,
+    blob: Vec<u8>,
+    comment: Option<String>,
+}
+
+impl PublicKey {
+    fn parse(key: &str) -> Result<Self> {
+        let invalid = |why: &str| Error::InvalidSshKey(why.to_string());
+
+        let mut parts = key.split_whitespace();
+        let algorithm = parts.next().ok_or_else(|| invalid("the key is empty"))?;
+        if !ALGORITHMS.contains(&algorithm) {
+            return Err(Error::InvalidSshKey(format!(
+                "unknown key type {}, use one of {}",
+                algorithm,
+                ALGORITHMS.join(", ")
+            )));
+        }
+        let blob = parts
+            .next()
+            .ok_or_else(|| invalid("the key has no data after its type"))?;
+        let blob = STANDARD
+            .decode(blob)
+            .map_err(|_| invalid("the key data isn't base64"))?;
+
+        // the blob starts with the key type again as a length-prefixed string
+        let inner = blob
+            .get(..4)
+            .map(|len| u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize)
+            .and_then(|len| blob.get(4..4 + len));
+        if inner != Some(algorithm.as_bytes()) {
+            return Err(invalid("the key data doesn't match its type"));
+        }
+
+        let comment = parts.collect::<Vec<_>>().join(" ");
+
+        Ok(PublicKey {
+            algorithm: algorithm.to_string(),
+            blob,
+            comment: (!comment.is_empty()).then_some(comment),
+        })
+    }
+
+    fn fingerprint(&self) -> String {
+        format!(
+            "SHA256:{}",
+            STANDARD_NO_PAD.encode(Sha256::digest(&self.blob))
+        )
+    }
+
+    /// The key without whatever whitespace it was pasted with.
+    fn to_line(&self) -> String {
+        let key = format!("{} {}", self.algorithm, STANDARD.encode(&self.blob));
+        match &self.comment {
+            Some(comment) => format!("{} {}", key, comment),
+            None => key,
+        }
+    }
+}
+
+const COLUMNS: &str = "id, owner, name, public_key, fingerprint, created_at";
+
+fn from_row(row: &Row) -> rusqlite::Result<SshKey> {
+    Ok(SshKey {
+        id: row.get(0)?,
+        owner: row.get(1)?,
+        name: row.get(2)?,
+        public_key: row.get(3)?,
+        fingerprint: row.get(4)?,
+        created_at: row.get(5)?,
+    })
+}
+
+impl SshKey {
+    /// Adds a key for `owner`, who can't have the same key twice.
+    pub fn create(conn: &Connection, owner: &str, details: NewSshKey) -> Result<Self> {
+        let key = PublicKey::parse(&details.public_key)?;
+        let fingerprint = key.fingerprint();
+
+        let existing: Option<Uuid> = conn
+            .query_row(
+                "SELECT id FROM ssh_keys WHERE owner = ?1 AND fingerprint = ?2",
+                params![owner, fingerprint],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(id) = existing {
+            return Err(Error::SshKeyExists(fingerprint, id));
+        }
+
+        let ssh_key = SshKey {
+            id: Uuid::new_v4(),
+            owner: owner.to_string(),
+            name: details
+                .name
+                .or_else(|| key.comment.clone())
+                .unwrap_or_else(|| fingerprint.clone()),
+            public_key: key.to_line(),
+            fingerprint,
+            created_at: Utc::now().timestamp(),
+        };
+
+        conn.execute(
+            "INSERT INTO ssh_keys(id, owner, name, public_key, fingerprint, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                ssh_key.id,
+                ssh_key.owner,
+                ssh_key.name,
+                ssh_key.public_key,
+                ssh_key.fingerprint,
+                ssh_key.created_at,
+            ],
+        )?;
+
+        Ok(ssh_key)
+    }
+
+    pub fn from_id(conn: &Connection, id: Uuid) -> Result<Self> {
+        Ok(conn.query_row(
+            &format!("SELECT {COLUMNS} FROM ssh_keys WHERE id = ?1"),
+            params![id],
+            from_row,
+        )?)
+    }
+
+    /// Lists keys oldest first, only the ones belonging to `owner` if it is set.
+    pub fn list(conn: &Connection, owner: Option<&str>) -> Result<Vec<Self>> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {COLUMNS} FROM ssh_keys WHERE ?1 IS NULL OR owner = ?1 ORDER BY created_at, name"
+        ))?;
+
+        let mut result = vec![];
+        for key in stmt.query_map(params![owner], from_row)? {
+            result.push(key?);
+        }
+
+        Ok(result)
+    }
+
+    /// Just the keys `owner` has, ready to go in `authorized_keys`.
+    pub fn public_keys_of(conn: &Connection, owner: &str) -> Result<Vec<String>> {
+        Ok(Self::list(conn, Some(owner))?
+            .into_iter()
+            .map(|key| key.public_key)
+            .collect())
+    }
+
+    pub fn delete(&self, conn: &Connection) -> Result {
+        conn.execute("DELETE FROM ssh_keys WHERE id = ?1", params![self.id])?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // random bytes shaped like an ed25519 key
+    const KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIGX2vZ3hGmQ7n2Yl0NZ0E1c7QeO1o0ZqQ6Rw0Ff3Bq9J cadey@shachi";
+
+    #[test]
+    fn parse_keys() {
+        let key = PublicKey::parse(&format!("  {}  \n", KEY)).unwrap();
+        assert_eq!(key.algorithm, "ssh-ed25519");
+        assert_eq!(key.comment.as_deref(), Some("cadey@shachi"));
+        assert_eq!(key.to_line(), KEY);
+        assert!(key.fingerprint().starts_with("SHA256:"));
+        assert!(!key.fingerprint().ends_with('='));
+
+        for bad in [
+            "",
+            "ssh-dss AAAAB3NzaC1kc3M=",
+            "ssh-ed25519",
+            "ssh-ed25519 not-base64!",
+            // an ed25519 blob claiming to be rsa
+            "ssh-rsa AAAAC3NzaC1lZDI1NTE5AAAAIGX2vZ3hGmQ7n2Yl0NZ0E1c7QeO1o0ZqQ6Rw0Ff3Bq9J",
+        ] {
+            assert!(
+                matches!(PublicKey::parse(bad), Err(Error::InvalidSshKey(_))),
+                "{:?}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn create_and_list() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("./migrate/base_schema.sql"))
+            .unwrap();
+        conn.execute_batch(include_str!("./migrate/20261018-ssh-keys.sql"))
+            .unwrap();
+
+        let key = SshKey::create(
+            &conn,
+            "cadey@example.com",
+            NewSshKey {
+                name: None,
+                public_key: KEY.into(),
+            },
+        )
+        .unwrap();
+        assert_eq!(key.name, "cadey@shachi");
+
+        let again = SshKey::create(
+            &conn,
+            "cadey@example.com",
+            NewSshKey {
+                name: Some("laptop".into()),
+                public_key: KEY.into(),
+            },
+        );
+        assert!(matches!(again, Err(Error::SshKeyExists(_, id)) if id == key.id));
+
+        // someone else can have the same key
+        SshKey::create(
+            &conn,
+            "mara@example.com",
+            NewSshKey {
+                name: None,
+                public_key: KEY.into(),
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            SshKey::public_keys_of(&conn, "cadey@example.com").unwrap(),
+            vec![KEY.to_string()]
+        );
+        assert_eq!(SshKey::list(&conn, None).unwrap().len(), 2);
+
+        key.delete(&conn).unwrap();
+        assert!(SshKey::public_keys_of(&conn, "cadey@example.com")
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -2,6 +2,7 @@ use crate::Error;
 use async_trait::async_trait;
 use axum::{extract::FromRequestParts, http::request::Parts, RequestPartsExt};
 
+#[derive(Clone)]
 pub struct Tailauth(pub ts_localapi::User, pub ts_localapi::WhoisPeer);
 
 #[async_trait]
@@ -12,6 +13,11 @@ where
     type Rejection = Error;
 
     async fn from_request_parts(req: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        // the role middleware has already asked tailscaled who this is
+        if let Some(auth) = req.extensions.get::<Tailauth>() {
+            return Ok(auth.clone());
+        }
+
         let addr: axum_client_ip::ClientIp =
             req.extract().await.map_err(|_| Error::BadMiddlewareStack)?;
 
@@ -28,6 +34,9 @@ where
                 .unwrap_or("<unknown>".to_string())
         );
 
-        Ok(Tailauth(result.user_profile, result.node))
+        let auth = Tailauth(result.user_profile, result.node);
+        req.extensions.insert(auth.clone());
+
+        Ok(auth)
     }
 }
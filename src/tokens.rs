@@ -0,0 +1,252 @@
+//! Bearer tokens for things that can't be on the tailnet, such as CI jobs.
+//!
+//! Only the SHA-256 hash of a token is stored, the token itself is shown once when it
+//! is made. A token acts as the user that made it, limited to its scopes.
+
+use crate::{rbac::Role, Error, Result};
+use bb8::PooledConnection;
+use bb8_rusqlite::RusqliteConnectionManager;
+use chrono::Utc;
+use rand::Rng;
+use rusqlite::{params, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// Every token starts with this, so they are easy to spot in logs and secret scanners.
+pub const PREFIX: &str = "waifud_";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Token {
+    pub id: Uuid,
+    /// Tailscale login name of the user the token acts as.
+    pub owner: String,
+    pub description: String,
+    /// The roles the token may act with, it can use any route one of them allows.
+    pub scopes: Vec<Role>,
+    pub created_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<i64>,
+}
+
+/// What to make a token with.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct NewToken {
+    pub description: String,
+    /// Defaults to viewer.
+    #[serde(default)]
+    pub scopes: Vec<Role>,
+    /// How many days the token works for, it never expires if this is not set.
+    pub expires_in_days: Option<i64>,
+}
+
+/// A freshly made token. `secret` is the only time the token is ever shown.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreatedToken {
+    pub token: Token,
+    pub secret: String,
+}
+
+const COLUMNS: &str = "id, owner, description, scopes, created_at, expires_at, last_used_at";
+
+fn from_row(row: &Row) -> rusqlite::Result<Token> {
+    let scopes: String = row.get(3)?;
+
+    Ok(Token {
+        id: row.get(0)?,
+        owner: row.get(1)?,
+        description: row.get(2)?,
+        scopes: serde_json::from_str(&scopes).map_err(|why| {
+            rusqlite::Error::FromSqlConversionFailure(3, rusqlite::types::Type::Text, Box::new(why))
+        })?,
+        created_at: row.get(4)?,
+        expires_at: row.get(5)?,
+        last_used_at: row.get(6)?,
+    })
+}
+
+impl Token {
+    /// The most powerful role the token has.
+    pub fn role(&self) -> Role {
+        self.scopes.iter().copied().max().unwrap_or(Role::Viewer)
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        matches!(self.expires_at, Some(at) if at <= now)
+    }
+
+    /// Makes a token for `owner`. Tokens can't have scopes more powerful than the
+    /// role of whoever makes them.
+    pub fn create(
+        conn: &PooledConnection<'_, RusqliteConnectionManager>,
+        owner: &str,
+        owner_role: Role,
+        details: NewToken,
+    ) -> Result<CreatedToken> {
+        let mut scopes = details.scopes;
+        if scopes.is_empty() {
+            scopes.push(Role::Viewer);
+        }
+        scopes.sort();
+        scopes.dedup();
+        if let Some(&scope) = scopes.iter().find(|&&scope| scope > owner_role) {
+            return Err(Error::CantMakeToken(format!(
+                "you are {owner_role}, you can't make a token with the {scope} scope"
+            )));
+        }
+        if matches!(details.expires_in_days, Some(days) if days <= 0) {
+            return Err(Error::CantMakeToken(
+                "tokens must last at least a day".into(),
+            ));
+        }
+
+        let now = Utc::now().timestamp();
+        let secret = generate();
+        let token = Token {
+            id: Uuid::new_v4(),
+            owner: owner.to_string(),
+            description: details.description,
+            scopes,
+            created_at: now,
+            expires_at: details
+                .expires_in_days
+                .map(|days| now + days * 24 * 60 * 60),
+            last_used_at: None,
+        };
+
+        conn.execute(
+            "INSERT INTO tokens(id, owner, description, token_hash, scopes, created_at, expires_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                token.id,
+                token.owner,
+                token.description,
+                hash(&secret),
+                serde_json::to_string(&token.scopes)?,
+                token.created_at,
+                token.expires_at,
+            ],
+        )?;
+
+        Ok(CreatedToken { token, secret })
+    }
+
+    pub fn from_id(
+        conn: &PooledConnection<'_, RusqliteConnectionManager>,
+        id: Uuid,
+    ) -> Result<Self> {
+        Ok(conn.query_row(
+            &format!("SELECT {COLUMNS} FROM tokens WHERE id = ?1"),
+            params![id],
+            from_row,
+        )?)
+    }
+
+    /// Lists tokens, only the ones acting as `owner` if it is set.
+    pub fn list(
+        conn: &PooledConnection<'_, RusqliteConnectionManager>,
+        owner: Option<&str>,
+    ) -> Result<Vec<Self>> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {COLUMNS} FROM tokens WHERE ?1 IS NULL OR owner = ?1 ORDER BY created_at"
+        ))?;
+
+        let mut result = vec![];
+        for token in stmt.query_map(params![owner], from_row)? {
+            result.push(token?);
+        }
+
+        Ok(result)
+    }
+
+    /// Finds the token a secret belongs to, failing if there isn't one or it has
+    /// expired.
+    pub fn authenticate(
+        conn: &PooledConnection<'_, RusqliteConnectionManager>,
+        secret: &str,
+    ) -> Result<Self> {
+        let token = conn
+            .query_row(
+                &format!("SELECT {COLUMNS} FROM tokens WHERE token_hash = ?1"),
+                params![hash(secret)],
+                from_row,
+            )
+            .optional()?
+            .ok_or_else(|| Error::InvalidToken("no such token".into()))?;
+
+        let now = Utc::now().timestamp();
+        if token.is_expired(now) {
+            return Err(Error::InvalidToken(format!(
+                "token {} has expired",
+                token.id
+            )));
+        }
+
+        conn.execute(
+            "UPDATE tokens SET last_used_at = ?1 WHERE id = ?2",
+            params![now, token.id],
+        )?;
+
+        Ok(token)
+    }
+
+    pub fn revoke(&self, conn: &PooledConnection<'_, RusqliteConnectionManager>) -> Result {
+        conn.execute("DELETE FROM tokens WHERE id = ?1", params![self.id])?;
+
+        Ok(())
+    }
+}
+
+fn generate() -> String {
+    format!(
+        "{PREFIX}{}",
+        hex::encode(rand::thread_rng().gen::<[u8; 32]>())
+    )
+}
+
+fn hash(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_are_random_and_hashed() {
+        let a = generate();
+        let b = generate();
+        assert!(a.starts_with(PREFIX));
+        assert_eq!(a.len(), PREFIX.len() + 64);
+        assert_ne!(a, b);
+
+        assert_ne!(hash(&a), hash(&b));
+        assert_eq!(
+            hash("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn expiry_and_role() {
+        let token = Token {
+            id: Uuid::new_v4(),
+            owner: "cadey@example.com".into(),
+            description: "ci".into(),
+            scopes: vec![Role::Viewer, Role::Operator],
+            created_at: 0,
+            expires_at: Some(100),
+            last_used_at: None,
+        };
+        assert_eq!(token.role(), Role::Operator);
+        assert!(!token.is_expired(99));
+        assert!(token.is_expired(100));
+
+        let forever = Token {
+            expires_at: None,
+            ..token
+        };
+        assert!(!forever.is_expired(i64::MAX));
+    }
+}
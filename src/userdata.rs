@@ -0,0 +1,229 @@
+//! Rendering cloud-init user-data for an instance when it asks for it.
+//!
+//! User-data that starts with a `## template: waifud` line is a template. That line
+//! is taken out and `{{ instance.name }}` is replaced with the instance's name, and the same goes for `uuid`, `host`, `owner`, `distro`,
+//! `memory_mb`, `cpus`, `disk_size_gb` and `ip_address`. `{{ ssh_keys }}` is the
+//! owner's SSH public keys and `{{ vars.<name> }}` is a variable given when the
+//! instance was made. Strings are put in as-is, anything else (or a string followed by
+//! `| json`) is put in as JSON, which is also valid YAML.
+//!
+//! Anything else, including user-data that starts with `## template: jinja` for
+//! cloud-init to render itself, is sent as-is.
+
+use crate::{models::Instance, sshkeys::SshKey, Error, Result};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+const TEMPLATE_HEADER: &str = "## template: waifud";
+
+/// What an instance boots with, stored in `cloudconfig_seeds`.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Seed {
+    pub user_data: String,
+    pub vars: BTreeMap<String, String>,
+}
+
+impl Seed {
+    pub fn get(conn: &Connection, uuid: Uuid) -> Result<Self> {
+        let (user_data, vars): (String, String) = conn.query_row(
+            "SELECT user_data, vars FROM cloudconfig_seeds WHERE uuid = ?1",
+            params![uuid],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+
+        Ok(Seed {
+            user_data,
+            vars: serde_json::from_str(&vars)?,
+        })
+    }
+
+    pub fn insert(&self, conn: &Connection, uuid: Uuid) -> Result {
+        conn.execute(
+            "INSERT INTO cloudconfig_seeds(uuid, user_data, vars) VALUES (?1, ?2, ?3)",
+            params![uuid, self.user_data, serde_json::to_string(&self.vars)?],
+        )?;
+
+        Ok(())
+    }
+
+    /// Renders the user-data for `ins`, failing if it refers to anything that doesn't
+    /// exist.
+    pub fn render(&self, conn: &Connection, ins: &Instance) -> Result<String> {
+        let ssh_keys = match &ins.owner {
+            Some(owner) => SshKey::public_keys_of(conn, owner)?,
+            None => vec![],
+        };
+
+        let ctx = Context {
+            instance: InstanceVars::from(ins),
+            ssh_keys,
+            vars: &self.vars,
+        };
+
+        render(&self.user_data, &ctx)
+    }
+}
+
+/// Everything a template can refer to.
+#[derive(Debug, Serialize)]
+pub struct Context<'a> {
+    pub instance: InstanceVars,
+    pub ssh_keys: Vec<String>,
+    pub vars: &'a BTreeMap<String, String>,
+}
+
+/// The parts of an [`Instance`] templates can use. Unset fields are still here so
+/// templates don't fail for instances that don't have them.
+#[derive(Debug, Serialize)]
+pub struct InstanceVars {
+    pub name: String,
+    pub uuid: Uuid,
+    pub host: String,
+    pub owner: Option<String>,
+    pub distro: String,
+    pub memory_mb: i32,
+    pub cpus: i32,
+    pub disk_size_gb: i32,
+    pub ip_address: Option<String>,
+}
+
+impl From<&Instance> for InstanceVars {
+    fn from(ins: &Instance) -> Self {
+        InstanceVars {
+            name: ins.name.clone(),
+            uuid: ins.uuid,
+            host: ins.host.clone(),
+            owner: ins.owner.clone(),
+            distro: ins.distro.clone(),
+            memory_mb: ins.memory,
+            cpus: ins.cpus,
+            disk_size_gb: ins.disk_size,
+            ip_address: ins.ip_address.clone(),
+        }
+    }
+}
+
+pub fn render(user_data: &str, ctx: &Context) -> Result<String> {
+    let (header, template) = user_data.split_once('\n').unwrap_or((user_data, ""));
+    if header.trim_end() != TEMPLATE_HEADER {
+        return Ok(user_data.to_string());
+    }
+
+    let root = serde_json::to_value(ctx)?;
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        // counting the header, so lines match what the user wrote
+        let line = template[..template.len() - rest.len() + start]
+            .matches('\n')
+            .count()
+            + 2;
+        result.push_str(&rest[..start]);
+
+        let after = &rest[start + 2..];
+        let end = after
+            .find("}}")
+            .ok_or_else(|| Error::Template(format!("line {}: {{{{ is never closed", line)))?;
+        let value = expand(after[..end].trim(), &root)
+            .map_err(|why| Error::Template(format!("line {}: {}", line, why)))?;
+        result.push_str(&value);
+
+        rest = &after[end + 2..];
+    }
+    result.push_str(rest);
+
+    Ok(result)
+}
+
+fn expand(expr: &str, root: &Value) -> std::result::Result<String, String> {
+    let (path, filter) = match expr.split_once('|') {
+        Some((path, filter)) => (path.trim(), Some(filter.trim())),
+        None => (expr, None),
+    };
+
+    let value = path
+        .split('.')
+        .try_fold(root, |value, key| value.get(key))
+        .ok_or_else(|| format!("{} is not defined", path))?;
+
+    match (filter, value) {
+        (None, Value::String(s)) => Ok(s.clone()),
+        (None, Value::Null) => Ok(String::new()),
+        (None, value) | (Some("json"), value) => Ok(value.to_string()),
+        (Some(filter), _) => Err(format!("unknown filter {}, use json", filter)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ctx(vars: &BTreeMap<String, String>) -> Context<'_> {
+        let ins = Instance {
+            name: "crobat".into(),
+            host: "vmhost1".into(),
+            owner: Some("cadey@example.com".into()),
+            ..Instance::default()
+        };
+
+        Context {
+            instance: InstanceVars::from(&ins),
+            ssh_keys: vec!["ssh-ed25519 AAAA cadey@shachi".into()],
+            vars,
+        }
+    }
+
+    #[test]
+    fn renders_fields() {
+        let vars = BTreeMap::from([("role".to_string(), "builder".to_string())]);
+        let out = render(
+            "## template: waifud\n#cloud-config\nhostname: {{ instance.name }}.{{instance.host}}\nusers:\n  - name: cadey\n    ssh_authorized_keys: {{ ssh_keys }}\nrole: {{ vars.role | json }}\nip: {{ instance.ip_address }}\n",
+            &ctx(&vars),
+        )
+        .unwrap();
+
+        assert_eq!(
+            out,
+            "#cloud-config\nhostname: crobat.vmhost1\nusers:\n  - name: cadey\n    ssh_authorized_keys: [\"ssh-ed25519 AAAA cadey@shachi\"]\nrole: \"builder\"\nip: \n"
+        );
+    }
+
+    #[test]
+    fn reports_errors_with_lines() {
+        let vars = BTreeMap::new();
+
+        let err = render(
+            "## template: waifud\n#cloud-config\n\nfoo: {{ vars.missing }}\n",
+            &ctx(&vars),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "can't render user-data: line 4: vars.missing is not defined"
+        );
+
+        assert!(render("## template: waifud\nfoo: {{ instance.name", &ctx(&vars)).is_err());
+        assert!(render(
+            "## template: waifud\nfoo: {{ instance.name | upper }}",
+            &ctx(&vars)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn leaves_everything_else_alone() {
+        let vars = BTreeMap::new();
+
+        for user_data in [
+            "## template: jinja\n#cloud-config\nhostname: {{ v1.local_hostname }}\n",
+            "#cloud-config\nruncmd:\n  - docker ps --format '{{.Names}}'\n",
+            "## template: waifud-ish\n{{ nope",
+        ] {
+            assert_eq!(render(user_data, &ctx(&vars)).unwrap(), user_data);
+        }
+    }
+}
@@ -0,0 +1,19 @@
+use super::{cmd, enable_tailscaled, tailscale_up, VendorDataStrategy};
+
+pub struct AmazonLinux;
+
+impl VendorDataStrategy for AmazonLinux {
+    fn join_tailnet(&self, authkey: &str) -> Vec<Vec<String>> {
+        vec![
+            cmd(&["yum", "install", "-y", "yum-utils"]),
+            cmd(&[
+                "yum-config-manager",
+                "--add-repo",
+                "https://pkgs.tailscale.com/stable/amazon-linux/2/tailscale.repo",
+            ]),
+            cmd(&["yum", "install", "-y", "tailscale"]),
+            enable_tailscaled(),
+            tailscale_up(authkey),
+        ]
+    }
+}
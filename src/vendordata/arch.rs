@@ -0,0 +1,13 @@
+use super::{cmd, enable_tailscaled, tailscale_up, VendorDataStrategy};
+
+pub struct Arch;
+
+impl VendorDataStrategy for Arch {
+    fn join_tailnet(&self, authkey: &str) -> Vec<Vec<String>> {
+        vec![
+            cmd(&["pacman", "-Sy", "--noconfirm", "--needed", "tailscale"]),
+            enable_tailscaled(),
+            tailscale_up(authkey),
+        ]
+    }
+}
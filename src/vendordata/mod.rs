@@ -0,0 +1,307 @@
+//! Cloud-init vendor-data for each distro family.
+//!
+//! Every distro row records which family it belongs to, and each family has a
+//! [`VendorDataStrategy`] that knows how to install tailscale with its package manager
+//! and join the tailnet on first boot. Distros that aren't in a known family use the
+//! tailscale install script.
+
+use crate::{Error, Result};
+use rusqlite::{
+    params,
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef},
+    Connection, OptionalExtension, ToSql,
+};
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+pub mod amazon_linux;
+pub mod arch;
+pub mod nixos;
+pub mod rocky_linux;
+pub mod ubuntu;
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CloudConfig {
+    #[serde(rename = "write_files", default, skip_serializing_if = "Vec::is_empty")]
+    pub write_files: Vec<File>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub runcmd: Vec<Vec<String>>,
+    /// Added to the default user's `authorized_keys`.
+    #[serde(
+        rename = "ssh_authorized_keys",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub ssh_authorized_keys: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phone_home: Option<PhoneHome>,
+}
+
+/// Settings for cloud-init's phone_home module, which runs at the very end of boot.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PhoneHome {
+    pub url: String,
+    /// Which facts to post, waifud only needs the request.
+    pub post: Vec<String>,
+    pub tries: u32,
+}
+
+impl PhoneHome {
+    pub fn new(url: String) -> Self {
+        PhoneHome {
+            url,
+            post: vec!["instance_id".into()],
+            tries: 10,
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct File {
+    pub owner: String,
+    pub path: String,
+    pub permissions: String,
+    pub content: String,
+}
+
+/// How to set up instances of one distro family on first boot.
+pub trait VendorDataStrategy: Send + Sync {
+    /// Commands that install tailscale, start it and join the tailnet with `authkey`.
+    fn join_tailnet(&self, authkey: &str) -> Vec<Vec<String>>;
+
+    /// The vendor-data for an instance, which only joins the tailnet if `authkey` is
+    /// set.
+    fn cloud_config(&self, authkey: Option<&str>) -> CloudConfig {
+        CloudConfig {
+            write_files: vec![motd()],
+            runcmd: authkey
+                .map(|authkey| self.join_tailnet(authkey))
+                .unwrap_or_default(),
+            ..CloudConfig::default()
+        }
+    }
+}
+
+/// Which [`VendorDataStrategy`] a distro uses.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum Family {
+    Ubuntu,
+    Nixos,
+    Arch,
+    AmazonLinux,
+    RockyLinux,
+    #[default]
+    Generic,
+}
+
+impl Family {
+    pub const ALL: [Family; 6] = [
+        Family::Ubuntu,
+        Family::Nixos,
+        Family::Arch,
+        Family::AmazonLinux,
+        Family::RockyLinux,
+        Family::Generic,
+    ];
+
+    /// Works out the family from a distro's name, for distros added without one.
+    pub fn guess(distro: &str) -> Self {
+        Family::ALL
+            .into_iter()
+            .find(|family| {
+                let prefix = family.to_string();
+                distro == prefix || distro.starts_with(&format!("{}-", prefix))
+            })
+            .unwrap_or_default()
+    }
+
+    /// The family recorded for `distro`, or a guess if the distro has been deleted.
+    pub fn of_distro(conn: &Connection, distro: &str) -> Result<Self> {
+        let family: Option<Family> = conn
+            .query_row(
+                "SELECT family FROM distros WHERE name = ?1",
+                params![distro],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(family.unwrap_or_else(|| Family::guess(distro)))
+    }
+
+    pub fn strategy(self) -> &'static dyn VendorDataStrategy {
+        match self {
+            Family::Ubuntu => &ubuntu::Ubuntu,
+            Family::Nixos => &nixos::Nixos,
+            Family::Arch => &arch::Arch,
+            Family::AmazonLinux => &amazon_linux::AmazonLinux,
+            Family::RockyLinux => &rocky_linux::RockyLinux,
+            Family::Generic => &Generic,
+        }
+    }
+}
+
+impl fmt::Display for Family {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Family::Ubuntu => "ubuntu",
+            Family::Nixos => "nixos",
+            Family::Arch => "arch",
+            Family::AmazonLinux => "amazon-linux",
+            Family::RockyLinux => "rocky-linux",
+            Family::Generic => "generic",
+        })
+    }
+}
+
+impl FromStr for Family {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Family::ALL
+            .into_iter()
+            .find(|family| family.to_string() == s)
+            .ok_or_else(|| Error::UnknownDistroFamily(s.to_string()))
+    }
+}
+
+impl From<Family> for String {
+    fn from(family: Family) -> Self {
+        family.to_string()
+    }
+}
+
+impl TryFrom<String> for Family {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl ToSql for Family {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.to_string()))
+    }
+}
+
+impl FromSql for Family {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|why: Error| FromSqlError::Other(why.to_string().into()))
+    }
+}
+
+/// Anything else, using the tailscale install script.
+pub struct Generic;
+
+impl VendorDataStrategy for Generic {
+    fn join_tailnet(&self, authkey: &str) -> Vec<Vec<String>> {
+        vec![
+            cmd(&[
+                "sh",
+                "-c",
+                "curl -fsSL https://tailscale.com/install.sh | sh",
+            ]),
+            enable_tailscaled(),
+            tailscale_up(authkey),
+        ]
+    }
+}
+
+pub(crate) fn cmd(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+pub(crate) fn enable_tailscaled() -> Vec<String> {
+    cmd(&["systemctl", "enable", "--now", "tailscaled.service"])
+}
+
+pub(crate) fn tailscale_up(authkey: &str) -> Vec<String> {
+    cmd(&[
+        "tailscale",
+        "up",
+        "--authkey",
+        authkey,
+        "--ssh",
+        "--advertise-tags=tag:vm",
+    ])
+}
+
+/// Greets people who log in with `/etc/update-motd.d`.
+pub(crate) fn motd() -> File {
+    File {
+        owner: "root:root".into(),
+        path: "/etc/update-motd.d/69-waifud".into(),
+        permissions: "0755".into(),
+        content: "#!/bin/sh\n#\n# This file is written by waifud.\necho \"\"\necho \"Welcome to waifud <3\"\n".into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AUTHKEY: &str = "tskey-auth-test";
+
+    fn snapshot(family: Family) -> &'static str {
+        match family {
+            Family::Ubuntu => include_str!("./snapshots/ubuntu.yaml"),
+            Family::Nixos => include_str!("./snapshots/nixos.yaml"),
+            Family::Arch => include_str!("./snapshots/arch.yaml"),
+            Family::AmazonLinux => include_str!("./snapshots/amazon-linux.yaml"),
+            Family::RockyLinux => include_str!("./snapshots/rocky-linux.yaml"),
+            Family::Generic => include_str!("./snapshots/generic.yaml"),
+        }
+    }
+
+    #[test]
+    fn snapshots() {
+        for family in Family::ALL {
+            let got =
+                serde_yaml::to_string(&family.strategy().cloud_config(Some(AUTHKEY))).unwrap();
+            assert_eq!(
+                serde_yaml::from_str::<serde_yaml::Value>(&got).unwrap(),
+                serde_yaml::from_str::<serde_yaml::Value>(snapshot(family)).unwrap(),
+                "{} rendered as:\n{}",
+                family,
+                got
+            );
+        }
+    }
+
+    #[test]
+    fn only_joins_tailnet_with_authkey() {
+        for family in Family::ALL {
+            let cc = family.strategy().cloud_config(None);
+            assert!(cc.runcmd.is_empty(), "{}", family);
+            assert!(
+                !serde_yaml::to_string(&cc).unwrap().contains("tailscale"),
+                "{}",
+                family
+            );
+        }
+    }
+
+    #[test]
+    fn families() {
+        for family in Family::ALL {
+            assert_eq!(family.to_string().parse::<Family>().unwrap(), family);
+        }
+        assert!(matches!(
+            "gentoo".parse::<Family>(),
+            Err(Error::UnknownDistroFamily(_))
+        ));
+
+        assert_eq!(Family::guess("ubuntu-22.04"), Family::Ubuntu);
+        assert_eq!(Family::guess("nixos-unstable"), Family::Nixos);
+        assert_eq!(Family::guess("arch"), Family::Arch);
+        assert_eq!(Family::guess("amazon-linux-2"), Family::AmazonLinux);
+        assert_eq!(Family::guess("rocky-linux-9"), Family::RockyLinux);
+        assert_eq!(Family::guess("alpine-3.16"), Family::Generic);
+        assert_eq!(Family::guess("archlinux"), Family::Generic);
+    }
+}
@@ -0,0 +1,50 @@
+use super::{cmd, tailscale_up, CloudConfig, File, VendorDataStrategy};
+
+/// NixOS can't install packages or write the motd imperatively, so instances joining
+/// the tailnet get a module that turns tailscale on. It imports the instance's own
+/// `configuration.nix`, later rebuilds need `-I nixos-config=/etc/nixos/waifud.nix`
+/// to keep tailscale.
+pub struct Nixos;
+
+const MODULE: &str = r#"# This file is written by waifud.
+{ lib, ... }:
+
+{
+  imports = lib.optional (builtins.pathExists ./configuration.nix) ./configuration.nix;
+
+  services.tailscale.enable = true;
+  users.motd = "Welcome to waifud <3";
+}
+"#;
+
+impl VendorDataStrategy for Nixos {
+    fn join_tailnet(&self, authkey: &str) -> Vec<Vec<String>> {
+        vec![
+            cmd(&[
+                "nixos-rebuild",
+                "switch",
+                "-I",
+                "nixos-config=/etc/nixos/waifud.nix",
+            ]),
+            tailscale_up(authkey),
+        ]
+    }
+
+    fn cloud_config(&self, authkey: Option<&str>) -> CloudConfig {
+        let authkey = match authkey {
+            Some(authkey) => authkey,
+            None => return CloudConfig::default(),
+        };
+
+        CloudConfig {
+            write_files: vec![File {
+                owner: "root:root".into(),
+                path: "/etc/nixos/waifud.nix".into(),
+                permissions: "0644".into(),
+                content: MODULE.into(),
+            }],
+            runcmd: self.join_tailnet(authkey),
+            ..CloudConfig::default()
+        }
+    }
+}
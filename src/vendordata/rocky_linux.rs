@@ -0,0 +1,20 @@
+use super::{cmd, enable_tailscaled, tailscale_up, VendorDataStrategy};
+
+pub struct RockyLinux;
+
+impl VendorDataStrategy for RockyLinux {
+    fn join_tailnet(&self, authkey: &str) -> Vec<Vec<String>> {
+        vec![
+            cmd(&["dnf", "install", "-y", "dnf-plugins-core"]),
+            // the repo is per RHEL major version
+            cmd(&[
+                "sh",
+                "-c",
+                "dnf config-manager --add-repo https://pkgs.tailscale.com/stable/rhel/$(rpm -E %rhel)/tailscale.repo",
+            ]),
+            cmd(&["dnf", "install", "-y", "tailscale"]),
+            enable_tailscaled(),
+            tailscale_up(authkey),
+        ]
+    }
+}
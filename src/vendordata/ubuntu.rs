@@ -0,0 +1,19 @@
+use super::{cmd, enable_tailscaled, tailscale_up, VendorDataStrategy};
+
+pub struct Ubuntu;
+
+impl VendorDataStrategy for Ubuntu {
+    fn join_tailnet(&self, authkey: &str) -> Vec<Vec<String>> {
+        vec![
+            cmd(&[
+                "sh",
+                "-c",
+                "curl -fsSL https://tailscale.com/install.sh | sh",
+            ]),
+            enable_tailscaled(),
+            tailscale_up(authkey),
+            // for machinectl shell
+            cmd(&["apt", "install", "-y", "systemd-container"]),
+        ]
+    }
+}